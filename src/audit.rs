@@ -0,0 +1,52 @@
+use crate::{generic::UUID, models::user::User};
+use rocket::{
+	route::{Handler, Outcome},
+	Data, Request, Route,
+};
+use std::cell::RefCell;
+
+tokio::task_local! {
+	/// The user responsible for mutations made by the current request
+	static ACTOR: RefCell<Option<UUID<User>>>;
+}
+
+/// Get the user responsible for mutations made by the current task.
+///
+/// Returns `None` outside of a request, or if the request has not validated a `BearerToken`.
+pub fn actor() -> Option<UUID<User>> {
+	ACTOR
+		.try_with(|actor| actor.borrow().to_owned())
+		.ok()
+		.flatten()
+}
+
+/// Set the user responsible for mutations made by the current request.
+///
+/// Called when a `BearerToken` is validated. Does nothing outside of a request.
+pub fn set_actor(user: UUID<User>) {
+	let _ = ACTOR.try_with(|actor| *actor.borrow_mut() = Some(user));
+}
+
+/// A route handler wrapper that scopes `actor()` to the request.
+#[derive(Clone)]
+struct AuditScope(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for AuditScope {
+	async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+		ACTOR
+			.scope(RefCell::new(None), self.0.handle(request, data))
+			.await
+	}
+}
+
+/// Wrap the handlers of `routes` so that mutations they make are attributed to the signed in user.
+pub fn scoped(routes: Vec<Route>) -> Vec<Route> {
+	routes
+		.into_iter()
+		.map(|mut route| {
+			route.handler = Box::new(AuditScope(route.handler));
+			route
+		})
+		.collect()
+}
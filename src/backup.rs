@@ -0,0 +1,329 @@
+use crate::{
+	dbrecord::{record_tables, relation_tables, DBRecord, DBTransaction, Edge},
+	error::Error,
+	generic::{surrealdb_client, Environment},
+	models::audit_entry::AuditEntry,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{BufRead, BufReader, BufWriter, Write},
+	path::Path,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+const BACKUP_FORMAT: &str = "kavacoast-backup";
+/// Incremented when the archive layout changes. Archives with a newer version can't be restored.
+const BACKUP_FORMAT_VERSION: u32 = 1;
+const BACKUP_FILE_PREFIX: &str = "backup-";
+const BACKUP_FILE_EXTENSION: &str = "ndjson";
+pub const DEFAULT_BACKUP_RETENTION_DAYS: i64 = 30;
+/// How many lines of an archive are staged in each transaction
+const RESTORE_BATCH_SIZE: usize = 1000;
+/// Holds the lines of an archive being restored until they replace the existing data
+const RESTORE_STAGING_TABLE: &str = "restore_staging";
+
+/// A line of an NDJSON backup archive. The first line is always a `Header`.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BackupLine {
+	Header {
+		format: String,
+		version: u32,
+		created_at: DateTime<Utc>,
+	},
+	Record {
+		table: String,
+		id: String,
+		content: serde_json::Value,
+	},
+	Edge(Edge),
+}
+
+/// A line of an archive being restored, kept in `RESTORE_STAGING_TABLE`
+#[derive(Serialize)]
+struct StagedLine {
+	line: BackupLine,
+}
+
+/// Export every record and edge to an NDJSON archive at `path`, returning the number of lines written.
+pub async fn backup(path: &Path) -> Result<usize, Error> {
+	let db = surrealdb_client().await?;
+	// Only readable by the owner, since records include password hashes and other secrets
+	let mut options = OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+
+	#[cfg(unix)]
+	options.mode(0o600);
+
+	let file = options.open(path)?;
+
+	let mut writer = BufWriter::new(file);
+	let mut lines = 0;
+
+	let mut write_line = |line: &BackupLine| -> Result<(), Error> {
+		serde_json::to_writer(&mut writer, line)?;
+		writer.write_all(b"\n")?;
+		lines += 1;
+		Ok(())
+	};
+
+	write_line(&BackupLine::Header {
+		format: BACKUP_FORMAT.to_owned(),
+		version: BACKUP_FORMAT_VERSION,
+		created_at: Utc::now(),
+	})?;
+
+	for table in record_tables() {
+		db.set("table", table.to_owned()).await?;
+
+		let mut response = db
+			.query("SELECT *, record::id(id) AS id FROM type::table($table)")
+			.await?;
+
+		let result: surrealdb::Value = response.take(0)?;
+		let serde_value = result.into_inner().into_json();
+		let records: Vec<serde_json::Map<String, serde_json::Value>> =
+			serde_json::from_value(serde_value)?;
+
+		for mut content in records {
+			let id = match content.remove("id") {
+				Some(serde_json::Value::String(id)) => id,
+				id => {
+					return Err(Error::generic_500(&format!(
+						"Unexpected record ID in {}: {:?}",
+						table, id
+					)))
+				}
+			};
+
+			write_line(&BackupLine::Record {
+				table: table.to_owned(),
+				id,
+				content: content.into(),
+			})?;
+		}
+	}
+
+	for relation in relation_tables() {
+		for edge in Edge::all(relation).await? {
+			write_line(&BackupLine::Edge(edge))?;
+		}
+	}
+
+	writer.flush()?;
+	Ok(lines)
+}
+
+/// Replace every record and edge with the contents of the NDJSON archive at `path`,
+/// returning the number of lines read.
+///
+/// The archive is validated, then streamed into `RESTORE_STAGING_TABLE` in batches of `RESTORE_BATCH_SIZE`
+/// lines, so it doesn't have to fit in memory. The existing data is only replaced once every line is staged,
+/// in a single transaction, so a failed restore leaves it as it was.
+///
+/// The audit log isn't replaced: entries from the archive that it doesn't have are added to it.
+pub async fn restore(path: &Path) -> Result<usize, Error> {
+	let record_tables = record_tables();
+	let relation_tables = relation_tables();
+	let mut count = 1;
+
+	for line in archive_lines(path)? {
+		let (number, line) = line?;
+		count = number;
+
+		match line {
+			BackupLine::Record { table, .. } if !record_tables.contains(&table) => {
+				return Err(Error::unprocessable(&format!("Unknown table: {}", table)));
+			}
+			BackupLine::Edge(edge) if !relation_tables.contains(&edge.relation()) => {
+				return Err(Error::unprocessable(&format!(
+					"Unknown relation: {}",
+					edge.relation()
+				)));
+			}
+			BackupLine::Header { .. } => {
+				return Err(Error::unprocessable(&format!(
+					"Unexpected header on line {}",
+					number
+				)))
+			}
+			_ => {}
+		}
+	}
+
+	// A previous restore may have failed while staging
+	remove_staging_table().await?;
+	let result = stage_and_replace(path, &record_tables, &relation_tables).await;
+	remove_staging_table().await?;
+	result?;
+
+	Ok(count)
+}
+
+/// Stage the lines of the archive at `path`, then replace every record and edge with them in one transaction.
+async fn stage_and_replace(
+	path: &Path,
+	record_tables: &[String],
+	relation_tables: &[&str],
+) -> Result<(), Error> {
+	let mut transaction = DBTransaction::new();
+	let mut queued = 0;
+
+	for line in archive_lines(path)? {
+		let (number, line) = line?;
+		transaction.create_in(
+			RESTORE_STAGING_TABLE,
+			&number.to_string(),
+			StagedLine { line },
+		)?;
+		queued += 1;
+
+		if queued == RESTORE_BATCH_SIZE {
+			std::mem::take(&mut transaction).commit().await?;
+			queued = 0;
+		}
+	}
+
+	transaction.commit().await?;
+
+	let mut transaction = DBTransaction::new();
+
+	for table in record_tables.iter() {
+		if table != AuditEntry::table() {
+			transaction.clear_table(table)?;
+		}
+	}
+
+	for relation in relation_tables.iter() {
+		transaction.clear_table(relation)?;
+	}
+
+	for table in record_tables.iter() {
+		let keep_existing = table == AuditEntry::table();
+		transaction.create_staged(RESTORE_STAGING_TABLE, table, keep_existing)?;
+	}
+
+	// Edges are created after every record so both ends exist
+	for relation in relation_tables.iter() {
+		transaction.insert_staged_edges(RESTORE_STAGING_TABLE, relation)?;
+	}
+
+	transaction.commit().await
+}
+
+/// Delete `RESTORE_STAGING_TABLE` and everything staged in it.
+async fn remove_staging_table() -> Result<(), Error> {
+	let db = surrealdb_client().await?;
+	db.query(format!("REMOVE TABLE IF EXISTS {}", RESTORE_STAGING_TABLE))
+		.await?
+		.check()?;
+	Ok(())
+}
+
+/// Read the lines of the archive at `path` after its header, with their line numbers.
+///
+/// Returns a 422 `Error` if it isn't an archive of a supported version.
+fn archive_lines(
+	path: &Path,
+) -> Result<impl Iterator<Item = Result<(usize, BackupLine), Error>>, Error> {
+	let mut lines = BufReader::new(File::open(path)?).lines();
+
+	let header = lines
+		.next()
+		.ok_or_else(|| Error::unprocessable("Backup archive is empty"))??;
+
+	match serde_json::from_str(&header)? {
+		BackupLine::Header {
+			format, version, ..
+		} if format == BACKUP_FORMAT => {
+			if version > BACKUP_FORMAT_VERSION {
+				return Err(Error::unprocessable(&format!(
+					"Backup archive version {} is newer than the supported version {}",
+					version, BACKUP_FORMAT_VERSION
+				)));
+			}
+		}
+		_ => return Err(Error::unprocessable("Not a backup archive")),
+	}
+
+	Ok(lines.enumerate().filter_map(|(index, line)| {
+		let line = match line {
+			Ok(line) => line,
+			Err(e) => return Some(Err(e.into())),
+		};
+
+		if line.trim().is_empty() {
+			return None;
+		}
+
+		Some(
+			serde_json::from_str(&line)
+				.map(|line| (index + 2, line))
+				.map_err(Error::from),
+		)
+	}))
+}
+
+/// Write a backup to `BACKUP_DIRECTORY` and delete backups older than `BACKUP_RETENTION_DAYS`.
+///
+/// Does nothing if `BACKUP_DIRECTORY` isn't configured.
+pub async fn scheduled_backup() -> Result<(), Error> {
+	let env = Environment::new();
+
+	let directory = match env.backup_directory.val_opt() {
+		Some(directory) => directory,
+		None => return Ok(()),
+	};
+
+	let retention_days = match env.backup_retention_days.val_opt() {
+		Some(days) => days
+			.parse::<i64>()
+			.map_err(|e| Error::generic_500(&format!("Invalid BACKUP_RETENTION_DAYS: {}", e)))?,
+		None => DEFAULT_BACKUP_RETENTION_DAYS,
+	};
+
+	let directory = Path::new(&directory);
+	fs::create_dir_all(directory)?;
+
+	let file_name = format!(
+		"{}{}.{}",
+		BACKUP_FILE_PREFIX,
+		Utc::now().format("%Y%m%dT%H%M%SZ"),
+		BACKUP_FILE_EXTENSION
+	);
+
+	let lines = backup(&directory.join(file_name)).await?;
+	log::info!("Wrote scheduled backup ({} lines)", lines);
+
+	let cutoff = Utc::now() - Duration::days(retention_days);
+
+	for entry in fs::read_dir(directory)? {
+		let entry = entry?;
+		let path = entry.path();
+
+		let is_backup = path
+			.file_name()
+			.and_then(|name| name.to_str())
+			.is_some_and(|name| name.starts_with(BACKUP_FILE_PREFIX))
+			&& path
+				.extension()
+				.is_some_and(|ext| ext == BACKUP_FILE_EXTENSION);
+
+		if !is_backup {
+			continue;
+		}
+
+		let modified: DateTime<Utc> = entry.metadata()?.modified()?.into();
+
+		if modified < cutoff {
+			fs::remove_file(&path)?;
+			log::info!("Deleted expired backup {}", path.display());
+		}
+	}
+
+	Ok(())
+}
@@ -0,0 +1,289 @@
+use crate::{
+	backup,
+	dbrecord::{self, DBRecord, DBTransaction},
+	error::Error,
+	generic::{random_alphanumeric_string, Environment, HashedString},
+	jobs, kavabot, migrations,
+	models::{
+		session::Session,
+		user::{Role, User},
+	},
+	seed::{self, SeedOptions},
+	shutdown::Shutdown,
+	signing_keys::SigningKeys,
+	web,
+};
+use clap::{Parser, Subcommand};
+use rocket::http::Status;
+use std::path::PathBuf;
+
+const GENERATED_PASSWORD_LENGTH: usize = 16;
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+	#[command(subcommand)]
+	pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Default)]
+pub enum Command {
+	/// Start the web server, Discord bot, and jobs (default)
+	#[default]
+	Serve,
+	/// Start only the Discord bot
+	BotOnly,
+	/// Start only the web server and jobs
+	WebOnly,
+	/// Replace all data with generated development data, including an admin (admin/admin123)
+	Seed {
+		/// Number of users to generate
+		#[arg(long, default_value_t = SeedOptions::default().users)]
+		users: usize,
+		/// Months of pool game history to generate
+		#[arg(long, default_value_t = SeedOptions::default().months)]
+		months: u32,
+		#[arg(long, default_value_t = SeedOptions::default().games_per_week)]
+		games_per_week: usize,
+		/// Random seed, the same seed generates the same data
+		#[arg(long, default_value_t = SeedOptions::default().seed)]
+		seed: u64,
+		/// Confirm deleting all data
+		#[arg(long)]
+		confirm: bool,
+	},
+	/// Create an admin user with a generated password
+	CreateAdmin {
+		username: String,
+		#[arg(long)]
+		display_name: Option<String>,
+	},
+	/// Grant a role to a user
+	GrantRole {
+		username: String,
+		#[arg(value_enum)]
+		role: Role,
+	},
+	/// Remove a role from a user
+	RevokeRole {
+		username: String,
+		#[arg(value_enum)]
+		role: Role,
+	},
+	/// Delete the sessions of a user, or of every user with `--all`
+	RevokeSessions {
+		#[arg(required_unless_present = "all", conflicts_with = "all")]
+		username: Option<String>,
+		#[arg(long)]
+		all: bool,
+		/// Confirm signing out the affected users
+		#[arg(long)]
+		confirm: bool,
+	},
+	/// Generate an access token signing key in `OAUTH_JWT_KEYS_DIRECTORY`
+	GenerateSigningKey {
+		/// Key ID, also the file name of the key
+		kid: String,
+	},
+	/// Define indexes and migrate records, then exit
+	Migrate,
+	/// Export every table to an NDJSON archive
+	Backup { file: PathBuf },
+	/// Replace every table with the contents of an NDJSON archive
+	Restore {
+		file: PathBuf,
+		/// Confirm replacing all data
+		#[arg(long)]
+		confirm: bool,
+	},
+}
+
+impl Command {
+	pub async fn run(self) -> Result<(), Error> {
+		if self.uses_database() {
+			dbrecord::define_indexes().await?;
+
+			// Restored archives may predate migrations, so they run afterwards
+			if !matches!(self, Command::Restore { .. }) {
+				migrations::migrate().await?;
+			}
+		}
+
+		match self {
+			Command::Serve => {
+				SigningKeys::get()?;
+				log::info!("Starting...");
+				let shutdown = Shutdown::new();
+				shutdown.listen_for_signals();
+				let jobs = jobs::Job::spawn_all(&shutdown);
+				let bot = tokio::spawn(kavabot::start_bot(shutdown.clone()));
+
+				web::start_web(shutdown.clone()).await;
+				log::info!("Shutting down...");
+				shutdown.trigger();
+				Shutdown::join("Bot", vec![bot]).await;
+				Shutdown::join("Jobs", jobs).await;
+			}
+			Command::BotOnly => {
+				log::info!("Starting bot...");
+				let shutdown = Shutdown::new();
+				shutdown.listen_for_signals();
+				kavabot::start_bot(shutdown).await;
+				log::info!("Shutting down...");
+			}
+			Command::WebOnly => {
+				SigningKeys::get()?;
+				log::info!("Starting web server...");
+				let shutdown = Shutdown::new();
+				shutdown.listen_for_signals();
+				let jobs = jobs::Job::spawn_all(&shutdown);
+
+				web::start_web(shutdown.clone()).await;
+				log::info!("Shutting down...");
+				shutdown.trigger();
+				Shutdown::join("Jobs", jobs).await;
+			}
+			Command::Seed {
+				users,
+				months,
+				games_per_week,
+				seed,
+				confirm,
+			} => {
+				require_confirmation(confirm, "Seeding replaces all data.")?;
+
+				seed::seed(&SeedOptions {
+					users,
+					months,
+					games_per_week,
+					seed,
+				})
+				.await?;
+			}
+			Command::CreateAdmin {
+				username,
+				display_name,
+			} => {
+				let username = User::validate_username_requirements(&username)?;
+
+				let display_name = match display_name {
+					Some(display_name) => User::validate_displayname_requirements(&display_name)?,
+					None => username.to_owned(),
+				};
+
+				let password = random_alphanumeric_string(GENERATED_PASSWORD_LENGTH);
+				User::verify_password_requirements(&password)?;
+
+				// Hashed before the admin is created, so it never exists without a password
+				let admin = User {
+					username,
+					display_name,
+					password_hash: HashedString::new(&password)?,
+					roles: vec![Role::Admin],
+					..Default::default()
+				};
+
+				let mut transaction = DBTransaction::new();
+				transaction.create(&admin)?;
+				transaction.commit().await?;
+
+				log::info!("Created admin {}", admin.username);
+
+				// Printed rather than logged, so the password isn't kept in the logs
+				println!("Password: {}", password);
+			}
+			Command::GrantRole { username, role } => {
+				let mut user = find_user(&username).await?;
+
+				if user.has_role(&role) {
+					log::info!("{} already has that role", user.username);
+					return Ok(());
+				}
+
+				let mut roles = user.roles.clone();
+				roles.push(role);
+				user.db_update_field("roles", &roles).await?;
+				log::info!("Granted role to {}", user.username);
+			}
+			Command::RevokeRole { username, role } => {
+				let mut user = find_user(&username).await?;
+
+				if !user.has_role(&role) {
+					log::info!("{} doesn't have that role", user.username);
+					return Ok(());
+				}
+
+				user.remove_role(&role).await?;
+				log::info!("Revoked role from {}", user.username);
+			}
+			Command::RevokeSessions {
+				username,
+				all,
+				confirm,
+			} => {
+				require_confirmation(confirm, "Revoking sessions signs users out.")?;
+
+				let sessions = match (username, all) {
+					(_, true) => Session::db_all().await?,
+					(Some(username), false) => {
+						let user = find_user(&username).await?;
+						Session::db_search("user", user.uuid).await?
+					}
+					(None, false) => unreachable!("clap requires a username without --all"),
+				};
+
+				let mut transaction = DBTransaction::new();
+
+				for session in sessions.iter() {
+					transaction.delete(session).await?;
+				}
+
+				transaction.commit().await?;
+				log::info!("Revoked {} sessions", sessions.len());
+			}
+			Command::GenerateSigningKey { kid } => {
+				let directory = PathBuf::from(Environment::new().oauth_jwt_keys_directory.val());
+				let path = SigningKeys::generate(&directory, &kid)?;
+				log::info!("Generated {}", path.display());
+				log::info!("Set OAUTH_JWT_SIGNING_KEY_ID to {} to sign with it", kid);
+			}
+			Command::Migrate => {
+				log::info!("Database is up to date");
+			}
+			Command::Backup { file } => {
+				let lines = backup::backup(&file).await?;
+				log::info!("Wrote backup to {} ({} lines)", file.display(), lines);
+			}
+			Command::Restore { file, confirm } => {
+				require_confirmation(confirm, "Restoring replaces all data.")?;
+				let lines = backup::restore(&file).await?;
+				migrations::migrate().await?;
+				log::info!("Restored backup from {} ({} lines)", file.display(), lines);
+			}
+		}
+
+		Ok(())
+	}
+
+	fn uses_database(&self) -> bool {
+		!matches!(self, Command::GenerateSigningKey { .. })
+	}
+}
+
+fn require_confirmation(confirm: bool, warning: &str) -> Result<(), Error> {
+	if confirm {
+		Ok(())
+	} else {
+		Err(Error::new(
+			Status::BadRequest,
+			&format!("{} Pass --confirm to continue.", warning),
+			None,
+		))
+	}
+}
+
+async fn find_user(username: &str) -> Result<User, Error> {
+	User::db_search_one("username", username.to_lowercase())
+		.await?
+		.ok_or_else(Error::user_not_found)
+}
@@ -1,4 +1,4 @@
-use crate::{dbrecord::DBRecord, models::registration::Registration};
+use crate::{dbrecord::DBRecord, generic::Expirable, models::registration::Registration};
 use serenity::{
 	all::User,
 	builder::{CreateCommand, CreateMessage},
@@ -20,8 +20,16 @@ pub async fn run(ctx: &Context, user: &User) -> String {
 		}
 	}
 
-	match Registration::db_search_one("discord_id", user_id.clone()).await {
-		Ok(Some(existing)) => return existing.dm_string(),
+	match Registration::find_by_discord_id(&user_id).await {
+		Ok(Some(existing)) if !existing.is_expired().unwrap_or(true) => {
+			return existing.dm_string()
+		}
+		Ok(Some(expired)) => {
+			if let Err(e) = expired.db_delete().await {
+				log::error!("Registration delete error on registration: {}", e);
+				return "Internal server error".to_owned();
+			}
+		}
 		Err(e) => {
 			log::error!("Registration search error on registration: {}", e);
 			return "Internal server error".to_owned();
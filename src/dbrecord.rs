@@ -1,16 +1,39 @@
 use crate::{
 	error::Error,
-	generic::{surrealdb_client, UUID},
+	generic::{surrealdb_client, Environment, UUID},
+	models::{
+		audit_entry::{AuditEntry, AuditOperation},
+		authorization_code::AuthorizationCode,
+		discord_login::DiscordLogin,
+		job_run::JobRun,
+		job_state::JobState,
+		oauth_client::OAuthClient,
+		passkey::Passkey,
+		password_reset::PasswordReset,
+		pool_game::PoolGame,
+		pool_player::PoolPlayer,
+		registration::Registration,
+		session::Session,
+		user::{User, CREATED_REFERRAL, PLAYS_AS, REFERRED},
+		webauthn_challenge::WebAuthnChallenge,
+	},
 };
 use async_trait::async_trait;
-use chrono::Utc;
-use serde::{de::DeserializeOwned, Serialize};
+use chrono::{DateTime, Duration, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
 	any::Any,
 	collections::HashMap,
 	fmt::{Display, Formatter},
+	marker::PhantomData,
 };
-use surrealdb::sql::Thing;
+use surrealdb::{engine::remote::ws::Client, opt::Resource, sql::Thing, Surreal};
+
+/// Thrown by a transaction to reject an update of a record whose version has changed
+pub const STALE_WRITE_ERROR: &str = "Stale write rejected";
+
+/// Used by `purge_expired_trash()` if `TRASH_RETENTION_DAYS` is not configured
+pub const DEFAULT_TRASH_RETENTION_DAYS: i64 = 90;
 
 /// Methods associated with SurrealDB tables
 ///
@@ -24,7 +47,18 @@ pub trait DBRecord: Any + Serialize + DeserializeOwned + Send + Sync {
 	/// Get the UUID associated with the record
 	fn uuid(&self) -> UUID<Self>;
 
+	/// Get the version of the record, which is incremented by every update.
+	///
+	/// Stored in a `version` field, which should default to 0 for records created before it existed.
+	fn version(&self) -> u64;
+
+	/// Set the version of the record.
+	///
+	/// Set it to a version provided by a client to reject their update if the record has changed since.
+	fn set_version(&mut self, version: u64);
+
 	/// Get the Thing (`surrealdb::sql::thing::Thing`) associated with the record
+	#[allow(dead_code)]
 	fn thing(&self) -> Thing {
 		self.uuid().thing()
 	}
@@ -34,12 +68,56 @@ pub trait DBRecord: Any + Serialize + DeserializeOwned + Send + Sync {
 		false
 	}
 
-	/// This method is called immediately before a record is deleted by `db_delete()`.
+	/// Whether mutations made through a `DBTransaction` should be recorded as an `AuditEntry`
+	fn audited() -> bool {
+		true
+	}
+
+	/// Fields whose values are replaced with `"[redacted]"` in an `AuditEntry`
+	fn sensitive_fields() -> Vec<&'static str> {
+		vec![]
+	}
+
+	/// Names of the relation tables with edges to or from records in the table.
+	///
+	/// SurrealDB deletes a record's edges along with it, so these edges are saved
+	/// with trashed records and recreated by `db_restore()`.
+	fn relations() -> Vec<&'static str> {
+		vec![]
+	}
+
+	/// Get the name of the table that records are moved to if `use_trash()` is set
+	fn trash_table() -> String {
+		format!("z_trashed_{}", Self::table())
+	}
+
+	/// Fields that must hold a distinct value for every record in the table.
 	///
-	/// Override this method to perform checks or cleanup tasks before the object's deletion.
+	/// A unique index is defined for each field by `db_define_indexes()`.
+	/// Writes that would violate it fail with a 409 (Conflict) `Error`.
+	///
+	/// Records where the field is `None` are not subject to the constraint.
+	fn unique_fields() -> Vec<&'static str> {
+		vec![]
+	}
+
+	/// This method is called immediately before a record's deletion is queued in a `DBTransaction`.
+	///
+	/// Override this method to perform checks, or to queue cleanup tasks in `transaction`
+	/// so that they are applied atomically with the object's deletion.
 	///
 	/// If the method returns an `Error`, the deletion will be aborted and `db_delete()` will return the error.
-	async fn delete_hook(&self) -> Result<(), Error> {
+	async fn delete_hook(&self, _transaction: &mut DBTransaction) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// This method is called immediately before a trashed record is restored by `db_restore()`.
+	///
+	/// Override this method to clear stale state, or to queue tasks in `transaction`
+	/// that re-link references removed by `delete_hook()`.
+	///
+	/// If the method returns an `Error`, the restoration will be aborted and `db_restore()` will return the error.
+	async fn restore_hook(&mut self, _transaction: &mut DBTransaction) -> Result<(), Error> {
 		Ok(())
 	}
 
@@ -101,53 +179,27 @@ pub trait DBRecord: Any + Serialize + DeserializeOwned + Send + Sync {
 
 	/// Add a new record to the database and return it.
 	async fn db_create(&self) -> Result<Self, Error> {
-		let db = surrealdb_client().await?;
 		let serde_value = serde_json::to_value(self)?;
-		let id = self.uuid().uuid_string();
+		let mut transaction = DBTransaction::new();
+		transaction.create(self)?;
+		transaction.commit().await?;
 
-		let opt: Option<Self> = db
-			.create((Self::table(), id.to_owned()))
-			.content(serde_value)
-			.await?;
-
-		match opt {
-			Some(e) => Ok(e),
-			None => Err(Error::generic_500("Failed to create record")),
-		}
+		// The record is stored exactly as it was serialized
+		Ok(serde_json::from_value(serde_value)?)
 	}
 
-	/// Delete a record from the database.
+	/// Delete a record from the database, along with anything queued by `delete_hook()`.
 	async fn db_delete(&self) -> Result<(), Error> {
-		let db = surrealdb_client().await?;
-		self.delete_hook().await?;
-
-		if Self::use_trash() {
-			let created: Option<Self> = db
-				.create((
-					format!("z_trashed_{}", Self::table()),
-					self.uuid().uuid_string(),
-				))
-				.content(serde_json::to_value(self)?)
-				.await?;
-
-			created.ok_or_else(|| {
-				Error::generic_500(&format!(
-					"Failed to create trash table record: {}",
-					self.uuid().uuid_string()
-				))
-			})?;
-		}
-
-		let thing = self.thing();
-		let _: Option<Self> = db.delete((thing.tb, self.uuid().uuid_string())).await?;
-		Ok(())
+		let mut transaction = DBTransaction::new();
+		transaction.delete(self).await?;
+		transaction.commit().await
 	}
 
 	/// Update a single field of a record in the database.
 	///
 	/// Use `db_update_fields()` to update multiple fields at once.
 	async fn db_update_field<T: Serialize + Sync>(
-		&self,
+		&mut self,
 		field: &str,
 		value: &T,
 	) -> Result<(), Error> {
@@ -155,27 +207,20 @@ pub trait DBRecord: Any + Serialize + DeserializeOwned + Send + Sync {
 		Ok(())
 	}
 
-	/// Update several fields of a record in the database at once.
+	/// Update several fields of a record in the database at once, and increment its version.
 	///
 	/// The first value of the tuple is the field name, and the second is the value to set.
+	///
+	/// Returns a 409 (Conflict) `Error` if the record's version in the database
+	/// doesn't match `version()`, as the update would overwrite changes made since it was loaded.
 	async fn db_update_fields<T: Serialize + Sync + Send + Clone>(
-		&self,
+		&mut self,
 		updates: Vec<(&str, T)>,
 	) -> Result<(), Error> {
-		let mut merge_data = HashMap::<String, serde_json::Value>::new();
-		merge_data.insert("updated_at".to_owned(), serde_json::to_value(Utc::now())?);
-
-		for update in updates {
-			merge_data.insert(update.0.to_owned(), serde_json::to_value(update.1.clone())?);
-		}
-
-		let db = surrealdb_client().await?;
-
-		let _: Option<Self> = db
-			.update((Self::table(), self.uuid().uuid_string()))
-			.merge(merge_data)
-			.await?;
-
+		let mut transaction = DBTransaction::new();
+		transaction.update_fields(self, updates)?;
+		transaction.commit().await?;
+		self.set_version(self.version() + 1);
 		Ok(())
 	}
 
@@ -190,6 +235,48 @@ pub trait DBRecord: Any + Serialize + DeserializeOwned + Send + Sync {
 		Ok(value)
 	}
 
+	/// Get all records in a single query, with each `(field, table)` in `fetch` replaced by the record it references.
+	///
+	/// `E` is the expanded representation of the record. Fields referencing a missing record are `null`.
+	async fn db_all_fetched<E: DeserializeOwned>(fetch: &[(&str, &str)]) -> Result<Vec<E>, Error> {
+		let db = surrealdb_client().await?;
+		db.set("table", Self::table()).await?;
+
+		// UUIDs are stored as `table:id` strings, which FETCH doesn't follow
+		let links: Vec<String> = fetch
+			.iter()
+			.map(|(field, table)| {
+				format!(
+					"type::thing('{}', string::split({}, ':')[1]) AS {}",
+					table, field, field
+				)
+			})
+			.collect();
+
+		let fields: Vec<&str> = fetch.iter().map(|(field, _)| *field).collect();
+
+		let query = format!(
+			"SELECT *, {} FROM type::table($table) FETCH {}",
+			links.join(", "),
+			fields.join(", ")
+		);
+
+		let mut response = db.query(query).await?;
+		let result: surrealdb::Value = response.take(0)?;
+		let serde_value = result.into_inner().into_json();
+		let value: Vec<E> = serde_json::from_value(serde_value)?;
+		Ok(value)
+	}
+
+	/// Subscribe to changes to records in the table with a SurrealDB `LIVE SELECT`.
+	///
+	/// The live query is killed when the stream is dropped.
+	async fn db_live() -> Result<surrealdb::method::Stream<surrealdb::Value>, Error> {
+		let db = surrealdb_client().await?;
+		let stream = db.select(Resource::from(Self::table())).live().await?;
+		Ok(stream)
+	}
+
 	/// For each record in the table, add any missing properties with default values to the record in the database.
 	///
 	/// Record retrieval already uses default values for missing fields, but this exists just in case it's ever needed.
@@ -210,6 +297,115 @@ pub trait DBRecord: Any + Serialize + DeserializeOwned + Send + Sync {
 		Ok(())
 	}
 
+	/// Get every record in the trash table, with the time each was trashed.
+	async fn db_trashed() -> Result<Vec<Trashed<Self>>, Error> {
+		let db = surrealdb_client().await?;
+		db.set("table", Self::trash_table()).await?;
+		let mut response = db.query("SELECT * FROM type::table($table)").await?;
+		let result: surrealdb::Value = response.take(0)?;
+		let serde_value = result.into_inner().into_json();
+		let value: Vec<Trashed<Self>> = serde_json::from_value(serde_value)?;
+		Ok(value)
+	}
+
+	/// Get a record from the trash table by its ID, or `None` if not found.
+	async fn db_trashed_by_id(id: &str) -> Result<Option<Trashed<Self>>, Error> {
+		let db = surrealdb_client().await?;
+		db.set("table", Self::trash_table()).await?;
+		db.set("id", id.to_owned()).await?;
+
+		let mut response = db.query("SELECT * FROM type::thing($table, $id)").await?;
+
+		let result: surrealdb::Value = response.take(0)?;
+		let serde_value = result.into_inner().into_json();
+		let value: Vec<Trashed<Self>> = serde_json::from_value(serde_value)?;
+		Ok(value.into_iter().next())
+	}
+
+	/// Move a record from the trash table back to the table, recreate its edges, and return it.
+	///
+	/// Edges to records that no longer exist are not recreated.
+	///
+	/// Returns a 404 `Error` if the record is not in the trash,
+	/// or a 409 `Error` if restoring it would violate a unique field.
+	async fn db_restore(id: &str) -> Result<Self, Error> {
+		let trashed = Self::db_trashed_by_id(id)
+			.await?
+			.ok_or_else(|| Error::not_found("Trashed record not found"))?;
+
+		let mut record = trashed.record;
+		let mut transaction = DBTransaction::new();
+		record.restore_hook(&mut transaction).await?;
+		transaction.create(&record)?;
+
+		for edge in trashed.trashed_edges {
+			transaction.relate_edge(&edge)?;
+		}
+
+		transaction.purge(&record)?;
+		transaction.commit().await?;
+		Ok(record)
+	}
+
+	/// Permanently delete a record from the trash table.
+	///
+	/// Returns a 404 `Error` if the record is not in the trash.
+	async fn db_purge(id: &str) -> Result<(), Error> {
+		let trashed = Self::db_trashed_by_id(id)
+			.await?
+			.ok_or_else(|| Error::not_found("Trashed record not found"))?;
+
+		let mut transaction = DBTransaction::new();
+		transaction.purge(&trashed.record)?;
+		transaction.commit().await
+	}
+
+	/// Permanently delete every record in the trash table that was trashed before `time`, auditing each one.
+	///
+	/// Records trashed before `trashed_at` was recorded are kept.
+	async fn db_purge_trashed_before(time: DateTime<Utc>) -> Result<(), Error> {
+		let db = surrealdb_client().await?;
+		db.set("table", Self::trash_table()).await?;
+		db.set("value", time.timestamp()).await?;
+
+		let mut response = db
+			.query(
+				"SELECT * FROM type::table($table) WHERE trashed_at != NONE AND time::unix(type::datetime(trashed_at)) < $value",
+			)
+			.await?;
+
+		let result: surrealdb::Value = response.take(0)?;
+		let serde_value = result.into_inner().into_json();
+		let trashed: Vec<Trashed<Self>> = serde_json::from_value(serde_value)?;
+		let mut transaction = DBTransaction::new();
+
+		for trashed in &trashed {
+			transaction.purge(&trashed.record)?;
+		}
+
+		transaction.commit().await
+	}
+
+	/// Define the unique indexes listed by `unique_fields()`, if they don't already exist.
+	///
+	/// Returns an `Error` if existing records already violate a constraint.
+	async fn db_define_indexes() -> Result<(), Error> {
+		let db = surrealdb_client().await?;
+		let table = Self::table();
+
+		for field in Self::unique_fields() {
+			db.query(format!(
+				"DEFINE INDEX IF NOT EXISTS {}{} ON TABLE {} FIELDS {} UNIQUE",
+				UNIQUE_INDEX_PREFIX, field, table, field
+			))
+			.await?
+			.check()?;
+		}
+
+		Ok(())
+	}
+
+	#[allow(dead_code)]
 	async fn db_delete_table() -> Result<(), Error> {
 		let db = surrealdb_client().await?;
 		let table = Self::table();
@@ -219,16 +415,813 @@ pub trait DBRecord: Any + Serialize + DeserializeOwned + Send + Sync {
 	}
 }
 
+/// A record in a trash table, with the time it was trashed.
+#[derive(Serialize, Deserialize)]
+pub struct Trashed<T> {
+	#[serde(flatten)]
+	pub record: T,
+	/// `None` if the record was trashed before this was recorded
+	pub trashed_at: Option<DateTime<Utc>>,
+	/// Edges of the `DBRecord::relations()` the record had when it was trashed
+	#[serde(default)]
+	pub trashed_edges: Vec<Edge>,
+}
+
+/// A SurrealDB relation table, with edges from `In` records to `Out` records, i.e. `In->relation->Out`.
+///
+/// Edges are created and deleted with a `DBTransaction`, and traversed with `UUID::related()` and `UUID::related_by()`.
+pub struct Relation<In, Out>(&'static str, PhantomData<(In, Out)>);
+
+impl<In: DBRecord, Out: DBRecord> Relation<In, Out> {
+	pub const fn new(name: &'static str) -> Self {
+		Self(name, PhantomData)
+	}
+
+	/// Get the name of the relation table
+	pub fn name(&self) -> &'static str {
+		self.0
+	}
+
+	/// Define a unique index on the `in` of the edges, if it doesn't already exist,
+	/// so each `In` record is related to at most one `Out` record.
+	pub async fn db_define_unique_in(&self) -> Result<(), Error> {
+		let db = surrealdb_client().await?;
+
+		db.query(format!(
+			"DEFINE INDEX IF NOT EXISTS {}{} ON TABLE {} FIELDS in UNIQUE",
+			UNIQUE_INDEX_PREFIX,
+			self.name(),
+			self.name()
+		))
+		.await?
+		.check()?;
+
+		Ok(())
+	}
+
+	/// Get the `In` and `Out` IDs of every edge in a single query, to map records to each other in memory.
+	pub async fn all_ends(&self) -> Result<Vec<(UUID<In>, UUID<Out>)>, Error> {
+		Ok(Edge::all(self.name())
+			.await?
+			.into_iter()
+			.map(|edge| {
+				(
+					Thing::from((edge.in_table, edge.in_id)).into(),
+					Thing::from((edge.out_table, edge.out_id)).into(),
+				)
+			})
+			.collect())
+	}
+
+	/// Get the `Out` records related to the `In` record with the given ID.
+	pub async fn outgoing(&self, id: &str) -> Result<Vec<Out>, Error> {
+		self.traverse("out", "in", In::table(), id).await
+	}
+
+	/// Get the `In` records related to the `Out` record with the given ID.
+	pub async fn incoming(&self, id: &str) -> Result<Vec<In>, Error> {
+		self.traverse("in", "out", Out::table(), id).await
+	}
+
+	async fn traverse<T: DBRecord>(
+		&self,
+		select: &str,
+		r#where: &str,
+		table: &str,
+		id: &str,
+	) -> Result<Vec<T>, Error> {
+		let db = surrealdb_client().await?;
+		db.set("relation", self.name()).await?;
+		db.set("table", table.to_owned()).await?;
+		db.set("id", id.to_owned()).await?;
+
+		let query = format!(
+			"SELECT * FROM (SELECT VALUE {} FROM type::table($relation) WHERE {} = type::thing($table, $id))",
+			select, r#where
+		);
+
+		let mut response = db.query(query).await?;
+		let result: surrealdb::Value = response.take(0)?;
+		let serde_value = result.into_inner().into_json();
+		let value: Vec<T> = serde_json::from_value(serde_value)?;
+		Ok(value)
+	}
+}
+
+/// An edge in a relation table, saved with a trashed record.
+#[derive(Serialize, Deserialize)]
+pub struct Edge {
+	relation: String,
+	in_table: String,
+	in_id: String,
+	out_table: String,
+	out_id: String,
+}
+
+impl Edge {
+	/// Get the name of the relation table
+	pub fn relation(&self) -> &str {
+		&self.relation
+	}
+
+	/// Get every edge in a relation table.
+	pub async fn all(relation: &str) -> Result<Vec<Self>, Error> {
+		let db = surrealdb_client().await?;
+		Self::select(&db, relation, "true").await
+	}
+
+	/// Get the edges to or from a record in each of `relations`.
+	async fn of_record(table: &str, id: &str, relations: Vec<&str>) -> Result<Vec<Self>, Error> {
+		let db = surrealdb_client().await?;
+		db.set("table", table.to_owned()).await?;
+		db.set("id", id.to_owned()).await?;
+		let mut edges = vec![];
+
+		for relation in relations {
+			let condition = "in = type::thing($table, $id) OR out = type::thing($table, $id)";
+			edges.extend(Self::select(&db, relation, condition).await?);
+		}
+
+		Ok(edges)
+	}
+
+	async fn select(
+		db: &Surreal<Client>,
+		relation: &str,
+		condition: &str,
+	) -> Result<Vec<Self>, Error> {
+		db.set("relation", relation.to_owned()).await?;
+
+		let query = format!(
+			"SELECT record::tb(in) AS in_table, record::id(in) AS in_id, record::tb(out) AS out_table, record::id(out) AS out_id \
+			FROM type::table($relation) WHERE {}",
+			condition
+		);
+
+		let mut response = db.query(query).await?;
+		let result: surrealdb::Value = response.take(0)?;
+		let serde_value = result.into_inner().into_json();
+
+		#[derive(Deserialize)]
+		struct EdgeEnds {
+			in_table: String,
+			in_id: String,
+			out_table: String,
+			out_id: String,
+		}
+
+		let ends: Vec<EdgeEnds> = serde_json::from_value(serde_value)?;
+
+		Ok(ends
+			.into_iter()
+			.map(|e| Self {
+				relation: relation.to_owned(),
+				in_table: e.in_table,
+				in_id: e.in_id,
+				out_table: e.out_table,
+				out_id: e.out_id,
+			})
+			.collect())
+	}
+}
+
+/// Build the data merged into a record by an update, stamping `updated_at`.
+fn merge_data<T: Serialize>(
+	updates: Vec<(&str, T)>,
+) -> Result<HashMap<String, serde_json::Value>, Error> {
+	let mut merge_data = HashMap::<String, serde_json::Value>::new();
+	merge_data.insert("updated_at".to_owned(), serde_json::to_value(Utc::now())?);
+
+	for update in updates {
+		merge_data.insert(update.0.to_owned(), serde_json::to_value(update.1)?);
+	}
+
+	Ok(merge_data)
+}
+
+/// Replace the `sensitive_fields()` of `T` in an audited `value` with "[redacted]".
+fn redact<T: DBRecord>(value: Option<serde_json::Value>) -> Option<serde_json::Value> {
+	value.map(|mut value| {
+		if let Some(object) = value.as_object_mut() {
+			for field in T::sensitive_fields() {
+				if let Some(v) = object.get_mut(field) {
+					*v = "[redacted]".into();
+				}
+			}
+		}
+
+		value
+	})
+}
+
+/// Whether `field` can be used as a field name in a statement without escaping.
+fn is_identifier(field: &str) -> bool {
+	!field.is_empty() && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A set of record operations that are applied atomically by `commit()`.
+///
+/// SurrealDB transactions can't span multiple requests, so operations are queued and sent together
+/// as a single `BEGIN TRANSACTION; ... COMMIT TRANSACTION;` query.
+/// If any operation fails, none of them are applied.
+#[derive(Default)]
+pub struct DBTransaction {
+	statements: Vec<String>,
+	bindings: Vec<(String, serde_json::Value)>,
+}
+
+impl DBTransaction {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Bind a value to a new query parameter and return the parameter as it appears in a statement.
+	fn bind<T: Serialize>(&mut self, value: T) -> Result<String, Error> {
+		let name = format!("p{}", self.bindings.len());
+		self.bindings
+			.push((name.to_owned(), serde_json::to_value(value)?));
+		Ok(format!("${}", name))
+	}
+
+	/// Bind a record ID and return an expression for its Thing.
+	fn bind_thing(&mut self, table: &str, id: &str) -> Result<String, Error> {
+		let table = self.bind(table)?;
+		let id = self.bind(id)?;
+		Ok(format!("type::thing({}, {})", table, id))
+	}
+
+	/// Queue the creation of a new record.
+	///
+	/// The transaction fails if a record with the same ID already exists.
+	pub fn create<T: DBRecord>(&mut self, record: &T) -> Result<(), Error> {
+		let id = record.uuid().uuid_string();
+		self.create_in(T::table(), &id, record)?;
+		let after = serde_json::to_value(record)?;
+		self.audit::<T>(T::table(), &id, AuditOperation::Create, None, Some(after))
+	}
+
+	/// Queue the creation of a record in any table without an `AuditEntry`, e.g. when restoring a backup.
+	pub fn create_in<C: Serialize>(
+		&mut self,
+		table: &str,
+		id: &str,
+		content: C,
+	) -> Result<(), Error> {
+		let thing = self.bind_thing(table, id)?;
+		let content = self.bind(content)?;
+		self.statements
+			.push(format!("CREATE {} CONTENT {}", thing, content));
+		Ok(())
+	}
+
+	/// Queue an update of several fields of a record, as with `DBRecord::db_update_fields()`.
+	///
+	/// The transaction fails with a 409 (Conflict) `Error` if the record's version has changed.
+	/// The version of `record` itself is not incremented.
+	///
+	/// The audited previous values are read from the stored record as the transaction updates it.
+	pub fn update_fields<T: DBRecord, V: Serialize>(
+		&mut self,
+		record: &T,
+		updates: Vec<(&str, V)>,
+	) -> Result<(), Error> {
+		let id = record.uuid().uuid_string();
+		let thing = self.bind_thing(T::table(), &id)?;
+		let mut merge_data = merge_data(updates)?;
+		merge_data.insert("version".to_owned(), (record.version() + 1).into());
+
+		let after: serde_json::Map<String, serde_json::Value> = merge_data
+			.iter()
+			.filter(|(field, _)| *field != "updated_at" && *field != "version")
+			.map(|(field, value)| (field.to_owned(), value.to_owned()))
+			.collect();
+
+		let fields: Vec<String> = after.keys().cloned().collect();
+		let merge_data = self.bind(merge_data)?;
+		let version = self.bind(record.version())?;
+		let stale = self.bind(format!("{}: {}:{}", STALE_WRITE_ERROR, T::table(), id))?;
+		let stored = format!("$stored{}", self.statements.len());
+
+		self.statements.push(format!(
+			"LET {} = (UPDATE {} MERGE {} WHERE (version ?? 0) = {} RETURN BEFORE)",
+			stored, thing, merge_data, version
+		));
+
+		self.statements.push(format!(
+			"IF array::len({}) = 0 {{ THROW {} }}",
+			stored, stale
+		));
+
+		self.audit_stored::<T>(
+			T::table(),
+			&id,
+			AuditOperation::Update,
+			&stored,
+			Some(&fields),
+			Some(after.into()),
+		)
+	}
+
+	/// Queue the deletion of a record, preceded by anything queued by its `delete_hook()`.
+	///
+	/// If `use_trash()` is set, the record is copied to its trash table with a `trashed_at` timestamp.
+	///
+	/// The transaction fails if the record no longer exists when it is committed,
+	/// so a record can only be consumed once (e.g. a `Registration`).
+	pub async fn delete<T: DBRecord>(&mut self, record: &T) -> Result<(), Error> {
+		record.delete_hook(self).await?;
+		let id = record.uuid().uuid_string();
+
+		if T::use_trash() {
+			let mut content = serde_json::to_value(record)?;
+			content["trashed_at"] = serde_json::to_value(Utc::now())?;
+			let edges = Edge::of_record(T::table(), &id, T::relations()).await?;
+			content["trashed_edges"] = serde_json::to_value(edges)?;
+			self.create_in(&T::trash_table(), &id, content)?;
+		}
+
+		let stored = self.delete_in(T::table(), &id)?;
+		self.audit_stored::<T>(T::table(), &id, AuditOperation::Delete, &stored, None, None)
+	}
+
+	/// Queue the permanent deletion of a record from its trash table.
+	pub fn purge<T: DBRecord>(&mut self, record: &T) -> Result<(), Error> {
+		let id = record.uuid().uuid_string();
+		let stored = self.delete_in(&T::trash_table(), &id)?;
+		self.audit_stored::<T>(
+			&T::trash_table(),
+			&id,
+			AuditOperation::Purge,
+			&stored,
+			None,
+			None,
+		)
+	}
+
+	/// Queue the creation of an edge `from->relation->to`.
+	///
+	/// The edge is only created if both records exist when the transaction is committed.
+	pub fn relate<In: DBRecord, Out: DBRecord>(
+		&mut self,
+		relation: &Relation<In, Out>,
+		from: &UUID<In>,
+		to: &UUID<Out>,
+	) -> Result<(), Error> {
+		self.relate_edge(&Edge {
+			relation: relation.name().to_owned(),
+			in_table: In::table().to_owned(),
+			in_id: from.uuid_string(),
+			out_table: Out::table().to_owned(),
+			out_id: to.uuid_string(),
+		})
+	}
+
+	fn relate_edge(&mut self, edge: &Edge) -> Result<(), Error> {
+		self.insert_edge(edge)?;
+		self.audit_relation(edge, AuditOperation::Create)
+	}
+
+	/// Queue the creation of an edge without an `AuditEntry`, e.g. when restoring a backup.
+	pub fn insert_edge(&mut self, edge: &Edge) -> Result<(), Error> {
+		let from = self.bind_thing(&edge.in_table, &edge.in_id)?;
+		let to = self.bind_thing(&edge.out_table, &edge.out_id)?;
+
+		// RELATE doesn't accept function calls as endpoints
+		let from_param = format!("$from{}", self.statements.len());
+		let to_param = format!("$to{}", self.statements.len());
+		self.statements
+			.push(format!("LET {} = {}", from_param, from));
+		self.statements.push(format!("LET {} = {}", to_param, to));
+
+		self.statements.push(format!(
+			"IF record::exists({}) AND record::exists({}) {{ RELATE {}->{}->{} }}",
+			from_param, to_param, from_param, edge.relation, to_param
+		));
+
+		Ok(())
+	}
+
+	/// Queue the creation of the records staged for `table` in the `staging` table without `AuditEntry`s,
+	/// e.g. when restoring. With `keep_existing`, records whose ID exists are left as they are.
+	///
+	/// Staged records have a `line` field holding a `record` line of a backup archive.
+	pub fn create_staged(
+		&mut self,
+		staging: &str,
+		table: &str,
+		keep_existing: bool,
+	) -> Result<(), Error> {
+		let staging = self.bind(staging)?;
+		let table = self.bind(table)?;
+		let thing = format!("type::thing({}, $staged.id)", table);
+
+		let create = if keep_existing {
+			format!(
+				"IF !record::exists({}) {{ CREATE {} CONTENT $staged.content }}",
+				thing, thing
+			)
+		} else {
+			format!("CREATE {} CONTENT $staged.content", thing)
+		};
+
+		self.statements.push(format!(
+			"FOR $staged IN (SELECT VALUE line FROM type::table({}) WHERE line.type = 'record' AND line.table = {}) {{ {} }}",
+			staging, table, create
+		));
+
+		Ok(())
+	}
+
+	/// Queue the creation of the edges staged for `relation` in the `staging` table, like `insert_edge()`.
+	///
+	/// Staged edges have a `line` field holding an `edge` line of a backup archive.
+	pub fn insert_staged_edges(&mut self, staging: &str, relation: &str) -> Result<(), Error> {
+		// RELATE doesn't accept a parameter as the relation table
+		if !is_identifier(relation) {
+			return Err(Error::generic_500(&format!(
+				"Invalid relation name: {}",
+				relation
+			)));
+		}
+
+		let staging = self.bind(staging)?;
+		let relation_param = self.bind(relation)?;
+
+		self.statements.push(format!(
+			"FOR $staged IN (SELECT VALUE line FROM type::table({}) WHERE line.type = 'edge' AND line.relation = {}) {{ \
+			LET $from = type::thing($staged.in_table, $staged.in_id); \
+			LET $to = type::thing($staged.out_table, $staged.out_id); \
+			IF record::exists($from) AND record::exists($to) {{ RELATE $from->{}->$to }} }}",
+			staging, relation_param, relation
+		));
+
+		Ok(())
+	}
+
+	/// Queue the deletion of every edge `from->relation->to`.
+	pub fn unrelate<In: DBRecord, Out: DBRecord>(
+		&mut self,
+		relation: &Relation<In, Out>,
+		from: &UUID<In>,
+		to: &UUID<Out>,
+	) -> Result<(), Error> {
+		let table = self.bind(relation.name())?;
+		let from_thing = self.bind_thing(In::table(), &from.uuid_string())?;
+		let to_thing = self.bind_thing(Out::table(), &to.uuid_string())?;
+
+		self.statements.push(format!(
+			"DELETE type::table({}) WHERE in = {} AND out = {}",
+			table, from_thing, to_thing
+		));
+
+		self.audit_relation(
+			&Edge {
+				relation: relation.name().to_owned(),
+				in_table: In::table().to_owned(),
+				in_id: from.uuid_string(),
+				out_table: Out::table().to_owned(),
+				out_id: to.uuid_string(),
+			},
+			AuditOperation::Delete,
+		)
+	}
+
+	/// Queue the deletion of every record in a table, e.g. when restoring a backup.
+	///
+	/// Audited as a single `Clear` of the table, without the deleted records.
+	pub fn clear_table(&mut self, table: &str) -> Result<(), Error> {
+		let table_param = self.bind(table)?;
+		self.statements
+			.push(format!("DELETE type::table({})", table_param));
+
+		let entry = AuditEntry::new(table, "*", AuditOperation::Clear, None, None);
+		self.create_in(AuditEntry::table(), &entry.uuid.uuid_string(), &entry)
+	}
+
+	/// Queue the removal of `fields` from every record of type `T`, e.g. when a field is replaced by a relation.
+	pub fn unset_all<T: DBRecord>(&mut self, fields: &[&str]) -> Result<(), Error> {
+		let table = self.bind(T::table())?;
+
+		self.statements.push(format!(
+			"UPDATE type::table({}) UNSET {}",
+			table,
+			fields.join(", ")
+		));
+
+		Ok(())
+	}
+
+	/// Queue the creation of an `AuditEntry` for the creation or deletion of an edge.
+	fn audit_relation(&mut self, edge: &Edge, operation: AuditOperation) -> Result<(), Error> {
+		let record = format!(
+			"{}:{}->{}:{}",
+			edge.in_table, edge.in_id, edge.out_table, edge.out_id
+		);
+
+		let entry = AuditEntry::new(&edge.relation, &record, operation, None, None);
+		self.create_in(AuditEntry::table(), &entry.uuid.uuid_string(), &entry)
+	}
+
+	/// Queue the creation of an `AuditEntry` for a mutation of a record of type `T`,
+	/// unless `T` is not `audited()`.
+	fn audit<T: DBRecord>(
+		&mut self,
+		table: &str,
+		id: &str,
+		operation: AuditOperation,
+		before: Option<serde_json::Value>,
+		after: Option<serde_json::Value>,
+	) -> Result<(), Error> {
+		if !T::audited() {
+			return Ok(());
+		}
+
+		let entry = AuditEntry::new(
+			table,
+			id,
+			operation,
+			redact::<T>(before),
+			redact::<T>(after),
+		);
+
+		self.create_in(AuditEntry::table(), &entry.uuid.uuid_string(), &entry)
+	}
+
+	/// Queue the creation of an `AuditEntry` like `audit()`, with `before` read from the parameter `stored`,
+	/// which holds the record as it was stored before the mutation.
+	///
+	/// Only `fields` of the record are included in `before`, or all of them if `None`.
+	fn audit_stored<T: DBRecord>(
+		&mut self,
+		table: &str,
+		id: &str,
+		operation: AuditOperation,
+		stored: &str,
+		fields: Option<&[String]>,
+		after: Option<serde_json::Value>,
+	) -> Result<(), Error> {
+		if !T::audited() {
+			return Ok(());
+		}
+
+		let entry = AuditEntry::new(table, id, operation, None, redact::<T>(after));
+		let entry_id = entry.uuid.uuid_string();
+		self.create_in(AuditEntry::table(), &entry_id, &entry)?;
+		let entry_thing = self.bind_thing(AuditEntry::table(), &entry_id)?;
+
+		let before = match fields {
+			Some(fields) => {
+				if let Some(field) = fields.iter().find(|field| !is_identifier(field)) {
+					return Err(Error::generic_500(&format!(
+						"Invalid field name: {}",
+						field
+					)));
+				}
+
+				let fields: Vec<String> = fields
+					.iter()
+					.map(|field| format!("{}: {}[0].{}", field, stored, field))
+					.collect();
+
+				format!("{{ {} }}", fields.join(", "))
+			}
+			None => format!("{}[0]", stored),
+		};
+
+		self.statements.push(format!(
+			"UPDATE {} MERGE {{ before: {} }}",
+			entry_thing, before
+		));
+
+		let redacted: serde_json::Map<String, serde_json::Value> = T::sensitive_fields()
+			.into_iter()
+			.filter(|field| fields.is_none_or(|fields| fields.iter().any(|f| f == field)))
+			.map(|field| (field.to_owned(), "[redacted]".into()))
+			.collect();
+
+		if !redacted.is_empty() {
+			let redacted = self.bind(serde_json::json!({ "before": redacted }))?;
+
+			self.statements
+				.push(format!("UPDATE {} MERGE {}", entry_thing, redacted));
+		}
+
+		Ok(())
+	}
+
+	/// Queue the deletion of a record, returning the parameter that holds it as it was stored.
+	fn delete_in(&mut self, table: &str, id: &str) -> Result<String, Error> {
+		let thing = self.bind_thing(table, id)?;
+		let not_found = self.bind(format!("Record to delete not found: {}:{}", table, id))?;
+		let stored = format!("$stored{}", self.statements.len());
+
+		self.statements
+			.push(format!("LET {} = (DELETE {} RETURN BEFORE)", stored, thing));
+
+		self.statements.push(format!(
+			"IF array::len({}) = 0 {{ THROW {} }}",
+			stored, not_found
+		));
+
+		Ok(stored)
+	}
+
+	/// Apply every queued operation, or none of them if any fails.
+	pub async fn commit(self) -> Result<(), Error> {
+		if self.statements.is_empty() {
+			return Ok(());
+		}
+
+		let db = surrealdb_client().await?;
+		let mut query = db.query("BEGIN TRANSACTION");
+
+		for statement in self.statements {
+			query = query.query(statement);
+		}
+
+		query = query.query("COMMIT TRANSACTION");
+
+		for binding in self.bindings {
+			query = query.bind(binding);
+		}
+
+		let mut errors: Vec<(usize, surrealdb::Error)> =
+			query.await?.take_errors().into_iter().collect();
+
+		errors.sort_by_key(|(index, _)| *index);
+
+		// Every other statement reports that it was not executed,
+		// so return the error of the statement that caused the failure.
+		let cause = errors
+			.iter()
+			.position(|(_, e)| !e.to_string().starts_with("The query was not executed"))
+			.unwrap_or(0);
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(errors.swap_remove(cause).1.into())
+		}
+	}
+}
+
+/// Permanently delete trashed records older than the configured retention period.
+pub async fn purge_expired_trash() -> Result<(), Error> {
+	let retention_days = match Environment::new().trash_retention_days.val_opt() {
+		Some(days) => days
+			.parse::<i64>()
+			.map_err(|e| Error::generic_500(&format!("Invalid TRASH_RETENTION_DAYS: {}", e)))?,
+		None => DEFAULT_TRASH_RETENTION_DAYS,
+	};
+
+	let earliest_kept_time = Utc::now()
+		.checked_sub_signed(Duration::days(retention_days))
+		.ok_or(Error::generic_500(
+			"Out of bounds datetime in purge_expired_trash()",
+		))?;
+
+	User::db_purge_trashed_before(earliest_kept_time).await?;
+	PoolPlayer::db_purge_trashed_before(earliest_kept_time).await?;
+	Ok(())
+}
+
+/// Prefix of the names of indexes defined by `db_define_indexes()`, followed by the field name.
+pub const UNIQUE_INDEX_PREFIX: &str = "unique_";
+
+/// Define the indexes of every table.
+///
+/// Called on startup, before any records are written.
+pub async fn define_indexes() -> Result<(), Error> {
+	User::db_define_indexes().await?;
+	Registration::db_define_indexes().await?;
+	Session::db_define_indexes().await?;
+	PoolPlayer::db_define_indexes().await?;
+	PoolGame::db_define_indexes().await?;
+	AuditEntry::db_define_indexes().await?;
+	JobState::db_define_indexes().await?;
+	JobRun::db_define_indexes().await?;
+	OAuthClient::db_define_indexes().await?;
+	AuthorizationCode::db_define_indexes().await?;
+	DiscordLogin::db_define_indexes().await?;
+	Passkey::db_define_indexes().await?;
+	PasswordReset::db_define_indexes().await?;
+	WebAuthnChallenge::db_define_indexes().await?;
+	PLAYS_AS.db_define_unique_in().await?;
+	Ok(())
+}
+
+/// The tables of every `DBRecord`, including trash tables.
+pub fn record_tables() -> Vec<String> {
+	[
+		tables_of::<User>(),
+		tables_of::<Registration>(),
+		tables_of::<Session>(),
+		tables_of::<PoolPlayer>(),
+		tables_of::<PoolGame>(),
+		tables_of::<AuditEntry>(),
+		tables_of::<JobState>(),
+		tables_of::<JobRun>(),
+		tables_of::<OAuthClient>(),
+		tables_of::<AuthorizationCode>(),
+		tables_of::<DiscordLogin>(),
+		tables_of::<Passkey>(),
+		tables_of::<PasswordReset>(),
+		tables_of::<WebAuthnChallenge>(),
+	]
+	.concat()
+}
+
+fn tables_of<T: DBRecord>() -> Vec<String> {
+	let mut tables = vec![T::table().to_owned()];
+
+	if T::use_trash() {
+		tables.push(T::trash_table());
+	}
+
+	tables
+}
+
+/// The relation tables between `DBRecord`s.
+pub fn relation_tables() -> Vec<&'static str> {
+	vec![REFERRED.name(), CREATED_REFERRAL.name(), PLAYS_AS.name()]
+}
+
+/// Commands for `DBRecord::db_query()`. Deletions go through a `DBTransaction`, so they're audited.
 pub enum SQLCommand {
 	Select,
-	Delete,
 }
 
 impl Display for SQLCommand {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
 		match self {
 			SQLCommand::Select => write!(f, "SELECT *"),
-			SQLCommand::Delete => write!(f, "DELETE"),
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::models::user::User;
+	use rocket::http::Status;
+
+	fn binding<'a>(transaction: &'a DBTransaction, parameter: &str) -> &'a serde_json::Value {
+		let name = parameter.trim_start_matches('$');
+
+		&transaction
+			.bindings
+			.iter()
+			.find(|(n, _)| n == name)
+			.unwrap()
+			.1
+	}
+
+	#[test]
+	fn update_fields_checks_version() {
+		// As set by `IfMatch::apply()` when the client last received version 3
+		let user = User {
+			version: 3,
+			..Default::default()
+		};
+
+		let mut transaction = DBTransaction::new();
+		transaction
+			.update_fields(&user, vec![("display_name", "Alex")])
+			.unwrap();
+
+		let update = &transaction.statements[0];
+		let (_, version) = update.split_once("WHERE (version ?? 0) = ").unwrap();
+		let version = version.split_whitespace().next().unwrap();
+		assert_eq!(binding(&transaction, version), &serde_json::json!(3));
+
+		let (_, merge_data) = update.split_once(" MERGE ").unwrap();
+		let merge_data = merge_data.split_whitespace().next().unwrap();
+		assert_eq!(binding(&transaction, merge_data)["version"], 4);
+		assert_eq!(binding(&transaction, merge_data)["display_name"], "Alex");
+
+		assert!(transaction.statements[1].starts_with("IF array::len($stored0) = 0 { THROW"));
+	}
+
+	#[test]
+	fn staged_edges_need_a_valid_relation() {
+		let mut transaction = DBTransaction::new();
+		transaction
+			.insert_staged_edges("restore_staging", REFERRED.name())
+			.unwrap();
+
+		assert!(transaction.statements[0].contains("RELATE $from->referred->$to"));
+
+		let result = transaction.insert_staged_edges("restore_staging", "referred; DELETE users");
+		assert!(result.is_err());
+		assert_eq!(transaction.statements.len(), 1);
+	}
+
+	#[test]
+	fn stale_writes_are_conflicts() {
+		let error = Error::from(surrealdb::Error::Api(surrealdb::error::Api::Query(
+			format!("An error occurred: {}: users:1", STALE_WRITE_ERROR),
+		)));
+
+		assert_eq!(error.status(), Status::Conflict);
+	}
+}
@@ -0,0 +1,118 @@
+//! Sign in with Discord, with the OAuth2 authorization code grant.
+//!
+//! Disabled unless `DISCORD_CLIENT_ID`, `DISCORD_CLIENT_SECRET` and `DISCORD_REDIRECT_URI` are set.
+//! `DISCORD_OAUTH_URL` can point to a local mock of Discord's endpoints for testing.
+
+use crate::{error::Error, generic::Environment};
+use rocket::http::Status;
+use serde::Deserialize;
+
+pub const DEFAULT_DISCORD_OAUTH_URL: &str = "https://discord.com";
+
+/// The Discord application that users authorize to sign in.
+pub struct DiscordOAuth {
+	client_id: String,
+	client_secret: String,
+	redirect_uri: String,
+	base_url: String,
+}
+
+/// The Discord account that authorized the application.
+#[derive(Deserialize)]
+pub struct DiscordUser {
+	pub id: String,
+	pub username: String,
+	/// Display name
+	pub global_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DiscordTokenResponse {
+	access_token: String,
+}
+
+impl DiscordOAuth {
+	/// Returns a 404 `Error` if Discord login isn't configured.
+	pub fn from_env() -> Result<Self, Error> {
+		let env = Environment::new();
+
+		match (
+			env.discord_client_id.val_opt(),
+			env.discord_client_secret.val_opt(),
+			env.discord_redirect_uri.val_opt(),
+		) {
+			(Some(client_id), Some(client_secret), Some(redirect_uri)) => Ok(Self {
+				client_id,
+				client_secret,
+				redirect_uri,
+				base_url: env
+					.discord_oauth_url
+					.val_opt()
+					.unwrap_or(DEFAULT_DISCORD_OAUTH_URL.to_owned())
+					.trim_end_matches('/')
+					.to_owned(),
+			}),
+			_ => Err(Error::not_found("Discord login is not enabled")),
+		}
+	}
+
+	/// The URL to send the user to, to authorize the application.
+	pub fn authorize_url(&self, state: &str) -> Result<String, Error> {
+		let mut url = reqwest::Url::parse(&format!("{}/oauth2/authorize", self.base_url))
+			.map_err(|e| Error::generic_500(&format!("Invalid DISCORD_OAUTH_URL: {}", e)))?;
+
+		url.query_pairs_mut()
+			.append_pair("response_type", "code")
+			.append_pair("client_id", &self.client_id)
+			.append_pair("scope", "identify")
+			.append_pair("redirect_uri", &self.redirect_uri)
+			.append_pair("state", state);
+
+		Ok(url.to_string())
+	}
+
+	/// Exchange the authorization `code` from the redirect for the Discord account that authorized it.
+	///
+	/// Returns a 401 `Error` if Discord rejects the code.
+	pub async fn identify(&self, code: &str) -> Result<DiscordUser, Error> {
+		let client = reqwest::Client::new();
+
+		let response = client
+			.post(format!("{}/api/v10/oauth2/token", self.base_url))
+			.basic_auth(&self.client_id, Some(&self.client_secret))
+			.form(&[
+				("grant_type", "authorization_code"),
+				("code", code),
+				("redirect_uri", &self.redirect_uri),
+			])
+			.send()
+			.await
+			.map_err(|e| Error::generic_500(&format!("Error requesting Discord token: {}", e)))?;
+
+		if response.status().is_client_error() {
+			return Err(Error::new(
+				Status::Unauthorized,
+				"Discord authorization failed",
+				None,
+			));
+		}
+
+		let token: DiscordTokenResponse = response
+			.error_for_status()
+			.map_err(|e| Error::generic_500(&format!("Error requesting Discord token: {}", e)))?
+			.json()
+			.await
+			.map_err(|e| Error::generic_500(&format!("Error parsing Discord token: {}", e)))?;
+
+		client
+			.get(format!("{}/api/v10/users/@me", self.base_url))
+			.bearer_auth(token.access_token)
+			.send()
+			.await
+			.and_then(|response| response.error_for_status())
+			.map_err(|e| Error::generic_500(&format!("Error getting Discord user: {}", e)))?
+			.json()
+			.await
+			.map_err(|e| Error::generic_500(&format!("Error parsing Discord user: {}", e)))
+	}
+}
@@ -1,7 +1,8 @@
+use crate::dbrecord::{STALE_WRITE_ERROR, UNIQUE_INDEX_PREFIX};
 use rocket::http::Status;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Error {
 	status: Status,
 	/// Error description for the client
@@ -67,6 +68,11 @@ impl Error {
 		Self::new(Status::UnprocessableEntity, client_msg, None)
 	}
 
+	/// Create a 409 (Conflict) error with a specified message for the client.
+	pub fn conflict(client_msg: &str) -> Self {
+		Self::new(Status::Conflict, client_msg, None)
+	}
+
 	/// Create an `ErrorResponse` from this `Error`.
 	pub fn as_errorresponse(&self) -> ErrorResponse {
 		ErrorResponse {
@@ -124,13 +130,55 @@ impl std::fmt::Display for Error {
 }
 
 impl From<surrealdb::Error> for Error {
+	/// Unique index violations become a 409 (Conflict) naming the field,
+	/// and stale writes rejected by a `DBTransaction` become a 409 asking to refresh.
+	/// Anything else is a 500.
 	fn from(e: surrealdb::Error) -> Self {
-		Error::generic_500(&format!("SurrealDB Operation error: {}", e))
+		let desc = e.to_string();
+
+		if let Some(field) = unique_violation_field(&desc) {
+			let mut field = field.replace('_', " ");
+
+			if let Some(first) = field.get_mut(0..1) {
+				first.make_ascii_uppercase();
+			}
+
+			return Error::conflict(&format!("{} unavailable.", field));
+		}
+
+		if desc.contains(STALE_WRITE_ERROR) {
+			return Error::conflict(
+				"This was changed by someone else since it was loaded. Refresh and try again.",
+			);
+		}
+
+		Error::generic_500(&format!("SurrealDB Operation error: {}", desc))
 	}
 }
 
+/// Get the field name from a SurrealDB unique index violation message, if it is one.
+///
+/// Expects SurrealDB's `IndexExists` message: ``Database index `{index}` already contains {value}, with record `{thing}` ``
+/// Only indexes defined by `DBRecord::db_define_indexes()` are recognized.
+fn unique_violation_field(desc: &str) -> Option<&str> {
+	let (_, rest) = desc.split_once("Database index `")?;
+	let (index, rest) = rest.split_once('`')?;
+
+	if !rest.starts_with(" already contains") {
+		return None;
+	}
+
+	index.strip_prefix(UNIQUE_INDEX_PREFIX)
+}
+
 impl From<serde_json::error::Error> for Error {
 	fn from(e: serde_json::error::Error) -> Self {
 		Error::generic_500(&format!("serde_json error: {:?}", e))
 	}
 }
+
+impl From<std::io::Error> for Error {
+	fn from(e: std::io::Error) -> Self {
+		Error::generic_500(&format!("IO error: {:?}", e))
+	}
+}
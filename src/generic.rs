@@ -1,10 +1,15 @@
 use crate::{
-	dbrecord::{DBRecord, SQLCommand},
+	dbrecord::{DBRecord, DBTransaction, Relation, SQLCommand},
 	error::Error,
-	models::session::Session,
+	models::{
+		oauth_client::{OAuthClient, Scope, CLIENT_AUDIENCE},
+		session::{Session, SESSION_AUDIENCE},
+	},
+	signing_keys::SigningKeys,
 };
 use argon2::Argon2;
 use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::{DateTime, Duration, Utc};
 use password_hash::{
 	rand_core::OsRng, PasswordHashString, PasswordHasher, PasswordVerifier, SaltString,
@@ -14,6 +19,8 @@ use rand::Rng;
 use rocket::{
 	http::{HeaderMap, Status},
 	request::{FromRequest, Outcome},
+	response::{self, Responder, Response},
+	serde::json::Json,
 };
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{collections::HashMap, marker::PhantomData};
@@ -57,7 +64,59 @@ pub struct Environment {
 	pub surreal_namespace: EnvVarKey,
 	pub surreal_database: EnvVarKey,
 	pub discord_invite_link: EnvVarKey,
-	pub oauth_jwt_secret: EnvVarKey,
+	/// Directory of `<kid>.pem` Ed25519 keys that verify access tokens
+	pub oauth_jwt_keys_directory: EnvVarKey,
+	/// The key in `oauth_jwt_keys_directory` that signs new access tokens
+	pub oauth_jwt_signing_key_id: EnvVarKey,
+	/// Optional
+	#[serde(default)]
+	pub trash_retention_days: EnvVarKey,
+	/// Optional
+	#[serde(default)]
+	pub registration_expiry_days: EnvVarKey,
+	/// Optional, scheduled backups are disabled if unset
+	#[serde(default)]
+	pub backup_directory: EnvVarKey,
+	/// Optional
+	#[serde(default)]
+	pub backup_retention_days: EnvVarKey,
+	/// Optional, Discord login is disabled unless the client ID, secret and redirect URI are set
+	#[serde(default)]
+	pub discord_client_id: EnvVarKey,
+	/// Optional
+	#[serde(default)]
+	pub discord_client_secret: EnvVarKey,
+	/// Optional, the page that completes Discord login, e.g. https://kavacoast.com/login
+	#[serde(default)]
+	pub discord_redirect_uri: EnvVarKey,
+	/// Optional, defaults to https://discord.com
+	#[serde(default)]
+	pub discord_oauth_url: EnvVarKey,
+	/// Optional, "true" to require admins to enable two-factor authentication before using admin routes
+	#[serde(default)]
+	pub require_admin_two_factor: EnvVarKey,
+	/// Optional, the domain passkeys are registered for, defaults to kavacoast.com
+	#[serde(default)]
+	pub webauthn_rp_id: EnvVarKey,
+	/// Optional, the origin the site is served from, for passkey ceremonies and links in messages,
+	/// defaults to https://kavacoast.com
+	#[serde(default)]
+	pub webauthn_origin: EnvVarKey,
+	/// Optional, attempts per client IP (and per username signing in) at each auth endpoint per window, defaults to 10
+	#[serde(default)]
+	pub rate_limit_requests: EnvVarKey,
+	/// Optional, defaults to 60
+	#[serde(default)]
+	pub rate_limit_window_seconds: EnvVarKey,
+	/// Optional, consecutive failed sign-ins before a username is locked out, defaults to 5
+	#[serde(default)]
+	pub lockout_threshold: EnvVarKey,
+	/// Optional, the first lockout, doubled for each further failure, defaults to 30
+	#[serde(default)]
+	pub lockout_seconds: EnvVarKey,
+	/// Optional, a webhook that delivers password reset links to users without Discord, e.g. by email
+	#[serde(default)]
+	pub notification_webhook_url: EnvVarKey,
 }
 
 macro_rules! initialize_env {
@@ -84,7 +143,24 @@ impl Environment {
 		surreal_namespace,
 		surreal_database,
 		discord_invite_link,
-		oauth_jwt_secret
+		oauth_jwt_keys_directory,
+		oauth_jwt_signing_key_id,
+		trash_retention_days,
+		registration_expiry_days,
+		backup_directory,
+		backup_retention_days,
+		discord_client_id,
+		discord_client_secret,
+		discord_redirect_uri,
+		discord_oauth_url,
+		require_admin_two_factor,
+		webauthn_rp_id,
+		webauthn_origin,
+		rate_limit_requests,
+		rate_limit_window_seconds,
+		lockout_threshold,
+		lockout_seconds,
+		notification_webhook_url
 	);
 
 	pub fn load_path(path: &str) {
@@ -119,6 +195,13 @@ impl EnvVarKey {
 		std::env::var(&self.0)
 			.unwrap_or_else(|_| panic!("Missing environment variable: {}", self.0))
 	}
+
+	/// Get the value, or `None` if it is unset or empty.
+	///
+	/// Use for optional keys, which default to an empty string.
+	pub fn val_opt(&self) -> Option<String> {
+		std::env::var(&self.0).ok().filter(|v| !v.is_empty())
+	}
 }
 
 /// A typed wrapper for the `Thing` object that corresponds to an ID in Surreal.
@@ -161,6 +244,12 @@ where
 		Thing::from((T::table().to_owned(), Id::from(Uuid::new_v4()))).into()
 	}
 
+	/// Create a new UUID for the given table with an ID generated by `rng`, so a seeded `rng` generates the same IDs.
+	pub fn from_rng(rng: &mut impl Rng) -> Self {
+		let uuid = uuid::Builder::from_random_bytes(rng.random()).into_uuid();
+		Thing::from((T::table().to_owned(), Id::from(Uuid::from(uuid)))).into()
+	}
+
 	/// Get the object associated with the UUID.
 	///
 	/// Returns an `Error` if SurrealDB unexpectedly fails.
@@ -184,6 +273,43 @@ where
 		let obj: Option<T> = T::db_by_id(&self.uuid_string()).await?;
 		Ok(obj)
 	}
+
+	/// Get the objects associated with many UUIDs in a single query, keyed by UUID string.
+	///
+	/// UUIDs without an associated object are not in the map.
+	pub async fn load_many(uuids: &[Self]) -> Result<HashMap<String, T>, Error> {
+		let db = surrealdb_client().await?;
+		let things: Vec<Thing> = uuids.iter().map(|uuid| uuid.thing()).collect();
+
+		// Selecting the records by ID reads only them, rather than filtering the whole table
+		db.set("things", things).await?;
+		let mut response = db.query("SELECT * FROM $things").await?;
+
+		let result: surrealdb::Value = response.take(0)?;
+		let serde_value = result.into_inner().into_json();
+		let objects: Vec<T> = serde_json::from_value(serde_value)?;
+
+		Ok(objects
+			.into_iter()
+			.map(|object| (object.uuid().uuid_string(), object))
+			.collect())
+	}
+
+	/// Get the records this record is related to by `relation`, i.e. `self->relation->?`.
+	pub async fn related<Out: DBRecord>(
+		&self,
+		relation: &Relation<T, Out>,
+	) -> Result<Vec<Out>, Error> {
+		relation.outgoing(&self.uuid_string()).await
+	}
+
+	/// Get the records related to this record by `relation`, i.e. `?->relation->self`.
+	pub async fn related_by<In: DBRecord>(
+		&self,
+		relation: &Relation<In, T>,
+	) -> Result<Vec<In>, Error> {
+		relation.incoming(&self.uuid_string()).await
+	}
 }
 
 impl<T: DBRecord> Default for UUID<T> {
@@ -269,6 +395,20 @@ impl HashedString {
 	}
 }
 
+/// A SHA-256 digest of a random token, such as a refresh token
+///
+/// Unlike a `HashedString`, the digest of a token is always the same, so records can be searched by it.
+/// Only for long random tokens, which can't be guessed from their digest.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenDigest(String);
+
+impl TokenDigest {
+	pub fn new(token: &str) -> Self {
+		let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+		Self(URL_SAFE_NO_PAD.encode(digest.as_ref()))
+	}
+}
+
 #[async_trait]
 pub trait Expirable: DBRecord {
 	fn start_time_field() -> &'static str;
@@ -297,6 +437,7 @@ pub trait Expirable: DBRecord {
 		Ok(start_time.timestamp())
 	}
 
+	/// Delete every expired record in a transaction, so each deletion is audited.
 	async fn clear_expired() -> Result<(), Error> {
 		let earliest_valid_time = Utc::now()
 			.checked_sub_signed(Duration::seconds(Self::expiry_seconds() as i64))
@@ -304,15 +445,21 @@ pub trait Expirable: DBRecord {
 				"Out of bounds datetime in clear_expired()",
 			))?;
 
-		Self::db_query(
-			SQLCommand::Delete,
+		let expired = Self::db_query(
+			SQLCommand::Select,
 			format!("time::unix(type::datetime({}))", Self::start_time_field()),
 			'<',
 			earliest_valid_time.timestamp(),
 		)
 		.await?;
 
-		Ok(())
+		let mut transaction = DBTransaction::new();
+
+		for record in &expired {
+			transaction.delete(record).await?;
+		}
+
+		transaction.commit().await
 	}
 
 	fn is_expired(&self) -> Result<bool, Error> {
@@ -351,15 +498,62 @@ impl BearerToken {
 		)
 	}
 
-	/// Validate the token and return the session
-	pub async fn validate(&self) -> Result<Session, Error> {
-		let token = self.0.as_ref().ok_or(Error::new(
+	fn token(&self) -> Result<&str, Error> {
+		self.0.as_deref().ok_or(Error::new(
 			Status::Unauthorized,
 			"Missing Authorization header",
 			None,
-		))?;
+		))
+	}
+
+	/// Validate the token and return the session
+	///
+	/// Only accepts sessions signed in to this site, not ones authorized for an `OAuthClient`.
+	pub async fn validate(&self) -> Result<Session, Error> {
+		Ok(self.validate_with_claims().await?.0)
+	}
 
-		Session::from_access_token(token).await
+	/// Validate the token like `validate()`, also returning its claims
+	pub async fn validate_with_claims(&self) -> Result<(Session, JwtClaims), Error> {
+		let claims = JwtClaims::decode(self.token()?, &[SESSION_AUDIENCE])?;
+		let session = Session::from_claims(&claims).await?;
+
+		if session.client.is_some() {
+			return Err(Error::insufficient_permissions());
+		}
+
+		crate::audit::set_actor(session.user_uuid());
+		Ok((session, claims))
+	}
+
+	/// Validate a token that may also be issued to an `OAuthClient`, requiring `scope`
+	pub async fn authorize(&self, scope: &Scope) -> Result<Authorization, Error> {
+		let claims = JwtClaims::decode(self.token()?, &[SESSION_AUDIENCE, CLIENT_AUDIENCE])?;
+
+		let authorization = if claims.aud == CLIENT_AUDIENCE {
+			let (_, scopes) = OAuthClient::from_claims(&claims).await?;
+			Authorization::Client(scopes)
+		} else {
+			let session = Session::from_claims(&claims).await?;
+			crate::audit::set_actor(session.user_uuid());
+
+			if session.client.is_some() {
+				Authorization::Delegated(session)
+			} else {
+				Authorization::FirstParty(session)
+			}
+		};
+
+		if !authorization.has_scope(scope) {
+			return Err(Error::insufficient_permissions());
+		}
+
+		Ok(authorization)
+	}
+
+	/// Verify the token and return its claims, without checking its session
+	pub fn claims(&self) -> Result<JwtClaims, Error> {
+		JwtClaims::decode(self.token()?, &[SESSION_AUDIENCE])
 	}
 }
 
@@ -371,12 +565,131 @@ impl<'r> FromRequest<'r> for BearerToken {
 	}
 }
 
+/// What a `BearerToken` can access, from `BearerToken::authorize()`.
+pub enum Authorization {
+	/// A session signed in to this site, which has every scope
+	FirstParty(Session),
+	/// A session of a user that authorized an `OAuthClient`, with the scopes they consented to
+	Delegated(Session),
+	/// An `OAuthClient` acting for itself with the client credentials grant, with its scopes
+	Client(Vec<Scope>),
+}
+
+impl Authorization {
+	pub fn has_scope(&self, scope: &Scope) -> bool {
+		match self {
+			Self::FirstParty(_) => true,
+			Self::Delegated(session) => session.scopes.contains(scope),
+			Self::Client(scopes) => scopes.contains(scope),
+		}
+	}
+
+	/// The session, if the token acts for a user.
+	pub fn session(&self) -> Option<&Session> {
+		match self {
+			Self::FirstParty(session) | Self::Delegated(session) => Some(session),
+			Self::Client(..) => None,
+		}
+	}
+}
+
+/// The client's user agent and IP address, recorded with new sessions.
+#[derive(Default)]
+pub struct ClientInfo {
+	pub user_agent: Option<String>,
+	pub ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+	type Error = ();
+	async fn from_request(request: &'r rocket::request::Request<'_>) -> Outcome<Self, Self::Error> {
+		Outcome::Success(ClientInfo {
+			user_agent: request
+				.headers()
+				.get_one("User-Agent")
+				.map(|user_agent| user_agent.to_owned()),
+			// Not `client_ip`, which trusts the `X-Real-IP` header that clients can set to avoid rate limits
+			ip: request.remote().map(|remote| remote.ip().to_string()),
+		})
+	}
+}
+
+/// The value of an `If-Match` header, which should be an `ETag` sent by a `Versioned` response.
+pub struct IfMatch(Option<String>);
+
+impl IfMatch {
+	/// Set the version of `record` to the one the client last received,
+	/// so that updating it fails with a 409 (Conflict) if it has changed since.
+	///
+	/// Does nothing if the header is `*`, for clients that overwrite the record regardless.
+	/// Returns a 428 (Precondition Required) `Error` if the header is missing, as the update could
+	/// silently overwrite changes the client hasn't seen.
+	pub fn apply<T: DBRecord>(&self, record: &mut T) -> Result<(), Error> {
+		let etag = match self.0.as_deref() {
+			Some("*") => return Ok(()),
+			Some(etag) => etag,
+			None => {
+				return Err(Error::new(
+					Status::PreconditionRequired,
+					"If-Match header required",
+					None,
+				))
+			}
+		};
+
+		let version = etag
+			.trim_start_matches("W/")
+			.trim_matches('"')
+			.parse()
+			.map_err(|_| Error::new(Status::BadRequest, "Invalid If-Match header", None))?;
+
+		record.set_version(version);
+		Ok(())
+	}
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+	type Error = ();
+	async fn from_request(request: &'r rocket::request::Request<'_>) -> Outcome<Self, Self::Error> {
+		Outcome::Success(IfMatch(
+			request
+				.headers()
+				.get_one("If-Match")
+				.map(|etag| etag.to_owned()),
+		))
+	}
+}
+
+/// A JSON response with an `ETag` header containing the version of a record.
+///
+/// Clients send it back in an `If-Match` header to update the record only if it hasn't changed.
+pub struct Versioned<T> {
+	body: T,
+	version: u64,
+}
+
+impl<T: Serialize> Versioned<T> {
+	pub fn new(body: T, version: u64) -> Self {
+		Self { body, version }
+	}
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Versioned<T> {
+	fn respond_to(self, request: &'r rocket::request::Request<'_>) -> response::Result<'static> {
+		Response::build_from(Json(self.body).respond_to(request)?)
+			.raw_header("ETag", format!("\"{}\"", self.version))
+			.ok()
+	}
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 /// [JWT Claims](https://datatracker.ietf.org/doc/html/rfc7519)
 pub struct JwtClaims {
 	/// Subject
 	///
-	/// (Session Id)
+	/// (Session Id, or OAuthClient Id for the client credentials grant)
 	pub sub: String,
 	/// Expiration Time
 	pub exp: u64,
@@ -386,6 +699,25 @@ pub struct JwtClaims {
 	pub iss: String,
 	/// Audience
 	pub aud: String,
+	/// JWT ID, used to deny a single token
+	pub jti: String,
+	/// The `token_epoch` of the session when the token was issued
+	pub epoch: u64,
+	/// Space-separated scopes, for tokens issued to an OAuth client
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub scope: Option<String>,
+}
+
+impl JwtClaims {
+	/// Verify the signature, issuer, audience and expiration of an access token and return its claims.
+	pub fn decode(access_token: &str, audiences: &[&str]) -> Result<Self, Error> {
+		let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::EdDSA);
+		validation.set_audience(audiences);
+		validation.set_issuer(&["kavacoast.com"]);
+
+		// note: decode() also checks expiration
+		SigningKeys::get()?.decode(access_token, validation)
+	}
 }
 
 pub fn random_alphanumeric_string(length: usize) -> String {
@@ -437,3 +769,82 @@ pub async fn get_discord_username(discord_user_id: &str) -> Result<String, Error
 		))),
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::models::user::User;
+	use rocket::local::blocking::Client;
+
+	fn apply(header: Option<&str>, version: u64) -> Result<u64, Error> {
+		let mut user = User {
+			version,
+			..Default::default()
+		};
+
+		IfMatch(header.map(str::to_owned)).apply(&mut user)?;
+		Ok(user.version())
+	}
+
+	#[test]
+	fn if_match_sets_version() {
+		assert_eq!(apply(Some("\"3\""), 5).unwrap(), 3);
+	}
+
+	#[test]
+	fn if_match_accepts_weak_etags() {
+		assert_eq!(apply(Some("W/\"3\""), 5).unwrap(), 3);
+	}
+
+	#[test]
+	fn if_match_wildcard_keeps_version() {
+		assert_eq!(apply(Some("*"), 5).unwrap(), 5);
+	}
+
+	#[test]
+	fn if_match_is_required() {
+		assert_eq!(
+			apply(None, 5).unwrap_err().status(),
+			Status::PreconditionRequired
+		);
+	}
+
+	#[test]
+	fn if_match_rejects_invalid_etags() {
+		for header in ["\"abc\"", "\"-1\"", "\"\""] {
+			assert_eq!(
+				apply(Some(header), 5).unwrap_err().status(),
+				Status::BadRequest
+			);
+		}
+	}
+
+	#[rocket::get("/versioned")]
+	fn versioned() -> Versioned<GenericOkResponse> {
+		Versioned::new(GenericOkResponse::new(), 7)
+	}
+
+	#[rocket::get("/if-match")]
+	fn if_match(if_match: IfMatch) -> Result<String, Status> {
+		let mut user = User::default();
+		if_match.apply(&mut user).map_err(|e| e.status())?;
+		Ok(user.version().to_string())
+	}
+
+	#[test]
+	fn etag_round_trips_through_if_match() {
+		let rocket = rocket::build().mount("/", rocket::routes![versioned, if_match]);
+		let client = Client::untracked(rocket).unwrap();
+
+		let response = client.get("/versioned").dispatch();
+		let etag = response.headers().get_one("ETag").unwrap().to_owned();
+		assert_eq!(etag, "\"7\"");
+
+		let response = client
+			.get("/if-match")
+			.header(rocket::http::Header::new("If-Match", etag))
+			.dispatch();
+
+		assert_eq!(response.into_string().unwrap(), "7");
+	}
+}
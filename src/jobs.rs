@@ -1,95 +1,287 @@
-use crate::{error::Error, generic::Expirable, models::session::Session};
-use std::{
-	future::Future,
-	pin::Pin,
-	sync::{Arc, Mutex},
-	thread,
+use crate::{
+	backup::scheduled_backup,
+	dbrecord::{purge_expired_trash, DBRecord, DBTransaction},
+	error::Error,
+	generic::Expirable,
+	models::{
+		authorization_code::AuthorizationCode, discord_login::DiscordLogin, job_run::JobRun,
+		job_state::JobState, password_reset::PasswordReset, registration::Registration,
+		session::Session, webauthn_challenge::WebAuthnChallenge,
+	},
+	shutdown::Shutdown,
 };
-use tokio::runtime::Runtime;
+use chrono::{DateTime, Duration, Utc};
+use cron::Schedule;
+use rocket::http::Status;
+use serde_json::{json, Value};
+use std::{future::Future, pin::Pin, str::FromStr, sync::Arc};
+use tokio::task::JoinHandle;
 
+/// How long a run can hold its claim before another instance may assume it crashed
+const JOB_LEASE_MINUTES: i64 = 60;
+/// How often a waiting job rechecks its state, in case another instance ran it
+const JOB_POLL_SECONDS: i64 = 60 * 5;
+/// How many times recording a finished run is retried if the job's state changes meanwhile
+const JOB_FINISH_ATTEMPTS: u32 = 3;
+
+/// A function run on a cron schedule (in UTC), with its state persisted as a `JobState`.
+///
+/// Runs missed while the server was down are caught up once on startup.
 pub struct Job {
-	function: Arc<Mutex<JobFunction>>,
-	interval: u64,
+	name: &'static str,
+	schedule: Schedule,
+	function: JobFunction,
 }
 
 impl Job {
-	fn active_jobs() -> Vec<Job> {
-		vec![Job::new(
-			Session::clear_expired,
-			60 * 60 * 24 * 7, // 1 week
-		)]
+	pub fn active_jobs() -> Vec<Job> {
+		vec![
+			Job::new(
+				"clear_expired_sessions",
+				"0 0 4 * * Sun", // Sundays at 04:00
+				Session::clear_expired,
+			),
+			Job::new(
+				"clear_expired_registrations",
+				"0 15 4 * * *", // Daily at 04:15
+				Registration::clear_expired,
+			),
+			Job::new(
+				"clear_expired_authorization_codes",
+				"0 20 4 * * *", // Daily at 04:20
+				AuthorizationCode::clear_expired,
+			),
+			Job::new(
+				"clear_expired_discord_logins",
+				"0 25 4 * * *", // Daily at 04:25
+				DiscordLogin::clear_expired,
+			),
+			Job::new(
+				"clear_expired_webauthn_challenges",
+				"0 35 4 * * *", // Daily at 04:35
+				WebAuthnChallenge::clear_expired,
+			),
+			Job::new(
+				"clear_expired_password_resets",
+				"0 40 4 * * *", // Daily at 04:40
+				PasswordReset::clear_expired,
+			),
+			Job::new(
+				"clear_expired_job_runs",
+				"0 45 4 * * *", // Daily at 04:45
+				JobRun::clear_expired,
+			),
+			Job::new(
+				"purge_expired_trash",
+				"0 30 4 * * *", // Daily at 04:30
+				purge_expired_trash,
+			),
+			Job::new(
+				"scheduled_backup",
+				"0 0 3 * * *", // Daily at 03:00
+				scheduled_backup,
+			),
+		]
 	}
 
-	fn new<F, Fut>(function: F, interval: u64) -> Self
+	/// Panics if `schedule` isn't a valid cron expression (with seconds).
+	fn new<F, Fut>(name: &'static str, schedule: &str, function: F) -> Self
 	where
 		F: Fn() -> Fut + Send + Sync + 'static,
 		Fut: Future<Output = Result<(), Error>> + Send + 'static,
 	{
-		let function = JobFunction::new(function);
+		let schedule = Schedule::from_str(schedule)
+			.unwrap_or_else(|e| panic!("Invalid schedule for job {}: {}", name, e));
+
+		Self {
+			name,
+			schedule,
+			function: JobFunction::new(function),
+		}
+	}
+
+	/// Get an active job by name.
+	pub fn find(name: &str) -> Option<Job> {
+		Self::active_jobs().into_iter().find(|job| job.name == name)
+	}
+
+	pub fn name(&self) -> &'static str {
+		self.name
+	}
 
-		Self { function, interval }
+	/// Get the cron expression of the schedule
+	pub fn schedule(&self) -> &str {
+		self.schedule.source()
 	}
 
-	fn spawn(&self) {
-		let i = self.interval;
-		let func = Arc::clone(&self.function);
-
-		thread::spawn(move || {
-			let rt = Runtime::new().unwrap();
-			rt.block_on(async move {
-				loop {
-					tokio::time::sleep(std::time::Duration::from_secs(i)).await;
-					let cloned_func = {
-						let func = func.lock().unwrap();
-						func.clone()
-					};
-					cloned_func.call().await;
+	/// Get the first scheduled time after `time`.
+	fn next_after(&self, time: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+		self.schedule.after(time).next()
+	}
+
+	/// Wait for each scheduled time and run the job, until `shutdown` is triggered.
+	///
+	/// A run in progress is finished first.
+	async fn run_scheduled(self, shutdown: Shutdown) {
+		while !shutdown.is_triggered() {
+			let mut state = match JobState::load(self.name).await {
+				Ok(state) => state,
+				Err(e) => {
+					log::error!("Failed to load state of job {}: {}", self.name, e);
+					Self::sleep(Duration::seconds(JOB_POLL_SECONDS), &shutdown).await;
+					continue;
+				}
+			};
+
+			let next_run_at = state.next_run_at.or_else(|| self.next_after(&Utc::now()));
+
+			let Some(next_run_at) = next_run_at else {
+				log::warn!("Job {} has no upcoming scheduled time", self.name);
+				return;
+			};
+
+			let wait = next_run_at - Utc::now();
+
+			if wait > Duration::zero() {
+				Self::sleep(wait.min(Duration::seconds(JOB_POLL_SECONDS)), &shutdown).await;
+				continue;
+			}
+
+			if state.paused {
+				// Skipped runs aren't caught up when the job is resumed
+				let next_run_at = self.next_after(&Utc::now());
+
+				if let Err(e) = state.db_update_field("next_run_at", &next_run_at).await {
+					log::error!("Failed to skip paused job {}: {}", self.name, e);
+					Self::sleep(Duration::seconds(JOB_POLL_SECONDS), &shutdown).await;
 				}
-			});
-		});
+
+				continue;
+			}
+
+			match self.run().await {
+				Ok(true) => {}
+				// Another instance is running it, so wait for that run to finish or its lease to expire
+				Ok(false) => Self::sleep(Duration::seconds(JOB_POLL_SECONDS), &shutdown).await,
+				Err(e) => {
+					log::error!("Failed to run job {}: {}", self.name, e);
+					Self::sleep(Duration::seconds(JOB_POLL_SECONDS), &shutdown).await;
+				}
+			}
+		}
 	}
 
-	pub fn spawn_all() {
-		for job in Self::active_jobs() {
-			job.spawn();
+	/// Sleep for `duration`, or until `shutdown` is triggered.
+	async fn sleep(duration: Duration, shutdown: &Shutdown) {
+		tokio::select! {
+			_ = tokio::time::sleep(duration.to_std().unwrap_or_default()) => {},
+			_ = shutdown.wait() => {},
 		}
 	}
-}
 
-pub struct JobFunction {
-	func: Arc<dyn Fn() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>,
-}
+	/// Run the job now, recording the run and scheduling the next one.
+	///
+	/// Returns `false` without running if the job is already running.
+	pub async fn run(&self) -> Result<bool, Error> {
+		let mut state = JobState::load(self.name).await?;
+		let now = Utc::now();
 
-impl Clone for JobFunction {
-	fn clone(&self) -> Self {
-		Self {
-			func: self.func.clone(),
+		if let Some(running_since) = state.running_since {
+			if now - running_since < Duration::minutes(JOB_LEASE_MINUTES) {
+				return Ok(false);
+			}
+		}
+
+		// The version check rejects the claim if another instance claimed it first
+		let claim = state
+			.db_update_fields(vec![
+				("running_since", json!(now)),
+				("next_run_at", json!(self.next_after(&now))),
+			])
+			.await;
+
+		match claim {
+			Err(e) if e.status() == Status::Conflict => return Ok(false),
+			claim => claim?,
+		}
+
+		let result = self.function.call().await;
+		let finished_at = Utc::now();
+		let error = result.err().map(|e| e.to_string());
+
+		if let Some(error) = &error {
+			log::error!("Job {} failed: {}", self.name, error);
+		}
+
+		let run = JobRun::new(self.name, now, finished_at, error);
+		self.finish(&run, &now).await?;
+		Ok(true)
+	}
+
+	/// Record `run`, and release the claim made at `claimed_at` if the job still holds it.
+	///
+	/// The state is reloaded rather than updated at the version that was claimed, as pausing or
+	/// resuming the job while it ran changes the version. If it changes again before the run is recorded,
+	/// recording it is retried.
+	async fn finish(&self, run: &JobRun, claimed_at: &DateTime<Utc>) -> Result<(), Error> {
+		let mut attempt = 1;
+
+		loop {
+			let state = JobState::load(self.name).await?;
+			let mut transaction = DBTransaction::new();
+			transaction.create(run)?;
+
+			// Another instance may have taken over the claim after its lease expired
+			if state.running_since.as_ref() == Some(claimed_at) {
+				transaction.update_fields(
+					&state,
+					vec![
+						("running_since", Value::Null),
+						("last_started_at", json!(run.started_at)),
+						("last_finished_at", json!(run.finished_at)),
+						("last_outcome", json!(run.outcome)),
+						("last_error", json!(run.error)),
+					],
+				)?;
+			}
+
+			match transaction.commit().await {
+				Err(e) if e.status() == Status::Conflict && attempt < JOB_FINISH_ATTEMPTS => {
+					attempt += 1;
+				}
+				result => return result,
+			}
 		}
 	}
+
+	/// Start running every job on its schedule, returning the tasks that finish after `shutdown` is triggered.
+	pub fn spawn_all(shutdown: &Shutdown) -> Vec<JoinHandle<()>> {
+		Self::active_jobs()
+			.into_iter()
+			.map(|job| tokio::spawn(job.run_scheduled(shutdown.clone())))
+			.collect()
+	}
+}
+
+type JobFuture = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+#[derive(Clone)]
+pub struct JobFunction {
+	func: Arc<dyn Fn() -> JobFuture + Send + Sync>,
 }
 
 impl JobFunction {
-	pub fn new<F, Fut>(function: F) -> Arc<Mutex<Self>>
+	pub fn new<F, Fut>(function: F) -> Self
 	where
 		F: Fn() -> Fut + Send + Sync + 'static,
 		Fut: Future<Output = Result<(), Error>> + Send + 'static,
 	{
-		let s = Self {
-			func: Arc::new(move || {
-				let future = function();
-
-				Box::pin(async move {
-					if let Err(e) = future.await {
-						log::error!("Job function error: {}", e);
-					}
-				})
-			}),
-		};
-
-		Arc::new(Mutex::new(s))
+		Self {
+			func: Arc::new(move || Box::pin(function())),
+		}
 	}
 
-	pub fn call(&self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+	pub fn call(&self) -> JobFuture {
 		(self.func)()
 	}
 }
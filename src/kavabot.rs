@@ -1,4 +1,4 @@
-use crate::{cmds, generic::Environment};
+use crate::{cmds, generic::Environment, shutdown::Shutdown};
 use chrono::{Datelike, TimeZone, Utc, Weekday};
 use serde::{Deserialize, Serialize};
 use serenity::{
@@ -77,7 +77,7 @@ impl EventHandler for Handler {
 			let content = match command.data.name.as_str() {
 				"ping" => Some(cmds::ping::run(command.data.options().as_slice())),
 				"register" => Some(cmds::register::run(&ctx, &command.user).await),
-				"resetpassword" => Some(cmds::resetpassword::run(&ctx, &command.user).await),
+				"resetpassword" => Some(cmds::resetpassword::run(&command.user).await),
 				_ => Some("Unknown command".to_string()),
 			};
 
@@ -259,7 +259,8 @@ async fn reset_state(ctx: &Context) -> BotState {
 	state.clone()
 }
 
-pub async fn start_bot() {
+/// Run the bot until `shutdown` is triggered, then disconnect its shards.
+pub async fn start_bot(shutdown: Shutdown) {
 	let token = Environment::new().bot_token.val();
 
 	let intents = GatewayIntents::non_privileged()
@@ -280,6 +281,13 @@ pub async fn start_bot() {
 		.await
 		.expect("Error creating client");
 
+	let shard_manager = client.shard_manager.clone();
+
+	tokio::spawn(async move {
+		shutdown.wait().await;
+		shard_manager.shutdown_all().await;
+	});
+
 	if let Err(e) = client.start().await {
 		log::error!("Client error: {}", e);
 	}
@@ -1,13 +1,26 @@
+mod audit;
+mod backup;
+mod cli;
 mod cmds;
 mod dbrecord;
+mod discord_oauth;
 mod error;
 mod generic;
 mod jobs;
 mod kavabot;
+mod migrations;
 mod models;
+mod notifier;
+mod rate_limit;
 mod routes;
-mod test_init;
+mod seed;
+mod shutdown;
+mod signing_keys;
+mod totp;
 mod web;
+mod webauthn;
+
+use clap::Parser;
 
 #[tokio::main]
 async fn main() {
@@ -17,21 +30,11 @@ async fn main() {
 		.filter_module("serenity", log::LevelFilter::Warn)
 		.init();
 
+	let cli = cli::Cli::parse();
 	generic::Environment::load_path("config.toml");
-	let args: Vec<String> = std::env::args().collect();
 
-	if args.contains(&"test".to_string()) {
-		crate::test_init::test_init().await;
-		return;
+	if let Err(e) = cli.command.unwrap_or_default().run().await {
+		log::error!("{}", e);
+		std::process::exit(1);
 	}
-
-	log::info!("Starting...");
-	jobs::Job::spawn_all();
-
-	tokio::spawn(async {
-		kavabot::start_bot().await;
-	});
-
-	web::start_web().await;
-	log::info!("Shutting down...");
 }
@@ -0,0 +1,127 @@
+use crate::{
+	dbrecord::{DBRecord, DBTransaction},
+	error::Error,
+	generic::{surrealdb_client, UUID},
+	models::{
+		pool_player::PoolPlayer,
+		registration::Registration,
+		session::Session,
+		user::{User, CREATED_REFERRAL, PLAYS_AS, REFERRED},
+	},
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// Bring records written by older versions up to date.
+///
+/// Called on startup, after indexes are defined. Each migration is a no-op once applied.
+pub async fn migrate() -> Result<(), Error> {
+	migrate_relation_arrays().await?;
+	migrate_refresh_token_hashes().await
+}
+
+/// `User.referred_by`, `User.referral_registrations`, `User.referred_users` and `PoolPlayer.user`
+/// were replaced by the `referred`, `created_referral` and `plays_as` relations.
+async fn migrate_relation_arrays() -> Result<(), Error> {
+	#[derive(Deserialize)]
+	struct LegacyUser {
+		uuid: UUID<User>,
+		referred_by: Option<UUID<User>>,
+		#[serde(default)]
+		referral_registrations: Vec<UUID<Registration>>,
+	}
+
+	#[derive(Deserialize)]
+	struct LegacyPoolPlayer {
+		uuid: UUID<PoolPlayer>,
+		user: Option<UUID<User>>,
+	}
+
+	let users: Vec<LegacyUser> = select_legacy::<User, _>(
+		"referred_by != NONE OR referral_registrations != NONE OR referred_users != NONE",
+	)
+	.await?;
+
+	let players: Vec<LegacyPoolPlayer> = select_legacy::<PoolPlayer, _>("user != NONE").await?;
+
+	if users.is_empty() && players.is_empty() {
+		return Ok(());
+	}
+
+	log::info!(
+		"Migrating relations of {} users and {} pool players",
+		users.len(),
+		players.len()
+	);
+
+	// `relate()` skips edges to records that no longer exist, which drops stale entries
+	let mut transaction = DBTransaction::new();
+
+	for user in users {
+		if let Some(referrer) = &user.referred_by {
+			transaction.relate(&REFERRED, referrer, &user.uuid)?;
+		}
+
+		for registration in &user.referral_registrations {
+			transaction.relate(&CREATED_REFERRAL, &user.uuid, registration)?;
+		}
+	}
+
+	// A user can only play as one player, so later players of the same user are left unlinked
+	let mut linked_users = HashSet::new();
+
+	for player in players {
+		if let Some(user) = &player.user {
+			if linked_users.insert(user.uuid_string()) {
+				transaction.relate(&PLAYS_AS, user, &player.uuid)?;
+			}
+		}
+	}
+
+	transaction.unset_all::<User>(&["referred_by", "referral_registrations", "referred_users"])?;
+	transaction.unset_all::<PoolPlayer>(&["user"])?;
+	transaction.commit().await
+}
+
+/// `Session.refresh_token_hash` and `Session.rotated_refresh_token_hashes` were Argon2 hashes, replaced by
+/// `refresh_token_digest` and `rotated_refresh_token_digests`. The hashes can't be converted, so those sessions
+/// are deleted and their users sign in again.
+async fn migrate_refresh_token_hashes() -> Result<(), Error> {
+	let sessions: Vec<Session> = select_legacy::<Session, _>("refresh_token_hash != NONE").await?;
+
+	if sessions.is_empty() {
+		return Ok(());
+	}
+
+	log::info!(
+		"Deleting {} sessions with Argon2 hashed refresh tokens",
+		sessions.len()
+	);
+
+	let mut transaction = DBTransaction::new();
+
+	for session in &sessions {
+		transaction.delete(session).await?;
+	}
+
+	transaction.commit().await
+}
+
+/// Select records of type `T` matching `condition` into a legacy representation.
+async fn select_legacy<T: DBRecord, L: DeserializeOwned>(condition: &str) -> Result<Vec<L>, Error> {
+	let db = surrealdb_client().await?;
+	db.set("table", T::table()).await?;
+
+	let mut response = db
+		.query(format!(
+			"SELECT * FROM type::table($table) WHERE {}",
+			condition
+		))
+		.await?;
+
+	let result: surrealdb::Value = response.take(0)?;
+	let serde_value = result.into_inner().into_json();
+	let value: Vec<L> = serde_json::from_value(serde_value)?;
+	Ok(value)
+}
@@ -0,0 +1,176 @@
+use crate::{
+	dbrecord::{DBRecord, DBTransaction},
+	error::Error,
+	generic::{surrealdb_client, UUID},
+	models::user::User,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const AUDIT_SEARCH_DEFAULT_LIMIT: u32 = 100;
+pub const AUDIT_SEARCH_MAX_LIMIT: u32 = 1000;
+
+/// A record of a mutation made through a `DBTransaction`.
+///
+/// Entries are append-only: they can't be deleted with `db_delete()`.
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+	pub uuid: UUID<AuditEntry>,
+	/// The signed in user that made the request, or `None` if the mutation wasn't made by one (e.g. jobs and bot commands).
+	pub actor: Option<UUID<User>>,
+	pub table: String,
+	/// ID of the mutated record
+	pub record: String,
+	pub operation: AuditOperation,
+	/// The record before the mutation, as it was stored.
+	///
+	/// For updates, only the updated fields.
+	pub before: Option<Value>,
+	/// The record after the mutation.
+	///
+	/// For updates, only the updated fields.
+	pub after: Option<Value>,
+	pub created_at: DateTime<Utc>,
+	#[serde(default)]
+	version: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+	Create,
+	Update,
+	Delete,
+	/// Permanent deletion from a trash table
+	Purge,
+	/// Deletion of every record in a table, e.g. when restoring a backup. The record is `*`.
+	Clear,
+}
+
+#[async_trait]
+impl DBRecord for AuditEntry {
+	fn table() -> &'static str {
+		"audit_log"
+	}
+
+	fn uuid(&self) -> UUID<Self> {
+		self.uuid.to_owned()
+	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn audited() -> bool {
+		false
+	}
+
+	async fn delete_hook(&self, _transaction: &mut DBTransaction) -> Result<(), Error> {
+		Err(Error::forbidden())
+	}
+}
+
+/// Filters for `AuditEntry::search()`. Every filter that is set must match.
+#[derive(Default)]
+pub struct AuditFilter {
+	pub actor: Option<UUID<User>>,
+	pub table: Option<String>,
+	pub record: Option<String>,
+	pub operation: Option<AuditOperation>,
+	pub since: Option<DateTime<Utc>>,
+	pub until: Option<DateTime<Utc>>,
+	/// Defaults to `AUDIT_SEARCH_DEFAULT_LIMIT`, and is capped at `AUDIT_SEARCH_MAX_LIMIT`.
+	pub limit: Option<u32>,
+}
+
+impl AuditEntry {
+	pub fn new(
+		table: &str,
+		record: &str,
+		operation: AuditOperation,
+		before: Option<Value>,
+		after: Option<Value>,
+	) -> Self {
+		Self {
+			uuid: UUID::new(),
+			actor: crate::audit::actor(),
+			table: table.to_owned(),
+			record: record.to_owned(),
+			operation,
+			before,
+			after,
+			created_at: Utc::now(),
+			version: 0,
+		}
+	}
+
+	/// Get the entries matching `filter`, most recent first.
+	pub async fn search(filter: &AuditFilter) -> Result<Vec<Self>, Error> {
+		let db = surrealdb_client().await?;
+		let mut conditions = vec![];
+
+		db.set("table", Self::table()).await?;
+
+		db.set(
+			"limit",
+			filter
+				.limit
+				.unwrap_or(AUDIT_SEARCH_DEFAULT_LIMIT)
+				.min(AUDIT_SEARCH_MAX_LIMIT),
+		)
+		.await?;
+
+		if let Some(actor) = &filter.actor {
+			db.set("actor", actor.to_owned()).await?;
+			conditions.push("actor = $actor");
+		}
+
+		if let Some(table) = &filter.table {
+			db.set("record_table", table.to_owned()).await?;
+			conditions.push("table = $record_table");
+		}
+
+		if let Some(record) = &filter.record {
+			db.set("record", record.to_owned()).await?;
+			conditions.push("record = $record");
+		}
+
+		if let Some(operation) = filter.operation {
+			db.set("operation", operation).await?;
+			conditions.push("operation = $operation");
+		}
+
+		if let Some(since) = filter.since {
+			db.set("since", since.timestamp()).await?;
+			conditions.push("time::unix(type::datetime(created_at)) >= $since");
+		}
+
+		if let Some(until) = filter.until {
+			db.set("until", until.timestamp()).await?;
+			conditions.push("time::unix(type::datetime(created_at)) < $until");
+		}
+
+		let where_clause = if conditions.is_empty() {
+			String::new()
+		} else {
+			format!("WHERE {}", conditions.join(" AND "))
+		};
+
+		let query = format!(
+			"SELECT * FROM type::table($table) {} ORDER BY created_at DESC LIMIT $limit",
+			where_clause
+		);
+
+		let mut response = db.query(query).await?;
+		let result: surrealdb::Value = response.take(0)?;
+		let serde_value = result.into_inner().into_json();
+		let value: Vec<Self> = serde_json::from_value(serde_value)?;
+		Ok(value)
+	}
+}
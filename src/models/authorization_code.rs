@@ -0,0 +1,226 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::Error,
+	generic::{random_alphanumeric_string, Expirable, UUID},
+	models::{
+		oauth_client::{OAuthClient, Scope},
+		user::User,
+	},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+
+const CODE_LENGTH: usize = 32;
+const AUTHORIZATION_CODE_EXPIRY_SECONDS: u64 = 60 * 10; // 10 minutes
+
+/// A one-time code that an `OAuthClient` exchanges for an access token after a user consents to it.
+///
+/// [Authorization Code Grant](https://datatracker.ietf.org/doc/html/rfc6749#section-4.1)
+#[derive(Serialize, Deserialize)]
+pub struct AuthorizationCode {
+	uuid: UUID<AuthorizationCode>,
+	created_at: DateTime<Utc>,
+	updated_at: DateTime<Utc>,
+	code: String,
+	pub client: UUID<OAuthClient>,
+	pub user: UUID<User>,
+	/// Must match the `redirect_uri` of the token request
+	redirect_uri: String,
+	pub scopes: Vec<Scope>,
+	/// [PKCE](https://datatracker.ietf.org/doc/html/rfc7636) S256 challenge, required for public clients
+	code_challenge: Option<String>,
+	#[serde(default)]
+	version: u64,
+}
+
+impl DBRecord for AuthorizationCode {
+	fn table() -> &'static str {
+		"authorization_codes"
+	}
+
+	fn uuid(&self) -> UUID<Self> {
+		self.uuid.to_owned()
+	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn unique_fields() -> Vec<&'static str> {
+		vec!["code"]
+	}
+
+	fn sensitive_fields() -> Vec<&'static str> {
+		vec!["code"]
+	}
+}
+
+impl AuthorizationCode {
+	/// Create a new code, without persisting it to the database.
+	pub fn new(
+		client: &OAuthClient,
+		user: &UUID<User>,
+		redirect_uri: &str,
+		scopes: Vec<Scope>,
+		code_challenge: Option<String>,
+	) -> Self {
+		Self {
+			uuid: UUID::new(),
+			created_at: Utc::now(),
+			updated_at: Utc::now(),
+			code: random_alphanumeric_string(CODE_LENGTH),
+			client: client.uuid(),
+			user: user.to_owned(),
+			redirect_uri: redirect_uri.to_owned(),
+			scopes,
+			code_challenge,
+			version: 0,
+		}
+	}
+
+	pub fn code(&self) -> &str {
+		&self.code
+	}
+
+	/// Exchange `code` for the authorization it represents, deleting it so it can't be used again.
+	///
+	/// Returns a 401 `Error` if the code is invalid, expired, or was issued for another client or redirect URI,
+	/// or if `code_verifier` doesn't match its PKCE challenge.
+	pub async fn redeem(
+		code: &str,
+		client: &OAuthClient,
+		redirect_uri: &str,
+		code_verifier: Option<&str>,
+	) -> Result<Self, Error> {
+		let authorization = Self::db_search_one("code", code.to_owned())
+			.await?
+			.ok_or(Error::generic_401())?;
+
+		// Any attempt uses up the code
+		authorization.db_delete().await?;
+
+		if authorization.is_expired()?
+			|| authorization.client != client.uuid()
+			|| authorization.redirect_uri != redirect_uri
+		{
+			return Err(Error::generic_401());
+		}
+
+		authorization.verify_code_verifier(code_verifier)?;
+		Ok(authorization)
+	}
+
+	/// Check `code_verifier` against the PKCE challenge of the code, if it has one.
+	///
+	/// Returns a 400 `Error` if it's missing, or a 401 `Error` if it doesn't match.
+	fn verify_code_verifier(&self, code_verifier: Option<&str>) -> Result<(), Error> {
+		if let Some(code_challenge) = &self.code_challenge {
+			let code_verifier = code_verifier.ok_or(Error::new(
+				Status::BadRequest,
+				"Missing code verifier",
+				None,
+			))?;
+
+			if &Self::code_challenge(code_verifier) != code_challenge {
+				return Err(Error::generic_401());
+			}
+		}
+
+		Ok(())
+	}
+
+	/// The S256 challenge of `code_verifier`: `BASE64URL(SHA256(code_verifier))`
+	fn code_challenge(code_verifier: &str) -> String {
+		let digest = ring::digest::digest(&ring::digest::SHA256, code_verifier.as_bytes());
+		URL_SAFE_NO_PAD.encode(digest.as_ref())
+	}
+}
+
+impl Expirable for AuthorizationCode {
+	fn start_time_field() -> &'static str {
+		"created_at"
+	}
+
+	fn expiry_seconds() -> u64 {
+		AUTHORIZATION_CODE_EXPIRY_SECONDS
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// From [RFC 7636 Appendix B](https://datatracker.ietf.org/doc/html/rfc7636#appendix-B)
+	const CODE_VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+	const CODE_CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+	fn authorization(code_challenge: Option<&str>) -> AuthorizationCode {
+		AuthorizationCode {
+			uuid: UUID::new(),
+			created_at: Utc::now(),
+			updated_at: Utc::now(),
+			code: random_alphanumeric_string(CODE_LENGTH),
+			client: UUID::new(),
+			user: UUID::new(),
+			redirect_uri: "https://example.com/callback".to_owned(),
+			scopes: vec![],
+			code_challenge: code_challenge.map(str::to_owned),
+			version: 0,
+		}
+	}
+
+	#[test]
+	fn code_challenge_matches_rfc_example() {
+		assert_eq!(
+			AuthorizationCode::code_challenge(CODE_VERIFIER),
+			CODE_CHALLENGE
+		);
+	}
+
+	#[test]
+	fn matching_code_verifier_is_accepted() {
+		authorization(Some(CODE_CHALLENGE))
+			.verify_code_verifier(Some(CODE_VERIFIER))
+			.unwrap();
+	}
+
+	#[test]
+	fn wrong_code_verifier_is_rejected() {
+		let error = authorization(Some(CODE_CHALLENGE))
+			.verify_code_verifier(Some("wrong-verifier"))
+			.unwrap_err();
+
+		assert_eq!(error.status(), Status::Unauthorized);
+	}
+
+	#[test]
+	fn challenge_is_not_accepted_as_verifier() {
+		assert!(authorization(Some(CODE_CHALLENGE))
+			.verify_code_verifier(Some(CODE_CHALLENGE))
+			.is_err());
+	}
+
+	#[test]
+	fn missing_code_verifier_is_rejected() {
+		let error = authorization(Some(CODE_CHALLENGE))
+			.verify_code_verifier(None)
+			.unwrap_err();
+
+		assert_eq!(error.status(), Status::BadRequest);
+	}
+
+	#[test]
+	fn code_verifier_is_optional_without_challenge() {
+		let authorization = authorization(None);
+		authorization.verify_code_verifier(None).unwrap();
+		authorization
+			.verify_code_verifier(Some(CODE_VERIFIER))
+			.unwrap();
+	}
+}
@@ -0,0 +1,148 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::Error,
+	generic::{random_alphanumeric_string, Expirable, UUID},
+	models::user::User,
+};
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+
+const STATE_LENGTH: usize = 32;
+const DISCORD_LOGIN_EXPIRY_SECONDS: u64 = 60 * 10; // 10 minutes
+
+/// A sign in with Discord that was started but not yet completed.
+///
+/// Its `state` is sent through Discord and back, so a redirect can't be forged or replayed.
+/// Once Discord identifies a user with two-factor authentication, a pending login for them
+/// is created to complete with a code.
+#[derive(Serialize, Deserialize)]
+pub struct DiscordLogin {
+	uuid: UUID<DiscordLogin>,
+	created_at: DateTime<Utc>,
+	updated_at: DateTime<Utc>,
+	state: String,
+	/// The signed in user to link the Discord account to, or `None` to sign in with it
+	link_user: Option<UUID<User>>,
+	/// The user identified by Discord, who still needs to enter a two-factor code
+	#[serde(default)]
+	pending_user: Option<UUID<User>>,
+	#[serde(default)]
+	version: u64,
+}
+
+impl DBRecord for DiscordLogin {
+	fn table() -> &'static str {
+		"discord_logins"
+	}
+
+	fn uuid(&self) -> UUID<Self> {
+		self.uuid.to_owned()
+	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn unique_fields() -> Vec<&'static str> {
+		vec!["state"]
+	}
+
+	fn sensitive_fields() -> Vec<&'static str> {
+		vec!["state"]
+	}
+}
+
+impl DiscordLogin {
+	/// Create a new login, without persisting it to the database.
+	pub fn new(link_user: Option<UUID<User>>) -> Self {
+		Self {
+			uuid: UUID::new(),
+			created_at: Utc::now(),
+			updated_at: Utc::now(),
+			state: random_alphanumeric_string(STATE_LENGTH),
+			link_user,
+			pending_user: None,
+			version: 0,
+		}
+	}
+
+	/// Create a login for `user` that's completed with their two-factor code, without persisting it to the database.
+	pub fn pending(user: UUID<User>) -> Self {
+		Self {
+			pending_user: Some(user),
+			..Self::new(None)
+		}
+	}
+
+	pub fn state(&self) -> &str {
+		&self.state
+	}
+
+	/// Complete the login with `state`, deleting it so it can't be used again.
+	///
+	/// Returns a 401 `Error` if it doesn't exist, expired, or wasn't started to link `link_user`.
+	pub async fn complete(state: &str, link_user: Option<&UUID<User>>) -> Result<Self, Error> {
+		let invalid = || {
+			Error::new(
+				Status::Unauthorized,
+				"Invalid or expired Discord login",
+				None,
+			)
+		};
+
+		let login = Self::db_search_one("state", state.to_owned())
+			.await?
+			.ok_or_else(invalid)?;
+
+		login.db_delete().await?;
+
+		if login.is_expired()?
+			|| login.link_user.as_ref() != link_user
+			|| login.pending_user.is_some()
+		{
+			return Err(invalid());
+		}
+
+		Ok(login)
+	}
+
+	/// Get the pending login with `state` and the user it's for.
+	///
+	/// It isn't deleted, so a wrong two-factor code can be retried until it expires.
+	/// Returns a 401 `Error` if it doesn't exist or expired.
+	pub async fn find_pending(state: &str) -> Result<(Self, UUID<User>), Error> {
+		let invalid = || {
+			Error::new(
+				Status::Unauthorized,
+				"Invalid or expired Discord login",
+				None,
+			)
+		};
+
+		let login = Self::db_search_one("state", state.to_owned())
+			.await?
+			.ok_or_else(invalid)?;
+
+		if login.is_expired()? {
+			return Err(invalid());
+		}
+
+		let user = login.pending_user.clone().ok_or_else(invalid)?;
+		Ok((login, user))
+	}
+}
+
+impl Expirable for DiscordLogin {
+	fn start_time_field() -> &'static str {
+		"created_at"
+	}
+
+	fn expiry_seconds() -> u64 {
+		DISCORD_LOGIN_EXPIRY_SECONDS
+	}
+}
@@ -0,0 +1,85 @@
+use crate::{
+	dbrecord::DBRecord,
+	generic::{Expirable, UUID},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+const JOB_RUN_RETENTION_SECONDS: u64 = 60 * 60 * 24 * 30; // 30 days
+
+/// A finished run of a scheduled `Job`, kept for 30 days.
+#[derive(Serialize, Deserialize)]
+pub struct JobRun {
+	pub uuid: UUID<JobRun>,
+	/// Name of the job
+	pub job: String,
+	pub started_at: DateTime<Utc>,
+	pub finished_at: DateTime<Utc>,
+	pub outcome: JobOutcome,
+	/// The error returned by the job if it failed
+	pub error: Option<String>,
+	#[serde(default)]
+	version: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobOutcome {
+	Success,
+	Failure,
+}
+
+impl DBRecord for JobRun {
+	fn table() -> &'static str {
+		"job_runs"
+	}
+
+	fn uuid(&self) -> UUID<Self> {
+		self.uuid.to_owned()
+	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn audited() -> bool {
+		false
+	}
+}
+
+impl JobRun {
+	pub fn new(
+		job: &str,
+		started_at: DateTime<Utc>,
+		finished_at: DateTime<Utc>,
+		error: Option<String>,
+	) -> Self {
+		Self {
+			uuid: UUID::new(),
+			job: job.to_owned(),
+			started_at,
+			finished_at,
+			outcome: if error.is_some() {
+				JobOutcome::Failure
+			} else {
+				JobOutcome::Success
+			},
+			error,
+			version: 0,
+		}
+	}
+}
+
+impl Expirable for JobRun {
+	fn start_time_field() -> &'static str {
+		"finished_at"
+	}
+
+	fn expiry_seconds() -> u64 {
+		JOB_RUN_RETENTION_SECONDS
+	}
+}
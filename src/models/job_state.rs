@@ -0,0 +1,80 @@
+use crate::{dbrecord::DBRecord, error::Error, generic::UUID, models::job_run::JobOutcome};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Id, Thing};
+
+/// The persisted schedule and last result of a `Job`, with the job's name as its ID.
+#[derive(Serialize, Deserialize)]
+pub struct JobState {
+	pub uuid: UUID<JobState>,
+	pub name: String,
+	/// `None` until the job has been scheduled once
+	pub next_run_at: Option<DateTime<Utc>>,
+	/// Set while the job runs, so it doesn't overlap with itself across instances
+	pub running_since: Option<DateTime<Utc>>,
+	pub last_started_at: Option<DateTime<Utc>>,
+	pub last_finished_at: Option<DateTime<Utc>>,
+	pub last_outcome: Option<JobOutcome>,
+	pub last_error: Option<String>,
+	/// Paused jobs skip their scheduled runs, but can still be run manually
+	#[serde(default)]
+	pub paused: bool,
+	created_at: DateTime<Utc>,
+	updated_at: DateTime<Utc>,
+	#[serde(default)]
+	version: u64,
+}
+
+impl DBRecord for JobState {
+	fn table() -> &'static str {
+		"job_states"
+	}
+
+	fn uuid(&self) -> UUID<Self> {
+		self.uuid.to_owned()
+	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn audited() -> bool {
+		false
+	}
+}
+
+impl JobState {
+	/// Get the state of the job with the given name, creating it if it doesn't exist.
+	pub async fn load(name: &str) -> Result<Self, Error> {
+		if let Some(state) = Self::db_by_id(name).await? {
+			return Ok(state);
+		}
+
+		let state = Self {
+			uuid: Thing::from((Self::table(), Id::from(name))).into(),
+			name: name.to_owned(),
+			next_run_at: None,
+			running_since: None,
+			last_started_at: None,
+			last_finished_at: None,
+			last_outcome: None,
+			last_error: None,
+			paused: false,
+			created_at: Utc::now(),
+			updated_at: Utc::now(),
+			version: 0,
+		};
+
+		match state.db_create().await {
+			Ok(state) => Ok(state),
+			// Created by another instance in the meantime
+			Err(_) => Self::db_by_id(name).await?.ok_or_else(|| {
+				Error::generic_500(&format!("Failed to create job state: {}", name))
+			}),
+		}
+	}
+}
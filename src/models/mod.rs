@@ -1,5 +1,14 @@
+pub mod audit_entry;
+pub mod authorization_code;
+pub mod discord_login;
+pub mod job_run;
+pub mod job_state;
+pub mod oauth_client;
+pub mod passkey;
+pub mod password_reset;
 pub mod pool_game;
 pub mod pool_player;
 pub mod registration;
 pub mod session;
 pub mod user;
+pub mod webauthn_challenge;
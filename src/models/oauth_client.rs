@@ -0,0 +1,250 @@
+use crate::{
+	dbrecord::{DBRecord, DBTransaction},
+	error::Error,
+	generic::{random_alphanumeric_string, HashedString, JwtClaims, UUID},
+	models::{authorization_code::AuthorizationCode, session::Session},
+	signing_keys::SigningKeys,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use strum::{AsRefStr, EnumString};
+use surrealdb::sql::Uuid;
+
+const CLIENT_SECRET_LENGTH: usize = 48;
+/// The audience of access tokens issued to clients with the client credentials grant
+pub const CLIENT_AUDIENCE: &str = "kavacoast.com-client";
+
+/// A third-party application that users can sign in to with their account.
+#[derive(Serialize, Deserialize)]
+pub struct OAuthClient {
+	pub uuid: UUID<OAuthClient>,
+	created_at: DateTime<Utc>,
+	updated_at: DateTime<Utc>,
+	pub name: String,
+	/// `None` for public clients, which can't keep a secret and must use PKCE
+	client_secret_hash: Option<HashedString>,
+	/// Authorization responses are only sent to these exact URIs
+	pub redirect_uris: Vec<String>,
+	/// The scopes the client may request
+	pub scopes: Vec<Scope>,
+	#[serde(default)]
+	version: u64,
+}
+
+/// Access that a user or admin can grant to an `OAuthClient`.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, EnumString, AsRefStr)]
+pub enum Scope {
+	/// Read the user's username and display name
+	#[serde(rename = "profile")]
+	#[strum(serialize = "profile")]
+	Profile,
+	/// Read pool players and games
+	#[serde(rename = "pool:read")]
+	#[strum(serialize = "pool:read")]
+	PoolRead,
+}
+
+#[async_trait]
+impl DBRecord for OAuthClient {
+	fn table() -> &'static str {
+		"oauth_clients"
+	}
+
+	fn uuid(&self) -> UUID<Self> {
+		self.uuid.to_owned()
+	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn sensitive_fields() -> Vec<&'static str> {
+		vec!["client_secret_hash"]
+	}
+
+	async fn delete_hook(&self, transaction: &mut DBTransaction) -> Result<(), Error> {
+		for session in Session::db_search("client", self.uuid()).await? {
+			transaction.delete(&session).await?;
+		}
+
+		for code in AuthorizationCode::db_search("client", self.uuid()).await? {
+			transaction.delete(&code).await?;
+		}
+
+		Ok(())
+	}
+}
+
+impl OAuthClient {
+	/// Create a new client, without persisting it to the database.
+	///
+	/// Confidential clients are returned with their secret, which is only stored hashed.
+	pub fn new(
+		name: &str,
+		redirect_uris: Vec<String>,
+		scopes: Vec<Scope>,
+		confidential: bool,
+	) -> Result<(Self, Option<String>), Error> {
+		for redirect_uri in &redirect_uris {
+			let url = reqwest::Url::parse(redirect_uri).map_err(|_| {
+				Error::new(
+					Status::BadRequest,
+					"Invalid redirect URI",
+					Some(redirect_uri),
+				)
+			})?;
+
+			if url.fragment().is_some() {
+				return Err(Error::new(
+					Status::BadRequest,
+					"Redirect URIs can't have a fragment",
+					None,
+				));
+			}
+		}
+
+		let client_secret = confidential.then(|| random_alphanumeric_string(CLIENT_SECRET_LENGTH));
+
+		let client = Self {
+			uuid: UUID::new(),
+			created_at: Utc::now(),
+			updated_at: Utc::now(),
+			name: name.to_owned(),
+			client_secret_hash: client_secret
+				.as_deref()
+				.map(HashedString::new)
+				.transpose()?,
+			redirect_uris,
+			scopes,
+			version: 0,
+		};
+
+		Ok((client, client_secret))
+	}
+
+	/// Whether the client can keep a secret, and so can use the client credentials grant.
+	pub fn is_confidential(&self) -> bool {
+		self.client_secret_hash.is_some()
+	}
+
+	/// Find the client with `client_id`, verifying `client_secret` if it is confidential.
+	///
+	/// Returns a 401 `Error` if the client doesn't exist or the secret doesn't match.
+	pub async fn authenticate(client_id: &str, client_secret: Option<&str>) -> Result<Self, Error> {
+		let client = Self::db_by_id(client_id)
+			.await?
+			.ok_or(Error::generic_401())?;
+
+		if let Some(hash) = &client.client_secret_hash {
+			let client_secret = client_secret.ok_or(Error::generic_401())?;
+
+			if !hash.verify(client_secret)? {
+				return Err(Error::generic_401());
+			}
+		}
+
+		Ok(client)
+	}
+
+	/// Parse space-separated `scope`, which must only include scopes the client may request.
+	///
+	/// Defaults to every scope of the client if `scope` is `None`.
+	pub fn requested_scopes(&self, scope: Option<&str>) -> Result<Vec<Scope>, Error> {
+		let Some(scope) = scope else {
+			return Ok(self.scopes.to_owned());
+		};
+
+		let scopes = Scope::parse_list(scope)?;
+
+		if scopes.iter().any(|s| !self.scopes.contains(s)) {
+			return Err(Error::new(
+				Status::BadRequest,
+				"Scope not allowed for this client",
+				None,
+			));
+		}
+
+		Ok(scopes)
+	}
+
+	/// Generate an access token for the client itself, not acting for any user.
+	pub fn generate_access_token(&self, scopes: &[Scope]) -> Result<String, Error> {
+		let now = Utc::now().timestamp() as u64;
+
+		let claims = JwtClaims {
+			sub: self.uuid.uuid_string(),
+			exp: now + crate::models::session::ACCESS_TOKEN_EXPIRY_SECONDS,
+			iat: now,
+			iss: "kavacoast.com".to_owned(),
+			aud: CLIENT_AUDIENCE.to_owned(),
+			jti: Uuid::new_v4().to_raw(),
+			epoch: 0,
+			scope: Some(Scope::join(scopes)),
+		};
+
+		SigningKeys::get()?.encode(&claims)
+	}
+
+	/// Get the client and scopes of verified client credentials `claims`.
+	///
+	/// Scopes removed from the client since the token was issued are excluded.
+	pub async fn from_claims(claims: &JwtClaims) -> Result<(Self, Vec<Scope>), Error> {
+		let client = Self::db_by_id(&claims.sub)
+			.await?
+			.ok_or(Error::generic_401())?;
+
+		let scopes = Scope::parse_list(claims.scope.as_deref().unwrap_or_default())?
+			.into_iter()
+			.filter(|scope| client.scopes.contains(scope))
+			.collect();
+
+		Ok((client, scopes))
+	}
+}
+
+impl Scope {
+	/// Parse a space-separated list of scopes.
+	pub fn parse_list(scope: &str) -> Result<Vec<Self>, Error> {
+		let mut scopes = vec![];
+
+		for name in scope.split_whitespace() {
+			let scope = Self::from_str(name).map_err(|_| {
+				Error::new(
+					Status::BadRequest,
+					&format!("Unknown scope: {}", name),
+					None,
+				)
+			})?;
+
+			if !scopes.contains(&scope) {
+				scopes.push(scope);
+			}
+		}
+
+		Ok(scopes)
+	}
+
+	/// Join scopes into a space-separated list.
+	pub fn join(scopes: &[Self]) -> String {
+		scopes
+			.iter()
+			.map(|scope| scope.as_ref())
+			.collect::<Vec<_>>()
+			.join(" ")
+	}
+
+	/// What the scope grants, as shown to users on the consent screen.
+	pub fn description(&self) -> &'static str {
+		match self {
+			Self::Profile => "See your username and display name",
+			Self::PoolRead => "See pool players and game results",
+		}
+	}
+}
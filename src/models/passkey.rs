@@ -0,0 +1,135 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::Error,
+	generic::UUID,
+	models::user::User,
+	webauthn::{NewCredential, PublicKey, RelyingParty},
+};
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+pub const MAX_PASSKEY_NAME_LENGTH: usize = 64;
+
+/// A WebAuthn credential that a user signs in with instead of their password.
+#[derive(Serialize, Deserialize)]
+pub struct Passkey {
+	pub uuid: UUID<Passkey>,
+	created_at: DateTime<Utc>,
+	updated_at: DateTime<Utc>,
+	pub user: UUID<User>,
+	/// Chosen by the user to tell their passkeys apart, e.g. "Phone"
+	pub name: String,
+	/// Base64url ID the authenticator identifies the credential by
+	pub credential_id: String,
+	public_key: PublicKey,
+	/// The authenticator's signature counter, used to detect cloned authenticators
+	sign_count: u32,
+	last_used_at: Option<DateTime<Utc>>,
+	#[serde(default)]
+	version: u64,
+}
+
+/// A passkey as listed on the settings page.
+#[derive(Serialize)]
+pub struct PasskeyInfo {
+	pub uuid: String,
+	pub name: String,
+	pub created_at: DateTime<Utc>,
+	pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl DBRecord for Passkey {
+	fn table() -> &'static str {
+		"passkeys"
+	}
+
+	fn uuid(&self) -> UUID<Self> {
+		self.uuid.to_owned()
+	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn unique_fields() -> Vec<&'static str> {
+		vec!["credential_id"]
+	}
+}
+
+impl Passkey {
+	/// Create a new passkey, without persisting it to the database.
+	pub fn new(user: &UUID<User>, name: &str, credential: NewCredential) -> Result<Self, Error> {
+		let name = name.trim();
+
+		if name.is_empty() || name.chars().count() > MAX_PASSKEY_NAME_LENGTH {
+			return Err(Error::new(
+				Status::BadRequest,
+				&format!(
+					"Passkey name must be between 1 and {} characters",
+					MAX_PASSKEY_NAME_LENGTH
+				),
+				None,
+			));
+		}
+
+		Ok(Self {
+			uuid: UUID::new(),
+			created_at: Utc::now(),
+			updated_at: Utc::now(),
+			user: user.to_owned(),
+			name: name.to_owned(),
+			credential_id: credential.id,
+			public_key: credential.public_key,
+			sign_count: credential.sign_count,
+			last_used_at: None,
+			version: 0,
+		})
+	}
+
+	/// Verify an authentication assertion signed by the passkey for `challenge`, and record its use.
+	///
+	/// Returns a 401 `Error` if it's invalid.
+	pub async fn authenticate(
+		&mut self,
+		challenge: &str,
+		client_data_json: &str,
+		authenticator_data: &str,
+		signature: &str,
+	) -> Result<(), Error> {
+		let sign_count = RelyingParty::from_env().verify_authentication(
+			challenge,
+			&self.public_key,
+			self.sign_count,
+			client_data_json,
+			authenticator_data,
+			signature,
+		)?;
+
+		let now = Utc::now();
+
+		self.db_update_fields(vec![
+			("sign_count", json!(sign_count)),
+			("last_used_at", json!(now)),
+		])
+		.await?;
+
+		self.sign_count = sign_count;
+		self.last_used_at = Some(now);
+		Ok(())
+	}
+
+	pub fn info(&self) -> PasskeyInfo {
+		PasskeyInfo {
+			uuid: self.uuid.uuid_string(),
+			name: self.name.to_owned(),
+			created_at: self.created_at,
+			last_used_at: self.last_used_at,
+		}
+	}
+}
@@ -0,0 +1,134 @@
+use crate::{
+	dbrecord::{DBRecord, DBTransaction},
+	error::Error,
+	generic::{random_alphanumeric_string, Expirable, TokenDigest, UUID},
+	models::user::User,
+	webauthn::RelyingParty,
+};
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+
+const TOKEN_LENGTH: usize = 48;
+const PASSWORD_RESET_EXPIRY_SECONDS: u64 = 60 * 30; // 30 minutes
+
+/// A one-time link that lets a user who forgot their password choose a new one.
+#[derive(Serialize, Deserialize)]
+pub struct PasswordReset {
+	uuid: UUID<PasswordReset>,
+	created_at: DateTime<Utc>,
+	updated_at: DateTime<Utc>,
+	/// The token itself is only sent to the user
+	token_digest: TokenDigest,
+	user: UUID<User>,
+	#[serde(default)]
+	version: u64,
+}
+
+impl DBRecord for PasswordReset {
+	fn table() -> &'static str {
+		"password_resets"
+	}
+
+	fn uuid(&self) -> UUID<Self> {
+		self.uuid.to_owned()
+	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn unique_fields() -> Vec<&'static str> {
+		vec!["token_digest"]
+	}
+
+	fn sensitive_fields() -> Vec<&'static str> {
+		vec!["token_digest"]
+	}
+}
+
+impl PasswordReset {
+	/// Create a reset for `user` and persist it to the database, replacing any earlier one.
+	///
+	/// Returns its token, to send to the user with `message()`.
+	pub async fn create(user: &User) -> Result<String, Error> {
+		let token = random_alphanumeric_string(TOKEN_LENGTH);
+
+		let reset = Self {
+			uuid: UUID::new(),
+			created_at: Utc::now(),
+			updated_at: Utc::now(),
+			token_digest: TokenDigest::new(&token),
+			user: user.uuid.to_owned(),
+			version: 0,
+		};
+
+		let mut transaction = DBTransaction::new();
+
+		for earlier in Self::db_search("user", user.uuid.to_owned()).await? {
+			transaction.delete(&earlier).await?;
+		}
+
+		transaction.create(&reset)?;
+		transaction.commit().await?;
+		Ok(token)
+	}
+
+	/// The link to reset the password with `token`, on the site's configured origin.
+	pub fn link(token: &str) -> String {
+		format!(
+			"{}/reset_password?token={}",
+			RelyingParty::from_env().origin,
+			token
+		)
+	}
+
+	/// The message to send to `username` with the link to reset their password with `token`.
+	pub fn message(token: &str, username: &str) -> String {
+		format!(
+			"A password reset was requested for `{}`. Choose a new password using this link:\n{}\n\nIt can only be used once and expires in {} minutes. If you didn't request it, ignore this message.",
+			username,
+			Self::link(token),
+			Self::expiry_seconds() / 60
+		)
+	}
+
+	/// Use the reset with `token`, deleting it so it can't be used again, and return its user.
+	///
+	/// Returns a 401 `Error` if it doesn't exist or expired.
+	pub async fn redeem(token: &str) -> Result<User, Error> {
+		let invalid = || {
+			Error::new(
+				Status::Unauthorized,
+				"Invalid or expired password reset link",
+				None,
+			)
+		};
+
+		let reset = Self::db_search_one("token_digest", TokenDigest::new(token))
+			.await?
+			.ok_or_else(invalid)?;
+
+		reset.db_delete().await?;
+
+		if reset.is_expired()? {
+			return Err(invalid());
+		}
+
+		reset.user.object_opt().await?.ok_or_else(invalid)
+	}
+}
+
+impl Expirable for PasswordReset {
+	fn start_time_field() -> &'static str {
+		"created_at"
+	}
+
+	fn expiry_seconds() -> u64 {
+		PASSWORD_RESET_EXPIRY_SECONDS
+	}
+}
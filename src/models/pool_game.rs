@@ -1,10 +1,15 @@
 use crate::{
 	dbrecord::DBRecord,
+	error::Error,
 	generic::UUID,
-	models::{pool_player::PoolPlayer, user::User},
+	models::{
+		pool_player::{PoolPlayer, PoolPlayerSummary},
+		user::{User, UserSummary},
+	},
 };
 use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
+use strum::{EnumIter, IntoEnumIterator};
 
 #[derive(Serialize, Deserialize)]
 pub struct PoolGame {
@@ -17,9 +22,27 @@ pub struct PoolGame {
 	host: UUID<User>,
 	created_at: DateTime<Utc>,
 	updated_at: DateTime<Utc>,
+	#[serde(default)]
+	version: u64,
 }
 
+/// A `PoolGame` with its players and host embedded, `None` if they no longer exist.
 #[derive(Serialize, Deserialize)]
+pub struct ExpandedPoolGame {
+	pub uuid: UUID<PoolGame>,
+	date: NaiveDate,
+	player1: Option<PoolPlayerSummary>,
+	player2: Option<PoolPlayerSummary>,
+	winner: PoolGameWinner,
+	game_type: PoolGameType,
+	host: Option<UserSummary>,
+	created_at: DateTime<Utc>,
+	updated_at: DateTime<Utc>,
+	#[serde(default)]
+	version: u64,
+}
+
+#[derive(Serialize, Deserialize, EnumIter)]
 #[serde(rename_all = "snake_case")]
 #[allow(clippy::enum_variant_names)]
 pub enum PoolGameType {
@@ -28,6 +51,12 @@ pub enum PoolGameType {
 	TenBall,
 }
 
+impl PoolGameType {
+	pub fn all() -> Vec<Self> {
+		PoolGameType::iter().collect()
+	}
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PoolGameWinner {
@@ -44,12 +73,20 @@ impl DBRecord for PoolGame {
 	fn uuid(&self) -> UUID<Self> {
 		self.uuid.to_owned()
 	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
 }
 
 impl PoolGame {
 	pub fn new(player1: UUID<PoolPlayer>, player2: UUID<PoolPlayer>, host: UUID<User>) -> Self {
 		Self {
-			uuid: UUID::default(),
+			uuid: UUID::new(),
 			date: Utc::now().date_naive(),
 			player1,
 			player2,
@@ -58,6 +95,29 @@ impl PoolGame {
 			host,
 			created_at: Utc::now(),
 			updated_at: Utc::now(),
+			version: 0,
 		}
 	}
+
+	pub fn with_date(self, date: NaiveDate) -> Self {
+		Self { date, ..self }
+	}
+
+	pub fn with_winner(self, winner: PoolGameWinner) -> Self {
+		Self { winner, ..self }
+	}
+
+	pub fn with_game_type(self, game_type: PoolGameType) -> Self {
+		Self { game_type, ..self }
+	}
+
+	/// Get all games with their players and host in a single query.
+	pub async fn all_expanded() -> Result<Vec<ExpandedPoolGame>, Error> {
+		Self::db_all_fetched(&[
+			("player1", PoolPlayer::table()),
+			("player2", PoolPlayer::table()),
+			("host", User::table()),
+		])
+		.await
+	}
 }
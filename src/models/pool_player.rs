@@ -1,14 +1,37 @@
-use crate::{dbrecord::DBRecord, generic::UUID, models::user::User};
+use crate::{
+	dbrecord::DBRecord,
+	error::Error,
+	generic::UUID,
+	models::user::{User, PLAYS_AS},
+};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize)]
 pub struct PoolPlayer {
 	pub uuid: UUID<PoolPlayer>,
-	pub user: Option<UUID<User>>,
 	pub descriptor: Option<String>,
 	created_at: DateTime<Utc>,
 	updated_at: DateTime<Utc>,
+	#[serde(default)]
+	version: u64,
+}
+
+/// A `PoolPlayer` with the user that plays as it, as returned by the API.
+#[derive(Serialize)]
+pub struct PoolPlayerInfo {
+	#[serde(flatten)]
+	player: PoolPlayer,
+	/// From the `plays_as` relation
+	user: Option<UUID<User>>,
+}
+
+/// The fields of a `PoolPlayer` embedded in other records.
+#[derive(Serialize, Deserialize)]
+pub struct PoolPlayerSummary {
+	pub uuid: UUID<PoolPlayer>,
+	pub descriptor: Option<String>,
 }
 
 impl DBRecord for PoolPlayer {
@@ -20,9 +43,21 @@ impl DBRecord for PoolPlayer {
 		self.uuid.to_owned()
 	}
 
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
 	fn use_trash() -> bool {
 		true
 	}
+
+	fn relations() -> Vec<&'static str> {
+		vec![PLAYS_AS.name()]
+	}
 }
 
 #[allow(dead_code)]
@@ -30,18 +65,45 @@ impl PoolPlayer {
 	pub fn new() -> Self {
 		Self {
 			uuid: UUID::new(),
-			user: None,
 			descriptor: None,
 			created_at: Utc::now(),
 			updated_at: Utc::now(),
+			version: 0,
 		}
 	}
 
-	pub fn with_user(self, user: UUID<User>) -> Self {
-		Self {
-			user: Some(user),
-			..self
-		}
+	/// Get the user linked to this player, if any.
+	pub async fn user(&self) -> Result<Option<User>, Error> {
+		Ok(self.uuid.related_by(&PLAYS_AS).await?.into_iter().next())
+	}
+
+	/// Get the player with the user linked to it.
+	pub async fn info(self) -> Result<PoolPlayerInfo, Error> {
+		let user = self.user().await?.map(|user| user.uuid());
+		Ok(self.info_with_user(user))
+	}
+
+	/// Get the players with the users linked to them, loading every `plays_as` edge in a single query.
+	pub async fn infos(players: Vec<Self>) -> Result<Vec<PoolPlayerInfo>, Error> {
+		let users: HashMap<String, UUID<User>> = PLAYS_AS
+			.all_ends()
+			.await?
+			.into_iter()
+			.map(|(user, player)| (player.uuid_string(), user))
+			.collect();
+
+		Ok(players
+			.into_iter()
+			.map(|player| {
+				let user = users.get(&player.uuid.uuid_string()).cloned();
+				player.info_with_user(user)
+			})
+			.collect())
+	}
+
+	/// Get the player with `user`, which must be the user linked to it.
+	pub fn info_with_user(self, user: Option<UUID<User>>) -> PoolPlayerInfo {
+		PoolPlayerInfo { player: self, user }
 	}
 
 	pub fn with_descriptor(self, descriptor: &str) -> Self {
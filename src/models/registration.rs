@@ -1,9 +1,15 @@
-use crate::{dbrecord::DBRecord, generic::UUID, models::user::User};
+use crate::{
+	dbrecord::DBRecord,
+	error::Error,
+	generic::{Environment, Expirable, UUID},
+	models::user::User,
+};
 use chrono::{DateTime, Utc};
 use either::Either;
 use serde::{Deserialize, Serialize};
 
 const KEY_LENGTH: usize = 16;
+pub const DEFAULT_REGISTRATION_EXPIRY_DAYS: u64 = 7;
 
 #[derive(Serialize, Deserialize)]
 /// A registration link that has not yet been used.
@@ -14,6 +20,8 @@ pub struct Registration {
 	pub registration_key: String,
 	/// Left if referral, or Right (discord id) if generated by the bot.
 	pub referrer_or_discord: Either<UUID<User>, String>,
+	#[serde(default)]
+	version: u64,
 }
 
 impl Default for Registration {
@@ -24,6 +32,7 @@ impl Default for Registration {
 			updated_at: Utc::now(),
 			registration_key: String::new(),
 			referrer_or_discord: Either::Right(String::new()),
+			version: 0,
 		}
 	}
 }
@@ -36,6 +45,22 @@ impl DBRecord for Registration {
 	fn uuid(&self) -> UUID<Self> {
 		self.uuid.to_owned()
 	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn unique_fields() -> Vec<&'static str> {
+		vec!["registration_key"]
+	}
+
+	fn sensitive_fields() -> Vec<&'static str> {
+		vec!["registration_key"]
+	}
 }
 
 impl Registration {
@@ -46,6 +71,7 @@ impl Registration {
 			updated_at: Utc::now(),
 			registration_key: crate::generic::random_alphanumeric_string(KEY_LENGTH),
 			referrer_or_discord: Either::Right(discord_id.to_owned()),
+			version: 0,
 		}
 	}
 
@@ -56,13 +82,51 @@ impl Registration {
 			updated_at: Utc::now(),
 			registration_key: crate::generic::random_alphanumeric_string(KEY_LENGTH),
 			referrer_or_discord: Either::Left(user.uuid()),
+			version: 0,
+		}
+	}
+
+	/// Get the registration generated by the bot for `discord_id`, or `None` if there isn't one.
+	pub async fn find_by_discord_id(discord_id: &str) -> Result<Option<Self>, Error> {
+		// `Either` is serialized as `{ "Right": discord_id }`
+		Self::db_search_one("referrer_or_discord.Right", discord_id.to_owned()).await
+	}
+
+	/// Get the registration with the given key, or `None` if there isn't one or it has expired.
+	pub async fn find_valid(registration_key: &str) -> Result<Option<Self>, Error> {
+		let registration =
+			Self::db_search_one("registration_key", registration_key.to_owned()).await?;
+
+		match registration {
+			Some(registration) if !registration.is_expired()? => Ok(Some(registration)),
+			_ => Ok(None),
 		}
 	}
 
 	pub fn dm_string(&self) -> String {
 		format!(
-			"Register using this link: \nhttps://kavacoast.com/register?k={}\n\nThis registration url is linked to your Discord account, and can only be used to register once.",
-			self.registration_key
+			"Register using this link: \nhttps://kavacoast.com/register?k={}\n\nThis registration url is linked to your Discord account, can only be used to register once, and expires in {} days.",
+			self.registration_key,
+			Self::expiry_seconds() / (60 * 60 * 24)
 		)
 	}
 }
+
+impl Expirable for Registration {
+	fn start_time_field() -> &'static str {
+		"created_at"
+	}
+
+	/// `REGISTRATION_EXPIRY_DAYS`, or `DEFAULT_REGISTRATION_EXPIRY_DAYS` if unset or invalid
+	fn expiry_seconds() -> u64 {
+		let days = match Environment::new().registration_expiry_days.val_opt() {
+			Some(days) => days.parse::<u64>().unwrap_or_else(|e| {
+				log::warn!("Invalid REGISTRATION_EXPIRY_DAYS: {}", e);
+				DEFAULT_REGISTRATION_EXPIRY_DAYS
+			}),
+			None => DEFAULT_REGISTRATION_EXPIRY_DAYS,
+		};
+
+		days * 60 * 60 * 24
+	}
+}
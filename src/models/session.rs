@@ -1,16 +1,29 @@
 use crate::{
-	dbrecord::DBRecord,
+	dbrecord::{DBRecord, DBTransaction},
 	error::Error,
-	generic::{Environment, Expirable, HashedString, JwtClaims, UUID},
-	models::user::User,
+	generic::{ClientInfo, Expirable, JwtClaims, TokenDigest, UUID},
+	models::{
+		authorization_code::AuthorizationCode,
+		oauth_client::{OAuthClient, Scope},
+		user::User,
+	},
+	signing_keys::SigningKeys,
 };
 use chrono::{DateTime, Utc};
 use rocket::http::Status;
 use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	sync::{LazyLock, Mutex},
+};
 use surrealdb::sql::Uuid;
 
 pub const ACCESS_TOKEN_EXPIRY_SECONDS: u64 = 60 * 60; // 1 hour
 pub const REFRESH_TOKEN_EXPIRY_SECONDS: u64 = 60 * 60 * 24 * 30; // 30 days
+/// The audience of access tokens issued for sessions
+pub const SESSION_AUDIENCE: &str = "kavacoast.com-session";
+/// How many rotated refresh tokens of a session are kept to detect their reuse
+pub const REFRESH_TOKEN_FAMILY_SIZE: usize = 10;
 
 #[derive(Serialize, Deserialize)]
 pub struct Session {
@@ -18,8 +31,52 @@ pub struct Session {
 	created_at: DateTime<Utc>,
 	updated_at: DateTime<Utc>,
 	user: UUID<User>,
-	pub refresh_token_hash: HashedString,
+	/// Empty for sessions from before refresh tokens were stored as digests, which are deleted on startup
+	#[serde(default)]
+	pub refresh_token_digest: TokenDigest,
 	pub refresh_token_issued_at: DateTime<Utc>,
+	/// Digests of the most recently rotated refresh tokens, newest first.
+	///
+	/// Presenting one of them means the token was stolen, so the session is revoked.
+	#[serde(default)]
+	pub rotated_refresh_token_digests: Vec<TokenDigest>,
+	/// The `OAuthClient` the user authorized, or `None` if signed in to this site
+	#[serde(default)]
+	pub client: Option<UUID<OAuthClient>>,
+	/// The scopes the user consented to, if authorized for an `OAuthClient`
+	#[serde(default)]
+	pub scopes: Vec<Scope>,
+	#[serde(default)]
+	pub user_agent: Option<String>,
+	#[serde(default)]
+	pub ip: Option<String>,
+	/// Incremented to revoke every access token issued for the session so far
+	#[serde(default)]
+	pub token_epoch: u64,
+	#[serde(default)]
+	version: u64,
+}
+
+/// Access tokens revoked before they expire, by `jti`, with their expiration times.
+///
+/// Lets this instance reject them without loading the session.
+/// Other instances reject them when they load the session, by its `token_epoch` or absence.
+static DENIED_ACCESS_TOKENS: LazyLock<Mutex<HashMap<String, u64>>> =
+	LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The public fields of a `Session`, as listed to its user.
+#[derive(Serialize)]
+pub struct SessionInfo {
+	id: String,
+	created_at: DateTime<Utc>,
+	last_refreshed_at: DateTime<Utc>,
+	user_agent: Option<String>,
+	ip: Option<String>,
+	/// The `OAuthClient` the user authorized, if any
+	client_id: Option<String>,
+	scopes: Vec<Scope>,
+	/// Whether this is the session making the request
+	current: bool,
 }
 
 impl DBRecord for Session {
@@ -30,54 +87,122 @@ impl DBRecord for Session {
 	fn uuid(&self) -> UUID<Self> {
 		self.uuid.to_owned()
 	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn sensitive_fields() -> Vec<&'static str> {
+		vec!["refresh_token_digest", "rotated_refresh_token_digests"]
+	}
 }
 
 impl Session {
 	/// Create a new Session, without persisting it to the database.
-	pub fn new(user: &UUID<User>) -> Result<Self, Error> {
+	pub fn new(user: &UUID<User>, client: &ClientInfo) -> Result<Self, Error> {
 		Ok(Self {
 			uuid: UUID::new(),
 			created_at: Utc::now(),
 			updated_at: Utc::now(),
 			user: user.to_owned(),
-			refresh_token_hash: HashedString::new(&Uuid::new_v4().to_string())?,
+			refresh_token_digest: TokenDigest::new(&Uuid::new_v4().to_string()),
 			refresh_token_issued_at: Utc::now(),
+			rotated_refresh_token_digests: vec![],
+			client: None,
+			scopes: vec![],
+			user_agent: client.user_agent.to_owned(),
+			ip: client.ip.to_owned(),
+			token_epoch: 0,
+			version: 0,
 		})
 	}
 
-	/// Verify the access token and return the Session associated with it.
-	pub async fn from_access_token(access_token: &str) -> Result<Self, Error> {
-		let env = Environment::new();
-		let secret = env.oauth_jwt_secret.val();
-		let decoding_key = jsonwebtoken::DecodingKey::from_secret(secret.as_ref());
+	/// Create a new Session for the `OAuthClient` a user authorized, without persisting it to the database.
+	///
+	/// Its refresh token is never issued, so it can't be refreshed.
+	pub fn from_authorization_code(
+		authorization: &AuthorizationCode,
+		client: &ClientInfo,
+	) -> Result<Self, Error> {
+		let mut session = Self::new(&authorization.user, client)?;
+		session.client = Some(authorization.client.to_owned());
+		session.scopes = authorization.scopes.to_owned();
+		Ok(session)
+	}
+
+	/// Get the public fields of the session, marked as current if it is `current`.
+	pub fn info(&self, current: &UUID<Session>) -> SessionInfo {
+		SessionInfo {
+			id: self.uuid.uuid_string(),
+			created_at: self.created_at,
+			last_refreshed_at: self.refresh_token_issued_at,
+			user_agent: self.user_agent.to_owned(),
+			ip: self.ip.to_owned(),
+			client_id: self.client.as_ref().map(|client| client.uuid_string()),
+			scopes: self.scopes.to_owned(),
+			current: &self.uuid == current,
+		}
+	}
 
-		let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
-		validation.set_audience(&["kavacoast.com-session"]);
-		validation.set_issuer(&["kavacoast.com"]);
+	/// Get the Session of verified access token `claims`, checking that the token wasn't revoked.
+	///
+	/// Returns a 401 `Error` if the token was denied, or the session was deleted or revoked its access tokens.
+	pub async fn from_claims(claims: &JwtClaims) -> Result<Self, Error> {
+		let denied = DENIED_ACCESS_TOKENS
+			.lock()
+			.map_err(|_| Error::generic_500("Denied access tokens lock poisoned"))?
+			.contains_key(&claims.jti);
 
-		let token_data =
-			jsonwebtoken::decode::<JwtClaims>(access_token, &decoding_key, &validation)
-				.map_err(|_| Error::generic_401())?;
-		// note: decode() also checks expiration
+		if denied {
+			return Err(Error::generic_401());
+		}
 
-		let session: Session = Self::db_by_id(&token_data.claims.sub)
+		let session: Session = Self::db_by_id(&claims.sub)
 			.await?
 			.ok_or(Error::generic_401())?;
 
+		if claims.epoch != session.token_epoch {
+			return Err(Error::generic_401());
+		}
+
 		Ok(session)
 	}
 
+	/// Reject an access token until it expires, even if its session still exists.
+	pub fn deny_access_token(claims: &JwtClaims) -> Result<(), Error> {
+		let now = Utc::now().timestamp() as u64;
+
+		let mut denied = DENIED_ACCESS_TOKENS
+			.lock()
+			.map_err(|_| Error::generic_500("Denied access tokens lock poisoned"))?;
+
+		// Expired tokens are rejected anyway
+		denied.retain(|_, exp| *exp > now);
+		denied.insert(claims.jti.to_owned(), claims.exp);
+		Ok(())
+	}
+
+	/// Queue revoking every access token issued for the session so far. Its refresh token stays valid.
+	pub fn queue_revoke_access_tokens(&self, transaction: &mut DBTransaction) -> Result<(), Error> {
+		transaction.update_fields(self, vec![("token_epoch", self.token_epoch + 1)])
+	}
+
 	/// Generate a new refresh token for the session, updating the database and invalidating the previous one.
 	pub async fn rotate_refresh_token(&mut self) -> Result<String, Error> {
-		let refresh_token = Uuid::new_v4().to_raw();
-
-		self.refresh_token_hash = HashedString::new(&refresh_token)?;
-		self.refresh_token_issued_at = Utc::now();
+		let refresh_token = self.replace_refresh_token();
 
 		self.db_update_fields(vec![
 			(
-				"refresh_token_hash",
-				&serde_json::to_value(&self.refresh_token_hash)?,
+				"refresh_token_digest",
+				&serde_json::to_value(&self.refresh_token_digest)?,
+			),
+			(
+				"rotated_refresh_token_digests",
+				&serde_json::to_value(&self.rotated_refresh_token_digests)?,
 			),
 			(
 				"refresh_token_issued_at",
@@ -89,31 +214,52 @@ impl Session {
 		Ok(refresh_token)
 	}
 
+	/// Generate a new refresh token for the session, keeping the digest of the previous one, without
+	/// updating the database.
+	fn replace_refresh_token(&mut self) -> String {
+		let refresh_token = Uuid::new_v4().to_raw();
+		let previous_digest = std::mem::replace(
+			&mut self.refresh_token_digest,
+			TokenDigest::new(&refresh_token),
+		);
+
+		self.rotated_refresh_token_digests
+			.insert(0, previous_digest);
+		self.rotated_refresh_token_digests
+			.truncate(REFRESH_TOKEN_FAMILY_SIZE);
+		self.refresh_token_issued_at = Utc::now();
+		refresh_token
+	}
+
 	/// Generate a new access token for the session.
 	///
 	/// This method does not update the database,
 	/// as the token is a stateless JWT.
 	pub fn generate_access_token(&self) -> Result<String, Error> {
 		let now = chrono::Utc::now().timestamp() as u64;
-		let env = Environment::new();
 
 		let claims = JwtClaims {
 			sub: self.uuid.uuid_string(),
 			exp: now + ACCESS_TOKEN_EXPIRY_SECONDS,
 			iat: now,
 			iss: "kavacoast.com".to_owned(),
-			aud: "kavacoast.com-session".to_owned(),
+			aud: SESSION_AUDIENCE.to_owned(),
+			jti: Uuid::new_v4().to_raw(),
+			epoch: self.token_epoch,
+			scope: self.client.as_ref().map(|_| Scope::join(&self.scopes)),
 		};
 
-		let secret = env.oauth_jwt_secret.val();
-		let encoding_key = jsonwebtoken::EncodingKey::from_secret(secret.as_ref());
+		SigningKeys::get()?.encode(&claims)
+	}
+
+	/// Whether `refresh_token` is one of the session's rotated refresh tokens.
+	pub fn is_rotated_refresh_token(&self, refresh_token: &str) -> bool {
+		self.rotated_refresh_token_digests
+			.contains(&TokenDigest::new(refresh_token))
+	}
 
-		jsonwebtoken::encode(
-			&jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256),
-			&claims,
-			&encoding_key,
-		)
-		.map_err(|e| Error::generic_500(&format!("Error encoding new JWT: {:?}", e)))
+	pub fn user_uuid(&self) -> UUID<User> {
+		self.user.to_owned()
 	}
 
 	pub async fn user(&self) -> Result<User, Error> {
@@ -133,3 +279,92 @@ impl Expirable for Session {
 		REFRESH_TOKEN_EXPIRY_SECONDS
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn session() -> Session {
+		Session::new(&UUID::new(), &ClientInfo::default()).unwrap()
+	}
+
+	#[test]
+	fn rotation_replaces_refresh_token() {
+		let mut session = session();
+		let previous_digest = session.refresh_token_digest.clone();
+
+		let refresh_token = session.replace_refresh_token();
+
+		assert!(session.refresh_token_digest == TokenDigest::new(&refresh_token));
+		assert!(session.rotated_refresh_token_digests == vec![previous_digest]);
+		assert!(!session.is_rotated_refresh_token(&refresh_token));
+	}
+
+	#[test]
+	fn rotated_refresh_tokens_are_detected() {
+		let mut session = session();
+		let first = session.replace_refresh_token();
+		let second = session.replace_refresh_token();
+		let current = session.replace_refresh_token();
+
+		assert!(session.is_rotated_refresh_token(&first));
+		assert!(session.is_rotated_refresh_token(&second));
+		assert!(!session.is_rotated_refresh_token(&current));
+		assert!(!session.is_rotated_refresh_token("unknown"));
+	}
+
+	#[test]
+	fn rotated_refresh_tokens_are_newest_first() {
+		let mut session = session();
+		let first = session.replace_refresh_token();
+		let second = session.replace_refresh_token();
+		session.replace_refresh_token();
+
+		assert!(session.rotated_refresh_token_digests[0] == TokenDigest::new(&second));
+		assert!(session.rotated_refresh_token_digests[1] == TokenDigest::new(&first));
+	}
+
+	#[test]
+	fn rotated_refresh_tokens_are_limited_to_family_size() {
+		let mut session = session();
+		let oldest = session.replace_refresh_token();
+
+		// The last one is current, so the others fill the family
+		let tokens: Vec<String> = (0..=REFRESH_TOKEN_FAMILY_SIZE)
+			.map(|_| session.replace_refresh_token())
+			.collect();
+
+		assert_eq!(
+			session.rotated_refresh_token_digests.len(),
+			REFRESH_TOKEN_FAMILY_SIZE
+		);
+		assert!(!session.is_rotated_refresh_token(&oldest));
+		assert!(tokens[..REFRESH_TOKEN_FAMILY_SIZE]
+			.iter()
+			.all(|token| session.is_rotated_refresh_token(token)));
+	}
+
+	#[test]
+	fn rotation_is_stamped() {
+		let mut session = session();
+		let issued_at = session.refresh_token_issued_at;
+		session.replace_refresh_token();
+		assert!(session.refresh_token_issued_at >= issued_at);
+	}
+
+	#[test]
+	fn new_sessions_have_no_rotated_refresh_tokens() {
+		let session = session();
+		assert!(session.rotated_refresh_token_digests.is_empty());
+		assert!(session.refresh_token_digest != TokenDigest::default());
+	}
+
+	#[test]
+	fn refresh_tokens_are_stored_as_digests() {
+		let mut session = session();
+		let refresh_token = session.replace_refresh_token();
+		let stored = serde_json::to_string(&session).unwrap();
+
+		assert!(!stored.contains(&refresh_token));
+	}
+}
@@ -1,13 +1,21 @@
 use crate::{
-	dbrecord::DBRecord,
+	dbrecord::{DBRecord, DBTransaction, Relation},
+	discord_oauth::DiscordUser,
 	error::Error,
-	generic::{Expirable, HashedString, UUID},
-	models::{registration::Registration, session::Session},
+	generic::{
+		random_alphanumeric_string, ClientInfo, Environment, Expirable, HashedString, TokenDigest,
+		UUID,
+	},
+	models::{
+		passkey::Passkey, pool_player::PoolPlayer, registration::Registration, session::Session,
+	},
 	routes::users::RegistrationRequest,
+	totp,
 };
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use either::Either::{self, Left};
+use clap::ValueEnum;
+use either::Either;
 use rocket::http::Status;
 use serde::{Deserialize, Serialize};
 use strum::{EnumIter, IntoEnumIterator};
@@ -15,6 +23,17 @@ use strum::{EnumIter, IntoEnumIterator};
 const NAME_MIN_LENGTH: usize = 2;
 const NAME_MAX_LENGTH: usize = 32;
 const PASSWORD_MIN_LENGTH: usize = 8;
+const GENERATED_PASSWORD_LENGTH: usize = 32;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+pub const TWO_FACTOR_REQUIRED_ERROR: &str = "Two-factor code required";
+
+/// `referrer->referred->user`
+pub const REFERRED: Relation<User, User> = Relation::new("referred");
+/// `user->created_referral->registration`
+pub const CREATED_REFERRAL: Relation<User, Registration> = Relation::new("created_referral");
+/// `user->plays_as->pool_player`, with at most one pool player per user
+pub const PLAYS_AS: Relation<User, PoolPlayer> = Relation::new("plays_as");
 
 #[derive(Serialize, Deserialize)]
 pub struct User {
@@ -23,12 +42,13 @@ pub struct User {
 	pub display_name: String,
 	pub password_hash: HashedString,
 	pub discord_id: Option<String>,
+	#[serde(default)]
+	pub two_factor: Option<TwoFactor>,
 	pub roles: Vec<Role>,
-	pub referral_registrations: Vec<UUID<Registration>>,
-	pub referred_users: Vec<UUID<User>>,
-	pub referred_by: Option<UUID<User>>,
 	pub created_at: DateTime<Utc>,
 	pub updated_at: DateTime<Utc>,
+	#[serde(default)]
+	pub version: u64,
 }
 
 impl Default for User {
@@ -39,17 +59,37 @@ impl Default for User {
 			display_name: "".to_owned(),
 			password_hash: Default::default(),
 			discord_id: None,
+			two_factor: None,
 			roles: vec![],
-			referral_registrations: vec![],
-			referred_users: vec![],
-			referred_by: None,
 			created_at: Utc::now(),
 			updated_at: Utc::now(),
+			version: 0,
 		}
 	}
 }
 
-#[derive(Serialize, Deserialize, PartialEq, EnumIter)]
+/// TOTP two-factor authentication of a `User`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TwoFactor {
+	/// Base32 TOTP secret
+	secret: String,
+	/// Whether the user confirmed enrollment with a code, which enables it
+	confirmed: bool,
+	/// One-time codes to sign in without the authenticator app
+	recovery_codes: Vec<HashedString>,
+	/// The time step of the last code used, so codes can't be replayed
+	last_used_step: Option<i64>,
+}
+
+/// The public fields of a `User` embedded in other records.
+#[derive(Serialize, Deserialize)]
+pub struct UserSummary {
+	pub uuid: UUID<User>,
+	pub username: String,
+	pub display_name: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, EnumIter, ValueEnum)]
 #[serde(rename_all = "snake_case")]
 pub enum Role {
 	Admin,
@@ -72,13 +112,21 @@ impl DBRecord for User {
 		self.uuid.to_owned()
 	}
 
-	async fn delete_hook(&self) -> Result<(), Error> {
-		for registration_uuid in self.referral_registrations.iter() {
-			let registration = Registration::db_by_id(&registration_uuid.uuid_string()).await?;
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
 
-			if let Some(registration) = registration {
-				registration.db_delete().await?;
-			}
+	async fn delete_hook(&self, transaction: &mut DBTransaction) -> Result<(), Error> {
+		for registration in self.referral_registrations().await? {
+			transaction.delete(&registration).await?;
+		}
+
+		for passkey in self.passkeys().await? {
+			transaction.delete(&passkey).await?;
 		}
 
 		Ok(())
@@ -87,6 +135,18 @@ impl DBRecord for User {
 	fn use_trash() -> bool {
 		true
 	}
+
+	fn relations() -> Vec<&'static str> {
+		vec![REFERRED.name(), CREATED_REFERRAL.name(), PLAYS_AS.name()]
+	}
+
+	fn unique_fields() -> Vec<&'static str> {
+		vec!["username", "discord_id"]
+	}
+
+	fn sensitive_fields() -> Vec<&'static str> {
+		vec!["password_hash", "two_factor"]
+	}
 }
 
 impl User {
@@ -97,44 +157,15 @@ impl User {
 	/// Default values are specified here.
 	pub async fn register(registration_request: &RegistrationRequest) -> Result<Self, Error> {
 		let username = Self::validate_username_requirements(&registration_request.username)?;
+		Self::verify_password_requirements(&registration_request.password)?;
 
-		if User::db_search_one("username", username.clone())
+		let registration = Registration::find_valid(&registration_request.registration_key)
 			.await?
-			.is_some()
-		{
-			return Err(Error::new(
-				Status::BadRequest,
-				"Username unavailable.",
-				None,
-			));
-		};
+			.ok_or_else(|| Error::new(Status::Unauthorized, "Invalid registration key", None))?;
 
-		let registration = Registration::db_search_one(
-			"registration_key",
-			registration_request.registration_key.clone(),
-		)
-		.await?
-		.ok_or_else(|| Error::new(Status::Unauthorized, "Invalid registration key", None))?;
-
-		registration.db_delete().await?;
-
-		Self::verify_password_requirements(&registration_request.password)?;
-
-		let (referred_by, discord_id) = match registration.referrer_or_discord {
-			Either::Right(discord_id) => {
-				if User::db_search_one("discord_id", discord_id.clone())
-					.await?
-					.is_some()
-				{
-					return Err(Error::new(
-						Status::BadRequest,
-						"A user with this Discord ID already exists.", // This shouldn't actually happen, but just in case.
-						None,
-					));
-				};
-				(None, Some(discord_id))
-			}
-			Either::Left(referred_by) => (Some(referred_by), None),
+		let (referrer, discord_id) = match &registration.referrer_or_discord {
+			Either::Right(discord_id) => (None, Some(discord_id.to_owned())),
+			Either::Left(referrer) => (Some(referrer.to_owned()), None),
 		};
 
 		let user = Self {
@@ -147,36 +178,77 @@ impl User {
 			discord_id,
 			created_at: Utc::now(),
 			updated_at: Utc::now(),
-			referred_by: referred_by.clone(),
 			..Default::default()
 		};
 
-		if let Some(referred_by) = referred_by {
-			let mut referred_by_user = User::db_by_id(&referred_by.uuid_string())
-				.await?
-				.ok_or_else(|| {
-					Error::new(
-						Status::InternalServerError,
-						"Referrer not found",
-						Some(&format!(
-							"Referrer not found: {}",
-							referred_by.uuid_string()
-						)),
-					)
-				})?;
-
-			referred_by_user.referred_users.push(user.uuid());
-
-			referred_by_user
-				.db_update_field("referred_users", &referred_by_user.referred_users)
-				.await?;
+		// Username and Discord ID uniqueness is enforced by the database,
+		// so the registration is only consumed if creation succeeds.
+		let mut transaction = DBTransaction::new();
+		transaction.create(&user)?;
+		transaction.delete(&registration).await?;
+
+		if let Some(referrer) = referrer {
+			transaction.relate(&REFERRED, &referrer, &user.uuid)?;
 		}
 
-		user.db_create().await?;
+		transaction.commit().await?;
+		Ok(user)
+	}
+
+	/// Create an account for a Discord user that signed in without one, with `username` or their Discord username.
+	///
+	/// The account has a random password, so it can only sign in with Discord until the password is reset.
+	pub async fn register_discord(
+		discord_user: &DiscordUser,
+		username: Option<&str>,
+	) -> Result<Self, Error> {
+		let username = match username {
+			Some(username) => username.to_owned(),
+			// Discord usernames may also contain periods
+			None => discord_user.username.replace('.', "_"),
+		};
 
+		let username = Self::validate_username_requirements(&username)?;
+
+		let display_name = Self::validate_displayname_requirements(
+			discord_user
+				.global_name
+				.as_deref()
+				.unwrap_or(&discord_user.username),
+		)
+		.unwrap_or(username.to_owned());
+
+		let user = Self {
+			uuid: UUID::new(),
+			username,
+			display_name,
+			password_hash: HashedString::new(&random_alphanumeric_string(
+				GENERATED_PASSWORD_LENGTH,
+			))?,
+			discord_id: Some(discord_user.id.to_owned()),
+			created_at: Utc::now(),
+			updated_at: Utc::now(),
+			..Default::default()
+		};
+
+		// Username and Discord ID uniqueness is enforced by the database
+		user.db_create().await?;
 		Ok(user)
 	}
 
+	/// Link a Discord account to a user that doesn't have one, such as one that registered by referral.
+	pub async fn link_discord(&mut self, discord_id: &str) -> Result<(), Error> {
+		if self.discord_id.is_some() {
+			return Err(Error::conflict("A Discord account is already linked"));
+		}
+
+		// Discord ID uniqueness is enforced by the database
+		self.db_update_field("discord_id", &Some(discord_id))
+			.await?;
+		self.discord_id = Some(discord_id.to_owned());
+		Ok(())
+	}
+
 	pub fn has_role(&self, role: &Role) -> bool {
 		self.roles.contains(role)
 	}
@@ -189,20 +261,157 @@ impl User {
 		}
 	}
 
+	/// Whether the user confirmed two-factor authentication, so signing in requires a code.
+	pub fn has_two_factor(&self) -> bool {
+		self.two_factor.as_ref().is_some_and(|t| t.confirmed)
+	}
+
+	/// Whether the user is an admin and `REQUIRE_ADMIN_TWO_FACTOR` is set to "true".
+	pub fn two_factor_required(&self) -> bool {
+		self.has_role(&Role::Admin)
+			&& Environment::new()
+				.require_admin_two_factor
+				.val_opt()
+				.as_deref() == Some("true")
+	}
+
+	/// Whether the user has admin privileges: they're an admin, and have two-factor authentication if it's
+	/// required for admins.
+	///
+	/// Every admin privilege check should use this rather than the role, so the requirement can't be skipped.
+	pub fn is_effective_admin(&self) -> bool {
+		self.has_role(&Role::Admin) && (self.has_two_factor() || !self.two_factor_required())
+	}
+
+	/// Start enrolling in two-factor authentication, returning the new secret and its provisioning URI.
+	///
+	/// Replaces an unconfirmed enrollment. Returns a 409 `Error` if it's already enabled.
+	pub async fn start_two_factor(&mut self) -> Result<(String, String), Error> {
+		if self.has_two_factor() {
+			return Err(Error::conflict(
+				"Two-factor authentication is already enabled",
+			));
+		}
+
+		let secret = totp::generate_secret()?;
+		let provisioning_uri = totp::provisioning_uri(&secret, &self.username)?;
+
+		let two_factor = Some(TwoFactor {
+			secret: secret.to_owned(),
+			confirmed: false,
+			recovery_codes: vec![],
+			last_used_step: None,
+		});
+
+		self.db_update_field("two_factor", &two_factor).await?;
+		self.two_factor = two_factor;
+		Ok((secret, provisioning_uri))
+	}
+
+	/// Enable two-factor authentication with a `code` from the authenticator app, returning new recovery codes.
+	pub async fn confirm_two_factor(&mut self, code: &str) -> Result<Vec<String>, Error> {
+		let mut two_factor = match &self.two_factor {
+			Some(two_factor) if !two_factor.confirmed => two_factor.to_owned(),
+			Some(_) => {
+				return Err(Error::conflict(
+					"Two-factor authentication is already enabled",
+				))
+			}
+			None => return Err(Error::not_found("Two-factor enrollment not started")),
+		};
+
+		let step = totp::verify(&two_factor.secret, code, None)?
+			.ok_or_else(|| Error::new(Status::Unauthorized, "Invalid code", None))?;
+
+		let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+			.map(|_| random_alphanumeric_string(RECOVERY_CODE_LENGTH))
+			.collect();
+
+		two_factor.confirmed = true;
+		two_factor.last_used_step = Some(step);
+		two_factor.recovery_codes = recovery_codes
+			.iter()
+			.map(|code| HashedString::new(code))
+			.collect::<Result<_, _>>()?;
+
+		let two_factor = Some(two_factor);
+		self.db_update_field("two_factor", &two_factor).await?;
+		self.two_factor = two_factor;
+		Ok(recovery_codes)
+	}
+
+	pub async fn disable_two_factor(&mut self) -> Result<(), Error> {
+		self.db_update_field("two_factor", &None::<TwoFactor>)
+			.await?;
+		self.two_factor = None;
+		Ok(())
+	}
+
+	/// Verify a code from the authenticator app, or a recovery code, using it up.
+	///
+	/// Returns a 401 `Error` if it doesn't match, or the user doesn't have two-factor authentication.
+	pub async fn verify_second_factor(&mut self, code: &str) -> Result<(), Error> {
+		let mut two_factor = match &self.two_factor {
+			Some(two_factor) if two_factor.confirmed => two_factor.to_owned(),
+			_ => return Err(Error::generic_401()),
+		};
+
+		if let Some(step) = totp::verify(&two_factor.secret, code, two_factor.last_used_step)? {
+			two_factor.last_used_step = Some(step);
+		} else {
+			let mut used = None;
+
+			for (i, hash) in two_factor.recovery_codes.iter().enumerate() {
+				if hash.verify(code.trim())? {
+					used = Some(i);
+					break;
+				}
+			}
+
+			let used = used.ok_or(Error::generic_401())?;
+			two_factor.recovery_codes.remove(used);
+		}
+
+		let two_factor = Some(two_factor);
+		self.db_update_field("two_factor", &two_factor).await?;
+		self.two_factor = two_factor;
+		Ok(())
+	}
+
+	/// Find the session that `refresh_token` was issued for.
+	///
+	/// If `refresh_token` was already rotated, it was stolen from the client or reused by the attacker who stole it,
+	/// so the session is revoked and a 401 `Error` is returned.
 	pub async fn get_session_from_refresh_token(
 		&self,
 		refresh_token: &str,
+		client: &ClientInfo,
 	) -> Result<Option<Session>, Error> {
-		let sessions: Vec<Session> = Session::db_search("user", self.uuid.clone()).await?;
-
-		for session in sessions {
-			if session.refresh_token_hash.verify(refresh_token)? {
-				if !session.is_expired()? {
-					return Ok(Some(session));
-				} else {
-					session.db_delete().await?;
-					continue;
-				}
+		let session =
+			Session::db_search_one("refresh_token_digest", TokenDigest::new(refresh_token))
+				.await?
+				.filter(|session| session.user_uuid() == self.uuid);
+
+		if let Some(session) = session {
+			if session.is_expired()? {
+				session.db_delete().await?;
+				return Ok(None);
+			}
+
+			return Ok(Some(session));
+		}
+
+		for session in self.sessions().await? {
+			if session.is_rotated_refresh_token(refresh_token) {
+				log::warn!(
+					"Security event: reuse of a rotated refresh token for user {} (session {}) from IP {}, revoking the session",
+					self.username,
+					session.uuid.uuid_string(),
+					client.ip.as_deref().unwrap_or("unknown")
+				);
+
+				session.db_delete().await?;
+				return Err(Error::generic_401());
 			}
 		}
 
@@ -248,7 +457,7 @@ impl User {
 		Ok(())
 	}
 
-	fn verify_password_requirements(password: &str) -> Result<(), Error> {
+	pub fn verify_password_requirements(password: &str) -> Result<(), Error> {
 		if password.len() < PASSWORD_MIN_LENGTH {
 			return Err(Error::new(
 				Status::BadRequest,
@@ -264,38 +473,122 @@ impl User {
 	}
 
 	/// Verify password requirements, update the password, and persist it to the database.
-	pub async fn set_password(&mut self, password: &str) -> Result<(), Error> {
-		Self::verify_password_requirements(password)?;
-		self.password_hash = HashedString::new(password)?;
+	///
+	/// Every session of the user except `keep_session` is revoked.
+	pub async fn set_password(
+		&mut self,
+		password: &str,
+		keep_session: Option<&UUID<Session>>,
+	) -> Result<(), Error> {
+		let mut transaction = DBTransaction::new();
+
+		let password_hash = self
+			.queue_password_change(&mut transaction, password, keep_session)
+			.await?;
+
+		transaction.update_fields(self, vec![("password_hash", &password_hash)])?;
+		transaction.commit().await?;
 
-		self.db_update_field("password_hash", &self.password_hash)
+		self.set_version(self.version + 1);
+		self.password_hash = password_hash;
+		Ok(())
+	}
+
+	/// Verify password requirements and hash `password`, for the caller to queue as the new `password_hash`
+	/// in `transaction`.
+	///
+	/// Revoking every session of the user except `keep_session` is queued in `transaction`.
+	pub async fn queue_password_change(
+		&self,
+		transaction: &mut DBTransaction,
+		password: &str,
+		keep_session: Option<&UUID<Session>>,
+	) -> Result<HashedString, Error> {
+		Self::verify_password_requirements(password)?;
+		let password_hash = HashedString::new(password)?;
+		self.queue_revoke_sessions(transaction, keep_session)
 			.await?;
+		Ok(password_hash)
+	}
+
+	/// Remove a role from the user, revoking the access tokens of every session so it takes effect immediately.
+	pub async fn remove_role(&mut self, role: &Role) -> Result<(), Error> {
+		let roles: Vec<Role> = self.roles.iter().filter(|r| *r != role).cloned().collect();
+		let mut transaction = DBTransaction::new();
+		transaction.update_fields(self, vec![("roles", &roles)])?;
+
+		for session in self.sessions().await? {
+			session.queue_revoke_access_tokens(&mut transaction)?;
+		}
 
+		transaction.commit().await?;
+		self.set_version(self.version + 1);
+		self.roles = roles;
 		Ok(())
 	}
 
-	/// Get referral registrations, removing any that don't exist from the user.
-	pub async fn get_referral_registrations(&self) -> Result<Vec<Registration>, Error> {
-		let mut referrals = vec![];
+	pub fn summary(&self) -> UserSummary {
+		UserSummary {
+			uuid: self.uuid(),
+			username: self.username.to_owned(),
+			display_name: self.display_name.to_owned(),
+		}
+	}
 
-		for referral in &self.referral_registrations {
-			if let Some(registration) = Registration::db_by_id(&referral.uuid_string()).await? {
-				if let Left(referred_by) = &registration.referrer_or_discord {
-					if referred_by == &self.uuid {
-						referrals.push(registration);
-					}
-				}
+	/// Get every session of the user, including expired ones that haven't been cleared yet.
+	pub async fn sessions(&self) -> Result<Vec<Session>, Error> {
+		Session::db_search("user", self.uuid.clone()).await
+	}
+
+	pub async fn passkeys(&self) -> Result<Vec<Passkey>, Error> {
+		Passkey::db_search("user", self.uuid.clone()).await
+	}
+
+	/// Delete every session of the user except `keep_session`, returning how many were deleted.
+	pub async fn revoke_sessions(
+		&self,
+		keep_session: Option<&UUID<Session>>,
+	) -> Result<usize, Error> {
+		let mut transaction = DBTransaction::new();
+		let count = self
+			.queue_revoke_sessions(&mut transaction, keep_session)
+			.await?;
+		transaction.commit().await?;
+		Ok(count)
+	}
+
+	async fn queue_revoke_sessions(
+		&self,
+		transaction: &mut DBTransaction,
+		keep_session: Option<&UUID<Session>>,
+	) -> Result<usize, Error> {
+		let mut count = 0;
+
+		for session in self.sessions().await? {
+			if Some(&session.uuid) != keep_session {
+				transaction.delete(&session).await?;
+				count += 1;
 			}
 		}
 
-		if referrals.len() != self.referral_registrations.len() {
-			let referral_ids: Vec<UUID<Registration>> =
-				referrals.iter().map(|r| r.uuid()).collect();
+		Ok(count)
+	}
+
+	/// Get the unexpired registrations this user created to refer other users.
+	pub async fn referral_registrations(&self) -> Result<Vec<Registration>, Error> {
+		let mut registrations = vec![];
 
-			self.db_update_field("referral_registrations", &referral_ids)
-				.await?;
+		for registration in self.uuid.related(&CREATED_REFERRAL).await? {
+			if !registration.is_expired()? {
+				registrations.push(registration);
+			}
 		}
 
-		Ok(referrals)
+		Ok(registrations)
+	}
+
+	/// Get the pool player linked to this user, if any.
+	pub async fn pool_player(&self) -> Result<Option<PoolPlayer>, Error> {
+		Ok(self.uuid.related(&PLAYS_AS).await?.into_iter().next())
 	}
 }
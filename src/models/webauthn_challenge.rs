@@ -0,0 +1,106 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::Error,
+	generic::{Expirable, UUID},
+	models::user::User,
+	webauthn,
+};
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+
+const WEBAUTHN_CHALLENGE_EXPIRY_SECONDS: u64 = 60 * 5; // 5 minutes
+
+/// A random challenge for a passkey ceremony that was started but not yet completed.
+///
+/// The authenticator signs it, so a response can't be replayed.
+#[derive(Serialize, Deserialize)]
+pub struct WebAuthnChallenge {
+	uuid: UUID<WebAuthnChallenge>,
+	created_at: DateTime<Utc>,
+	updated_at: DateTime<Utc>,
+	challenge: String,
+	/// The signed in user registering a passkey, or `None` to sign in with one
+	user: Option<UUID<User>>,
+	#[serde(default)]
+	version: u64,
+}
+
+impl DBRecord for WebAuthnChallenge {
+	fn table() -> &'static str {
+		"webauthn_challenges"
+	}
+
+	fn uuid(&self) -> UUID<Self> {
+		self.uuid.to_owned()
+	}
+
+	fn version(&self) -> u64 {
+		self.version
+	}
+
+	fn set_version(&mut self, version: u64) {
+		self.version = version;
+	}
+
+	fn unique_fields() -> Vec<&'static str> {
+		vec!["challenge"]
+	}
+
+	fn sensitive_fields() -> Vec<&'static str> {
+		vec!["challenge"]
+	}
+}
+
+impl WebAuthnChallenge {
+	/// Create a new challenge, without persisting it to the database.
+	pub fn new(user: Option<UUID<User>>) -> Result<Self, Error> {
+		Ok(Self {
+			uuid: UUID::new(),
+			created_at: Utc::now(),
+			updated_at: Utc::now(),
+			challenge: webauthn::generate_challenge()?,
+			user,
+			version: 0,
+		})
+	}
+
+	pub fn challenge(&self) -> &str {
+		&self.challenge
+	}
+
+	/// Complete the ceremony with `challenge`, deleting it so it can't be used again.
+	///
+	/// Returns a 401 `Error` if it doesn't exist, expired, or wasn't started by `user`.
+	pub async fn consume(challenge: &str, user: Option<&UUID<User>>) -> Result<Self, Error> {
+		let invalid = || {
+			Error::new(
+				Status::Unauthorized,
+				"Invalid or expired passkey challenge",
+				None,
+			)
+		};
+
+		let record = Self::db_search_one("challenge", challenge.trim_end_matches('=').to_owned())
+			.await?
+			.ok_or_else(invalid)?;
+
+		record.db_delete().await?;
+
+		if record.is_expired()? || record.user.as_ref() != user {
+			return Err(invalid());
+		}
+
+		Ok(record)
+	}
+}
+
+impl Expirable for WebAuthnChallenge {
+	fn start_time_field() -> &'static str {
+		"created_at"
+	}
+
+	fn expiry_seconds() -> u64 {
+		WEBAUTHN_CHALLENGE_EXPIRY_SECONDS
+	}
+}
@@ -0,0 +1,136 @@
+//! Delivery of messages to users outside the site, such as password reset links.
+//!
+//! Each `Notifier` reaches some users. Messages go through the first one that reaches the user.
+
+use crate::{
+	error::Error,
+	generic::Environment,
+	models::user::{User, UserSummary},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+const DISCORD_API_URL: &str = "https://discord.com/api/v10";
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+	/// Whether the notifier can deliver messages to `user`.
+	fn reaches(&self, user: &User) -> bool;
+
+	async fn send(&self, user: &User, message: &str) -> Result<(), Error>;
+}
+
+/// Sends direct messages from the bot to users with a linked Discord account.
+pub struct DiscordNotifier;
+
+/// Posts messages to `NOTIFICATION_WEBHOOK_URL`, for a service that delivers them another way, such as email.
+///
+/// The body is JSON with `user` (`uuid`, `username` and `display_name`) and `message`.
+pub struct WebhookNotifier {
+	url: String,
+}
+
+#[derive(Serialize)]
+struct WebhookBody<'a> {
+	user: UserSummary,
+	message: &'a str,
+}
+
+/// The configured notifiers, in order of preference.
+pub fn notifiers() -> Vec<Box<dyn Notifier>> {
+	let mut notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(DiscordNotifier)];
+
+	if let Some(url) = Environment::new().notification_webhook_url.val_opt() {
+		notifiers.push(Box::new(WebhookNotifier { url }));
+	}
+
+	notifiers
+}
+
+/// Send `message` to `user` with the first notifier that reaches them.
+///
+/// Returns `false` if none of them do.
+pub async fn notify(user: &User, message: &str) -> Result<bool, Error> {
+	for notifier in notifiers() {
+		if notifier.reaches(user) {
+			notifier.send(user, message).await?;
+			return Ok(true);
+		}
+	}
+
+	Ok(false)
+}
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+	fn reaches(&self, user: &User) -> bool {
+		user.discord_id.is_some()
+	}
+
+	async fn send(&self, user: &User, message: &str) -> Result<(), Error> {
+		let discord_id = user
+			.discord_id
+			.as_ref()
+			.ok_or_else(|| Error::generic_500("User has no Discord account"))?;
+
+		let client = reqwest::Client::new();
+		let authorization = format!("Bot {}", Environment::new().bot_token.val());
+		let error =
+			|e: reqwest::Error| Error::generic_500(&format!("Error sending Discord DM: {}", e));
+
+		#[derive(Deserialize)]
+		struct Channel {
+			id: String,
+		}
+
+		let channel: Channel = client
+			.post(format!("{}/users/@me/channels", DISCORD_API_URL))
+			.header("Authorization", &authorization)
+			.json(&serde_json::json!({ "recipient_id": discord_id }))
+			.send()
+			.await
+			.and_then(|res| res.error_for_status())
+			.map_err(error)?
+			.json()
+			.await
+			.map_err(error)?;
+
+		client
+			.post(format!(
+				"{}/channels/{}/messages",
+				DISCORD_API_URL, channel.id
+			))
+			.header("Authorization", &authorization)
+			.json(&serde_json::json!({ "content": message }))
+			.send()
+			.await
+			.and_then(|res| res.error_for_status())
+			.map_err(error)?;
+
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+	fn reaches(&self, _user: &User) -> bool {
+		true
+	}
+
+	async fn send(&self, user: &User, message: &str) -> Result<(), Error> {
+		reqwest::Client::new()
+			.post(&self.url)
+			.json(&WebhookBody {
+				user: user.summary(),
+				message,
+			})
+			.send()
+			.await
+			.and_then(|res| res.error_for_status())
+			.map_err(|e| {
+				Error::generic_500(&format!("Error sending notification webhook: {}", e))
+			})?;
+
+		Ok(())
+	}
+}
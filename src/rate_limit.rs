@@ -0,0 +1,430 @@
+//! Rate limiting and brute-force lockout of authentication endpoints.
+//!
+//! Attempts are counted in a sliding window per client IP and endpoint, and per username and endpoint.
+//! Usernames with repeated failed sign-ins are also locked out, for twice as long after each further failure.
+//!
+//! Counts are kept in memory, so each instance limits the attempts it receives and they reset on restart.
+
+use crate::{
+	error::Error,
+	generic::{ClientInfo, Environment},
+	models::user::TWO_FACTOR_REQUIRED_ERROR,
+};
+use rocket::{
+	http::Status,
+	route::{Handler, Outcome},
+	Data, Request, Route,
+};
+use std::{
+	cell::Cell,
+	collections::{HashMap, VecDeque},
+	sync::{LazyLock, Mutex, MutexGuard, PoisonError},
+	time::{Duration, Instant},
+};
+
+const DEFAULT_RATE_LIMIT_REQUESTS: usize = 10;
+const DEFAULT_RATE_LIMIT_WINDOW_SECONDS: u64 = 60;
+const DEFAULT_LOCKOUT_THRESHOLD: u32 = 5;
+const DEFAULT_LOCKOUT_SECONDS: u64 = 30;
+/// Lockouts stop doubling at this length, and failures older than it are forgotten
+const MAX_LOCKOUT_SECONDS: u64 = 60 * 60; // 1 hour
+
+/// Parsed once, as the environment doesn't change while the server runs
+static LIMITS: LazyLock<Result<Limits, Error>> = LazyLock::new(Limits::from_env);
+static ATTEMPTS: LazyLock<Mutex<Attempts>> = LazyLock::new(Default::default);
+static FAILED_LOGINS: LazyLock<Mutex<HashMap<String, FailedLogins>>> =
+	LazyLock::new(Default::default);
+
+tokio::task_local! {
+	/// How long the client of the current request must wait before retrying, if it was rate limited
+	static RETRY_AFTER: Cell<Option<u64>>;
+}
+
+#[derive(Default)]
+struct Attempts {
+	/// Times of the attempts in the current window, oldest first, by key
+	by_key: HashMap<String, VecDeque<Instant>>,
+	last_pruned: Option<Instant>,
+}
+
+/// Consecutive failed sign-ins of a username.
+struct FailedLogins {
+	count: u32,
+	last_failed_at: Instant,
+	locked_until: Option<Instant>,
+}
+
+/// The configured limits, from `RATE_LIMIT_REQUESTS`, `RATE_LIMIT_WINDOW_SECONDS`,
+/// `LOCKOUT_THRESHOLD` and `LOCKOUT_SECONDS`.
+struct Limits {
+	requests: usize,
+	window: Duration,
+	lockout_threshold: u32,
+	lockout: Duration,
+}
+
+impl Limits {
+	fn from_env() -> Result<Self, Error> {
+		let env = Environment::new();
+
+		Ok(Self {
+			requests: parse_opt(env.rate_limit_requests.val_opt(), "RATE_LIMIT_REQUESTS")?
+				.unwrap_or(DEFAULT_RATE_LIMIT_REQUESTS),
+			window: Duration::from_secs(
+				parse_opt(
+					env.rate_limit_window_seconds.val_opt(),
+					"RATE_LIMIT_WINDOW_SECONDS",
+				)?
+				.unwrap_or(DEFAULT_RATE_LIMIT_WINDOW_SECONDS),
+			),
+			lockout_threshold: parse_opt(env.lockout_threshold.val_opt(), "LOCKOUT_THRESHOLD")?
+				.unwrap_or(DEFAULT_LOCKOUT_THRESHOLD),
+			lockout: Duration::from_secs(
+				parse_opt(env.lockout_seconds.val_opt(), "LOCKOUT_SECONDS")?
+					.unwrap_or(DEFAULT_LOCKOUT_SECONDS),
+			),
+		})
+	}
+}
+
+/// Get the configured limits, or a 500 `Error` if they're invalid.
+fn limits() -> Result<&'static Limits, Error> {
+	LIMITS.as_ref().map_err(Error::clone)
+}
+
+/// Lock `mutex`, returning a 500 `Error` if a thread panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, Error> {
+	mutex
+		.lock()
+		.map_err(|e| Error::generic_500(&format!("Rate limit state is poisoned: {}", e)))
+}
+
+fn parse_opt<T: std::str::FromStr>(value: Option<String>, name: &str) -> Result<Option<T>, Error>
+where
+	T::Err: std::fmt::Display,
+{
+	value
+		.map(|value| value.parse())
+		.transpose()
+		.map_err(|e| Error::generic_500(&format!("Invalid {}: {}", name, e)))
+}
+
+/// Count an attempt by the client at `endpoint`.
+///
+/// Returns a 429 `Error` if the client made too many attempts there in the current window.
+/// Clients without a known IP aren't limited.
+pub fn check_ip(endpoint: &str, client: &ClientInfo) -> Result<(), Error> {
+	match &client.ip {
+		Some(ip) => check(&format!("ip:{}:{}", endpoint, ip)),
+		None => Ok(()),
+	}
+}
+
+/// Count an attempt to sign in as `username`.
+///
+/// Returns a 429 `Error` if the username is locked out, or had too many attempts in the current window.
+pub fn check_login(username: &str) -> Result<(), Error> {
+	let username = username.to_lowercase();
+
+	if let Some(locked_until) = lock(&FAILED_LOGINS)?
+		.get(&username)
+		.and_then(|failed| failed.locked_until)
+	{
+		let now = Instant::now();
+
+		if locked_until > now {
+			return Err(too_many_requests(locked_until - now));
+		}
+	}
+
+	check_username("token", &username)
+}
+
+/// Count an attempt at `endpoint` for `username`, such as requesting a password reset for it.
+///
+/// Returns a 429 `Error` if there were too many for the username there in the current window.
+pub fn check_username(endpoint: &str, username: &str) -> Result<(), Error> {
+	check(&format!(
+		"username:{}:{}",
+		endpoint,
+		username.to_lowercase()
+	))
+}
+
+/// Record a failed sign-in as `username`, locking it out once it reaches the threshold.
+pub fn login_failed(username: &str) -> Result<(), Error> {
+	let limits = limits()?;
+	let now = Instant::now();
+	let max_lockout = Duration::from_secs(MAX_LOCKOUT_SECONDS);
+	let mut failed_logins = lock(&FAILED_LOGINS)?;
+
+	failed_logins.retain(|_, failed| now - failed.last_failed_at < max_lockout);
+
+	let failed = failed_logins
+		.entry(username.to_lowercase())
+		.or_insert(FailedLogins {
+			count: 0,
+			last_failed_at: now,
+			locked_until: None,
+		});
+
+	failed.count += 1;
+	failed.last_failed_at = now;
+
+	if failed.count >= limits.lockout_threshold {
+		let doublings = (failed.count - limits.lockout_threshold).min(16);
+		let lockout = (limits.lockout * 2u32.pow(doublings)).min(max_lockout);
+		failed.locked_until = Some(now + lockout);
+
+		log::warn!(
+			"Security event: {} locked out for {}s after {} failed sign-ins",
+			username,
+			lockout.as_secs(),
+			failed.count
+		);
+	}
+
+	Ok(())
+}
+
+/// Record a failed sign-in as `username` if `result` is a 401 `Error`, then return `result`.
+///
+/// Asking for the two-factor code isn't a failure.
+pub fn count_failed_login<T>(username: &str, result: Result<T, Error>) -> Result<T, Error> {
+	if let Err(e) = &result {
+		if e.status() == Status::Unauthorized && e.public_desc() != TWO_FACTOR_REQUIRED_ERROR {
+			login_failed(username)?;
+		}
+	}
+
+	result
+}
+
+/// Clear the failed sign-ins of `username` after it signs in.
+pub fn login_succeeded(username: &str) {
+	// The failures are removed either way, so a panic while they were locked doesn't matter
+	FAILED_LOGINS
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.remove(&username.to_lowercase());
+}
+
+fn check(key: &str) -> Result<(), Error> {
+	let limits = limits()?;
+	let now = Instant::now();
+	let mut attempts = lock(&ATTEMPTS)?;
+
+	// Forget keys without recent attempts, at most once per window
+	if attempts
+		.last_pruned
+		.is_none_or(|last_pruned| now - last_pruned >= limits.window)
+	{
+		attempts
+			.by_key
+			.retain(|_, times| times.back().is_some_and(|last| now - *last < limits.window));
+
+		attempts.last_pruned = Some(now);
+	}
+
+	let times = attempts.by_key.entry(key.to_owned()).or_default();
+
+	while times
+		.front()
+		.is_some_and(|first| now - *first >= limits.window)
+	{
+		times.pop_front();
+	}
+
+	if times.len() >= limits.requests {
+		let retry_after = times
+			.front()
+			.map(|first| *first + limits.window - now)
+			.unwrap_or(limits.window);
+
+		return Err(too_many_requests(retry_after));
+	}
+
+	times.push_back(now);
+	Ok(())
+}
+
+/// Create a 429 (Too Many Requests) error, and send `retry_after` in the `Retry-After` header of the response.
+fn too_many_requests(retry_after: Duration) -> Error {
+	// Round up, so retrying right on time isn't limited again
+	let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+	let _ = RETRY_AFTER.try_with(|cell| cell.set(Some(seconds)));
+
+	Error::new(
+		Status::TooManyRequests,
+		"Too many attempts. Try again later.",
+		None,
+	)
+}
+
+/// A route handler wrapper that adds the `Retry-After` header to rate limited responses.
+#[derive(Clone)]
+struct RetryAfterScope(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for RetryAfterScope {
+	async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+		RETRY_AFTER
+			.scope(Cell::new(None), async {
+				let mut outcome = self.0.handle(request, data).await;
+
+				if let (Outcome::Success(response), Some(seconds)) =
+					(&mut outcome, RETRY_AFTER.with(|cell| cell.get()))
+				{
+					response.set_raw_header("Retry-After", seconds.to_string());
+				}
+
+				outcome
+			})
+			.await
+	}
+}
+
+/// Wrap the handlers of `routes` so that rate limited responses tell the client when to retry.
+pub fn scoped(routes: Vec<Route>) -> Vec<Route> {
+	routes
+		.into_iter()
+		.map(|mut route| {
+			route.handler = Box::new(RetryAfterScope(route.handler));
+			route
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// How long `username` is locked out for, from its last failure
+	fn lockout(username: &str) -> Option<Duration> {
+		let failed_logins = FAILED_LOGINS.lock().unwrap();
+		let failed = failed_logins.get(username)?;
+
+		failed
+			.locked_until
+			.map(|locked_until| locked_until - failed.last_failed_at)
+	}
+
+	fn fail(username: &str, times: u32) {
+		for _ in 0..times {
+			login_failed(username).unwrap();
+		}
+	}
+
+	#[test]
+	fn check_limits_attempts_per_window() {
+		let limits = limits().unwrap();
+
+		for _ in 0..limits.requests {
+			check("test:check_limits_attempts_per_window").unwrap();
+		}
+
+		let error = check("test:check_limits_attempts_per_window").unwrap_err();
+		assert_eq!(error.status(), Status::TooManyRequests);
+
+		// Other keys have their own window
+		check("test:check_limits_attempts_per_window:other").unwrap();
+	}
+
+	#[test]
+	fn check_ip_ignores_clients_without_ip() {
+		let limits = limits().unwrap();
+
+		for _ in 0..=limits.requests {
+			check_ip("test_without_ip", &ClientInfo::default()).unwrap();
+		}
+	}
+
+	#[test]
+	fn lockout_starts_at_threshold() {
+		let limits = limits().unwrap();
+		fail("lockout_starts_at_threshold", limits.lockout_threshold - 1);
+		assert_eq!(lockout("lockout_starts_at_threshold"), None);
+
+		fail("lockout_starts_at_threshold", 1);
+		assert_eq!(lockout("lockout_starts_at_threshold"), Some(limits.lockout));
+
+		let error = check_login("lockout_starts_at_threshold").unwrap_err();
+		assert_eq!(error.status(), Status::TooManyRequests);
+	}
+
+	#[test]
+	fn lockout_doubles_after_each_further_failure() {
+		let limits = limits().unwrap();
+		fail("lockout_doubles", limits.lockout_threshold);
+
+		fail("lockout_doubles", 1);
+		assert_eq!(lockout("lockout_doubles"), Some(limits.lockout * 2));
+
+		fail("lockout_doubles", 1);
+		assert_eq!(lockout("lockout_doubles"), Some(limits.lockout * 4));
+	}
+
+	#[test]
+	fn lockout_is_capped() {
+		let limits = limits().unwrap();
+		fail("lockout_is_capped", limits.lockout_threshold + 20);
+
+		assert_eq!(
+			lockout("lockout_is_capped"),
+			Some(Duration::from_secs(MAX_LOCKOUT_SECONDS))
+		);
+	}
+
+	#[test]
+	fn usernames_are_case_insensitive() {
+		let limits = limits().unwrap();
+		fail("Case_Insensitive", limits.lockout_threshold);
+		assert!(check_login("case_insensitive").is_err());
+	}
+
+	#[test]
+	fn login_succeeded_clears_failures() {
+		let limits = limits().unwrap();
+		fail("login_succeeded_clears", limits.lockout_threshold);
+		login_succeeded("Login_Succeeded_Clears");
+
+		assert!(!FAILED_LOGINS
+			.lock()
+			.unwrap()
+			.contains_key("login_succeeded_clears"));
+		check_login("login_succeeded_clears").unwrap();
+	}
+
+	#[test]
+	fn count_failed_login_counts_unauthorized_errors() {
+		let result: Result<(), Error> = Err(Error::generic_401());
+		assert!(count_failed_login("count_unauthorized", result).is_err());
+		assert_eq!(FAILED_LOGINS.lock().unwrap()["count_unauthorized"].count, 1);
+	}
+
+	#[test]
+	fn count_failed_login_ignores_other_results() {
+		let two_factor_required: Result<(), Error> = Err(Error::new(
+			Status::Unauthorized,
+			TWO_FACTOR_REQUIRED_ERROR,
+			None,
+		));
+		let bad_request: Result<(), Error> =
+			Err(Error::new(Status::BadRequest, "Bad request", None));
+
+		assert!(count_failed_login("count_ignored", two_factor_required).is_err());
+		assert!(count_failed_login("count_ignored", bad_request).is_err());
+		assert!(count_failed_login("count_ignored", Ok(())).is_ok());
+		assert!(!FAILED_LOGINS.lock().unwrap().contains_key("count_ignored"));
+	}
+
+	#[test]
+	fn retry_after_rounds_up() {
+		let retry_after = RETRY_AFTER.sync_scope(Cell::new(None), || {
+			too_many_requests(Duration::from_millis(1500));
+			RETRY_AFTER.with(|cell| cell.get())
+		});
+
+		assert_eq!(retry_after, Some(2));
+	}
+}
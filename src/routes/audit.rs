@@ -0,0 +1,68 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::{Error, ErrorResponse},
+	generic::{BearerToken, UUID},
+	models::{
+		audit_entry::{AuditEntry, AuditFilter, AuditOperation},
+		user::User,
+	},
+	routes::users::require_admin,
+};
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, response::status, serde::json::Json};
+use surrealdb::sql::Thing;
+
+fn parse_time(name: &str, value: Option<&str>) -> Result<Option<DateTime<Utc>>, Error> {
+	value
+		.map(|value| {
+			DateTime::parse_from_rfc3339(value)
+				.map(|time| time.with_timezone(&Utc))
+				.map_err(|_| {
+					Error::new(
+						Status::BadRequest,
+						&format!("Invalid {}: expected an RFC 3339 timestamp", name),
+						None,
+					)
+				})
+		})
+		.transpose()
+}
+
+/// Get audit log entries, most recent first. Admins only.
+///
+/// `actor` is a user ID, `table` and `record` identify the mutated record,
+/// and `since` and `until` are RFC 3339 timestamps.
+#[rocket::get("/api/audit?<actor>&<table>&<record>&<operation>&<since>&<until>&<limit>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn get_audit_entries(
+	actor: Option<&str>,
+	table: Option<&str>,
+	record: Option<&str>,
+	operation: Option<&str>,
+	since: Option<&str>,
+	until: Option<&str>,
+	limit: Option<u32>,
+	bearer_token: BearerToken,
+) -> Result<Json<Vec<AuditEntry>>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+
+	let operation = operation
+		.map(|operation| {
+			serde_json::from_value::<AuditOperation>(operation.into())
+				.map_err(|_| Error::new(Status::BadRequest, "Invalid operation", None))
+		})
+		.transpose()?;
+
+	let filter = AuditFilter {
+		actor: actor.map(|id| UUID::from(Thing::from((User::table(), id)))),
+		table: table.map(|table| table.to_owned()),
+		record: record.map(|record| record.to_owned()),
+		operation,
+		since: parse_time("since", since)?,
+		until: parse_time("until", until)?,
+		limit,
+	};
+
+	Ok(Json(AuditEntry::search(&filter).await?))
+}
@@ -1,8 +1,8 @@
 use crate::{
-	dbrecord::DBRecord,
 	error::{Error, ErrorResponse},
-	generic::GenericOkResponse,
+	generic::{ClientInfo, GenericOkResponse},
 	models::registration::Registration,
+	rate_limit,
 };
 use rocket::{
 	http::Status,
@@ -18,8 +18,11 @@ pub struct CheckRegistrationKeyRequest {
 #[rocket::post("/api/check_registration_key", format = "json", data = "<request>")]
 pub async fn check_registration_key(
 	request: Json<CheckRegistrationKeyRequest>,
+	client: ClientInfo,
 ) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
-	Registration::db_search_one("registration_key", request.registration_key.clone())
+	rate_limit::check_ip("check_registration_key", &client)?;
+
+	Registration::find_valid(&request.registration_key)
 		.await?
 		.ok_or_else(|| Error::new(Status::Unauthorized, "Invalid registration key", None))?;
 
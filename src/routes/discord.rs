@@ -0,0 +1,222 @@
+use crate::{
+	dbrecord::DBRecord,
+	discord_oauth::DiscordOAuth,
+	error::{Error, ErrorResponse},
+	generic::{BearerToken, ClientInfo, Expirable, GenericOkResponse},
+	models::{
+		discord_login::DiscordLogin,
+		session::Session,
+		user::{User, TWO_FACTOR_REQUIRED_ERROR},
+	},
+	rate_limit,
+	routes::{token::TokenResponse, users::get_user},
+};
+use rocket::{
+	http::{Cookie, CookieJar, SameSite, Status},
+	response::status,
+	serde::json::Json,
+	time::Duration,
+};
+use serde::{Deserialize, Serialize};
+
+/// Holds the `state` of the Discord login started by the browser, so it can't be completed in another one
+const STATE_COOKIE: &str = "discord_state";
+/// Holds the `state` of the pending login of a user with two-factor authentication, until they enter a code
+const PENDING_COOKIE: &str = "discord_pending";
+
+#[derive(Serialize)]
+pub struct DiscordAuthorizeResponse {
+	/// Where to send the user to authorize with Discord
+	authorize_url: String,
+}
+
+#[derive(Deserialize)]
+pub struct DiscordCallbackRequest {
+	/// From the query of the redirect from Discord
+	code: String,
+	/// From the query of the redirect from Discord
+	state: String,
+	/// The username of a new account, defaults to the Discord username
+	username: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DiscordTwoFactorRequest {
+	/// A code from the authenticator app or a recovery code
+	otp: String,
+}
+
+#[derive(Serialize)]
+pub struct DiscordLoginResponse {
+	#[serde(flatten)]
+	tokens: TokenResponse,
+	username: String,
+	/// Whether an account was created for the Discord user
+	created: bool,
+}
+
+impl DiscordLoginResponse {
+	/// Sign in as `user` with a new session.
+	async fn new(user: User, created: bool, client: &ClientInfo) -> Result<Self, Error> {
+		let mut session = Session::new(&user.uuid(), client)?;
+		session.db_create().await?;
+
+		Ok(Self {
+			tokens: TokenResponse::generate(&mut session).await?,
+			username: user.username,
+			created,
+		})
+	}
+}
+
+/// Start signing in with Discord, or linking Discord to the signed in user with `link=true`.
+#[rocket::get("/api/auth/discord?<link>")]
+pub async fn discord_authorize(
+	link: Option<bool>,
+	bearer_token: BearerToken,
+	client: ClientInfo,
+	cookies: &CookieJar<'_>,
+) -> Result<Json<DiscordAuthorizeResponse>, status::Custom<Json<ErrorResponse>>> {
+	rate_limit::check_ip("discord_authorize", &client)?;
+	let oauth = DiscordOAuth::from_env()?;
+
+	let link_user = if link.unwrap_or(false) {
+		Some(bearer_token.validate().await?.user_uuid())
+	} else {
+		None
+	};
+
+	let login = DiscordLogin::new(link_user);
+	login.db_create().await?;
+
+	cookies.add(
+		Cookie::build((STATE_COOKIE, login.state().to_owned()))
+			.path("/api")
+			.http_only(true)
+			.secure(true)
+			.same_site(SameSite::Lax)
+			.max_age(Duration::seconds(DiscordLogin::expiry_seconds() as i64)),
+	);
+
+	Ok(Json(DiscordAuthorizeResponse {
+		authorize_url: oauth.authorize_url(login.state())?,
+	}))
+}
+
+/// Sign in with Discord, creating an account if no user has linked the Discord account.
+///
+/// Users with two-factor authentication get a 401 `Error` asking for a code,
+/// which they send to `/api/auth/discord/two_factor` to complete signing in.
+#[rocket::post("/api/auth/discord", format = "json", data = "<request>")]
+pub async fn discord_login(
+	request: Json<DiscordCallbackRequest>,
+	client: ClientInfo,
+	cookies: &CookieJar<'_>,
+) -> Result<Json<DiscordLoginResponse>, status::Custom<Json<ErrorResponse>>> {
+	let oauth = DiscordOAuth::from_env()?;
+	check_state_cookie(&request.state, cookies)?;
+	DiscordLogin::complete(&request.state, None).await?;
+	let discord_user = oauth.identify(&request.code).await?;
+
+	let (user, created) =
+		match User::db_search_one("discord_id", discord_user.id.to_owned()).await? {
+			Some(user) => (user, false),
+			None => (
+				User::register_discord(&discord_user, request.username.as_deref()).await?,
+				true,
+			),
+		};
+
+	// The state and the code from Discord can't be used again, so the two-factor code completes a pending login
+	if user.has_two_factor() {
+		let pending = DiscordLogin::pending(user.uuid());
+		pending.db_create().await?;
+
+		cookies.add(
+			Cookie::build((PENDING_COOKIE, pending.state().to_owned()))
+				.path("/api")
+				.http_only(true)
+				.secure(true)
+				.same_site(SameSite::Strict)
+				.max_age(Duration::seconds(DiscordLogin::expiry_seconds() as i64)),
+		);
+
+		return Err(Error::new(Status::Unauthorized, TWO_FACTOR_REQUIRED_ERROR, None).into());
+	}
+
+	Ok(Json(
+		DiscordLoginResponse::new(user, created, &client).await?,
+	))
+}
+
+/// Complete signing in with Discord as a user with two-factor authentication, with their code.
+///
+/// A wrong code can be retried until the pending login expires, subject to the sign-in lockout.
+#[rocket::post("/api/auth/discord/two_factor", format = "json", data = "<request>")]
+pub async fn discord_two_factor(
+	request: Json<DiscordTwoFactorRequest>,
+	client: ClientInfo,
+	cookies: &CookieJar<'_>,
+) -> Result<Json<DiscordLoginResponse>, status::Custom<Json<ErrorResponse>>> {
+	rate_limit::check_ip("discord_two_factor", &client)?;
+
+	let state = cookies
+		.get(PENDING_COOKIE)
+		.map(|cookie| cookie.value().to_owned())
+		.ok_or(Error::generic_401())?;
+
+	let (login, user) = DiscordLogin::find_pending(&state).await?;
+	let mut user = user.object_opt().await?.ok_or(Error::generic_401())?;
+
+	rate_limit::check_login(&user.username)?;
+	let verified = user.verify_second_factor(&request.otp).await;
+	rate_limit::count_failed_login(&user.username, verified)?;
+	rate_limit::login_succeeded(&user.username);
+
+	login.db_delete().await?;
+	cookies.remove(Cookie::build(PENDING_COOKIE).path("/api"));
+
+	// Accounts created by signing in with Discord don't have two-factor authentication yet
+	Ok(Json(DiscordLoginResponse::new(user, false, &client).await?))
+}
+
+/// Link a Discord account to a user, who must have started linking with `/api/auth/discord?link=true`.
+#[rocket::post("/api/users/<id>/discord", format = "json", data = "<request>")]
+pub async fn link_discord(
+	id: &str,
+	request: Json<DiscordCallbackRequest>,
+	bearer_token: BearerToken,
+	cookies: &CookieJar<'_>,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	let oauth = DiscordOAuth::from_env()?;
+	let session = bearer_token.validate().await?;
+	let mut user = get_user(id, session).await?;
+	check_state_cookie(&request.state, cookies)?;
+
+	DiscordLogin::complete(&request.state, Some(&user.uuid())).await?;
+	let discord_user = oauth.identify(&request.code).await?;
+
+	user.link_discord(&discord_user.id).await?;
+	Ok(Json(GenericOkResponse::new()))
+}
+
+/// Check that `state` is the one `discord_authorize` gave this browser, and remove the cookie holding it.
+///
+/// Otherwise, someone could start a login, then send the redirect from Discord to a victim to complete it,
+/// signing them in as the attacker or linking the attacker's Discord account.
+fn check_state_cookie(state: &str, cookies: &CookieJar<'_>) -> Result<(), Error> {
+	let cookie_state = cookies
+		.get(STATE_COOKIE)
+		.map(|cookie| cookie.value().to_owned());
+	cookies.remove(Cookie::build(STATE_COOKIE).path("/api"));
+
+	if cookie_state.as_deref() != Some(state) {
+		return Err(Error::new(
+			Status::Unauthorized,
+			"Discord login was started in another browser",
+			None,
+		));
+	}
+
+	Ok(())
+}
@@ -0,0 +1,120 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::{Error, ErrorResponse},
+	generic::BearerToken,
+	jobs::Job,
+	models::{job_run::JobOutcome, job_state::JobState},
+	routes::users::require_admin,
+};
+use chrono::{DateTime, Utc};
+use rocket::{response::status, serde::json::Json};
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct JobStatus {
+	name: String,
+	/// Cron expression, in UTC
+	schedule: String,
+	paused: bool,
+	next_run_at: Option<DateTime<Utc>>,
+	running_since: Option<DateTime<Utc>>,
+	last_started_at: Option<DateTime<Utc>>,
+	last_finished_at: Option<DateTime<Utc>>,
+	last_outcome: Option<JobOutcome>,
+	last_error: Option<String>,
+}
+
+impl JobStatus {
+	async fn of(job: &Job) -> Result<Self, Error> {
+		let state = JobState::load(job.name()).await?;
+
+		Ok(Self {
+			name: state.name,
+			schedule: job.schedule().to_owned(),
+			paused: state.paused,
+			next_run_at: state.next_run_at,
+			running_since: state.running_since,
+			last_started_at: state.last_started_at,
+			last_finished_at: state.last_finished_at,
+			last_outcome: state.last_outcome,
+			last_error: state.last_error,
+		})
+	}
+}
+
+fn find_job(name: &str) -> Result<Job, Error> {
+	Job::find(name).ok_or_else(|| Error::not_found("Job not found"))
+}
+
+/// Get the status of every job. Admins only.
+#[rocket::get("/api/admin/jobs")]
+pub async fn get_jobs(
+	bearer_token: BearerToken,
+) -> Result<Json<Vec<JobStatus>>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+
+	let mut statuses = vec![];
+
+	for job in Job::active_jobs() {
+		statuses.push(JobStatus::of(&job).await?);
+	}
+
+	Ok(Json(statuses))
+}
+
+/// Run a job now, even if it is paused, and wait for it to finish. Admins only.
+///
+/// Returns a 409 if the job is already running.
+#[rocket::post("/api/admin/jobs/<name>/run")]
+pub async fn run_job(
+	name: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<JobStatus>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+
+	let job = find_job(name)?;
+
+	if !job.run().await? {
+		return Err(Error::conflict("Job is already running").into());
+	}
+
+	Ok(Json(JobStatus::of(&job).await?))
+}
+
+/// Skip a job's scheduled runs until it is resumed. Admins only.
+#[rocket::post("/api/admin/jobs/<name>/pause")]
+pub async fn pause_job(
+	name: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<JobStatus>, status::Custom<Json<ErrorResponse>>> {
+	set_paused(name, true, bearer_token).await
+}
+
+/// Resume a paused job's scheduled runs. Admins only.
+#[rocket::post("/api/admin/jobs/<name>/resume")]
+pub async fn resume_job(
+	name: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<JobStatus>, status::Custom<Json<ErrorResponse>>> {
+	set_paused(name, false, bearer_token).await
+}
+
+async fn set_paused(
+	name: &str,
+	paused: bool,
+	bearer_token: BearerToken,
+) -> Result<Json<JobStatus>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+
+	let job = find_job(name)?;
+	let mut state = JobState::load(job.name()).await?;
+
+	if state.paused != paused {
+		state.db_update_field("paused", &paused).await?;
+	}
+
+	Ok(Json(JobStatus::of(&job).await?))
+}
@@ -0,0 +1,11 @@
+use crate::{error::ErrorResponse, signing_keys::SigningKeys};
+use jsonwebtoken::jwk::JwkSet;
+use rocket::{get, response::status, serde::json::Json};
+
+#[get("/.well-known/jwks.json")]
+/// The public keys that verify access tokens
+///
+/// [JSON Web Key Set](https://datatracker.ietf.org/doc/html/rfc7517#section-5)
+pub async fn jwks() -> Result<Json<JwkSet>, status::Custom<Json<ErrorResponse>>> {
+	Ok(Json(SigningKeys::get()?.jwk_set()))
+}
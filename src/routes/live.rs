@@ -0,0 +1,104 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::ErrorResponse,
+	generic::{BearerToken, JwtClaims},
+	models::{pool_game::PoolGame, pool_player::PoolPlayer, session::Session},
+	routes::pool_player::require_pool_host,
+};
+use chrono::Utc;
+use rocket::{
+	futures::StreamExt,
+	response::{
+		status,
+		stream::{Event, EventStream},
+	},
+	serde::json::Json,
+	Shutdown,
+};
+use serde::Serialize;
+use std::time::Duration;
+use surrealdb::{Action, Notification, Value};
+use tokio::time::Instant;
+
+/// How often a stream rechecks that its session may still read it
+const LIVE_RECHECK_SECONDS: u64 = 60;
+
+#[derive(Serialize)]
+pub struct LiveUpdate {
+	table: &'static str,
+	/// "create", "update" or "delete"
+	action: &'static str,
+	/// The record after the change, or before it if deleted
+	record: serde_json::Value,
+}
+
+impl LiveUpdate {
+	fn new(table: &'static str, notification: Notification<Value>) -> Self {
+		let action = match notification.action {
+			Action::Create => "create",
+			Action::Update => "update",
+			Action::Delete => "delete",
+			_ => "unknown",
+		};
+
+		Self {
+			table,
+			action,
+			record: notification.data.into_inner().into_json(),
+		}
+	}
+}
+
+/// Stream changes to pool games and pool players as server-sent `update` events containing a `LiveUpdate`.
+///
+/// Intended to be read with `fetch()`, as `EventSource` can't send the Authorization header.
+///
+/// The stream ends when the access token expires, or when a recheck finds that the session was revoked
+/// or lost the pool host role. Clients reconnect with a refreshed token.
+#[rocket::get("/api/live/pool")]
+pub async fn live_pool(
+	bearer_token: BearerToken,
+	mut shutdown: Shutdown,
+) -> Result<EventStream![], status::Custom<Json<ErrorResponse>>> {
+	let (session, claims) = bearer_token.validate_with_claims().await?;
+	require_pool_host(&session).await?;
+
+	let mut games = PoolGame::db_live().await?;
+	let mut players = PoolPlayer::db_live().await?;
+
+	let expires_in = claims.exp.saturating_sub(Utc::now().timestamp() as u64);
+	let expiry = tokio::time::sleep(Duration::from_secs(expires_in));
+	let recheck_period = Duration::from_secs(LIVE_RECHECK_SECONDS);
+	let mut recheck = tokio::time::interval_at(Instant::now() + recheck_period, recheck_period);
+
+	Ok(EventStream! {
+		tokio::pin!(expiry);
+
+		loop {
+			let update = tokio::select! {
+				Some(notification) = games.next() => LiveUpdate::new(PoolGame::table(), notification),
+				Some(notification) = players.next() => LiveUpdate::new(PoolPlayer::table(), notification),
+				_ = recheck.tick() => {
+					if is_still_pool_host(&claims).await {
+						continue;
+					}
+
+					break;
+				}
+				_ = &mut expiry => break,
+				_ = &mut shutdown => break,
+				else => break,
+			};
+
+			yield Event::json(&update).event("update");
+		}
+	})
+}
+
+/// Whether the session of `claims` still exists, hasn't revoked the token, and its user is still a pool host.
+async fn is_still_pool_host(claims: &JwtClaims) -> bool {
+	match Session::from_claims(claims).await {
+		Ok(session) => require_pool_host(&session).await.is_ok(),
+		Err(_) => false,
+	}
+}
@@ -0,0 +1,18 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::ErrorResponse,
+	generic::{BearerToken, GenericOkResponse},
+	models::session::Session,
+};
+use rocket::{post, response::status, serde::json::Json};
+
+#[post("/api/auth/logout")]
+/// Sign out, deleting the session and rejecting its access token immediately
+pub async fn logout(
+	bearer_token: BearerToken,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	session.db_delete().await?;
+	Session::deny_access_token(&bearer_token.claims()?)?;
+	Ok(Json(GenericOkResponse::new()))
+}
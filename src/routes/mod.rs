@@ -1,7 +1,19 @@
+pub mod audit;
 pub mod check_registration_key;
 pub mod check_token;
+pub mod discord;
+pub mod jobs;
+pub mod jwks;
+pub mod live;
+pub mod logout;
+pub mod oauth;
+pub mod oauth_clients;
 pub mod pages;
+pub mod passkeys;
+pub mod password_reset;
 pub mod pool_game;
 pub mod pool_player;
 pub mod token;
+pub mod trash;
+pub mod two_factor;
 pub mod users;
@@ -0,0 +1,190 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::{Error, ErrorResponse},
+	generic::BearerToken,
+	models::{
+		authorization_code::AuthorizationCode,
+		oauth_client::{OAuthClient, Scope},
+		user::UserSummary,
+	},
+};
+use rocket::{form::FromForm, http::Status, response::status, serde::json::Json};
+use serde::{Deserialize, Serialize};
+
+#[derive(FromForm, Deserialize)]
+/// [Authorization Request](https://datatracker.ietf.org/doc/html/rfc6749#section-4.1.1)
+pub struct AuthorizationRequest {
+	/// "code"
+	response_type: String,
+	/// OAuthClient.uuid
+	client_id: String,
+	/// Must be registered for the client
+	redirect_uri: String,
+	/// Space-separated scopes, defaults to every scope of the client
+	scope: Option<String>,
+	/// Returned to the client unchanged
+	state: Option<String>,
+	/// Required for public clients
+	code_challenge: Option<String>,
+	/// "S256"
+	code_challenge_method: Option<String>,
+}
+
+impl AuthorizationRequest {
+	/// Validate the request against its client, returning the client and the requested scopes.
+	async fn validate(&self) -> Result<(OAuthClient, Vec<Scope>), Error> {
+		let client = OAuthClient::db_by_id(&self.client_id)
+			.await?
+			.ok_or_else(|| Error::not_found("Client not found"))?;
+
+		if !client.redirect_uris.contains(&self.redirect_uri) {
+			return Err(Error::new(
+				Status::BadRequest,
+				"Redirect URI not registered for this client",
+				None,
+			));
+		}
+
+		if self.response_type != "code" {
+			return Err(Error::new(
+				Status::BadRequest,
+				"Unsupported response type",
+				None,
+			));
+		}
+
+		match (&self.code_challenge, self.code_challenge_method.as_deref()) {
+			(Some(_), Some("S256")) => {}
+			(Some(_), _) => {
+				return Err(Error::new(
+					Status::BadRequest,
+					"Only the S256 code challenge method is supported",
+					None,
+				))
+			}
+			(None, _) if !client.is_confidential() => {
+				return Err(Error::new(
+					Status::BadRequest,
+					"Public clients must use PKCE",
+					None,
+				))
+			}
+			(None, _) => {}
+		}
+
+		let scopes = client.requested_scopes(self.scope.as_deref())?;
+		Ok((client, scopes))
+	}
+
+	/// The redirect URI with `params` and the request's `state` added to its query.
+	fn redirect_to(&self, params: &[(&str, &str)]) -> Result<String, Error> {
+		let mut url = reqwest::Url::parse(&self.redirect_uri)
+			.map_err(|e| Error::generic_500(&format!("Invalid redirect URI: {}", e)))?;
+
+		{
+			let mut query = url.query_pairs_mut();
+			query.extend_pairs(params);
+
+			if let Some(state) = &self.state {
+				query.append_pair("state", state);
+			}
+		}
+
+		Ok(url.to_string())
+	}
+}
+
+#[derive(Serialize)]
+/// What the consent screen shows the user before they authorize a client
+pub struct ConsentRequest {
+	client_id: String,
+	client_name: String,
+	scopes: Vec<ScopeInfo>,
+}
+
+#[derive(Serialize)]
+pub struct ScopeInfo {
+	scope: Scope,
+	description: &'static str,
+}
+
+#[derive(Deserialize)]
+pub struct ConsentDecision {
+	#[serde(flatten)]
+	request: AuthorizationRequest,
+	/// Whether the user authorized the client
+	approve: bool,
+}
+
+#[derive(Serialize)]
+pub struct AuthorizationResponse {
+	/// Where to send the user, with the authorization code or error
+	redirect_to: String,
+}
+
+/// Validate an authorization request and describe it for the consent screen.
+#[rocket::get("/api/oauth/authorize?<request..>")]
+pub async fn get_authorization(
+	request: AuthorizationRequest,
+	bearer_token: BearerToken,
+) -> Result<Json<ConsentRequest>, status::Custom<Json<ErrorResponse>>> {
+	bearer_token.validate().await?;
+	let (client, scopes) = request.validate().await?;
+
+	Ok(Json(ConsentRequest {
+		client_id: client.uuid.uuid_string(),
+		client_name: client.name,
+		scopes: scopes
+			.into_iter()
+			.map(|scope| ScopeInfo {
+				description: scope.description(),
+				scope,
+			})
+			.collect(),
+	}))
+}
+
+/// Authorize a client, or deny it, as decided by the user on the consent screen.
+#[rocket::post("/api/oauth/authorize", format = "json", data = "<decision>")]
+pub async fn authorize(
+	decision: Json<ConsentDecision>,
+	bearer_token: BearerToken,
+) -> Result<Json<AuthorizationResponse>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	let request = &decision.request;
+	let (client, scopes) = request.validate().await?;
+
+	if !decision.approve {
+		return Ok(Json(AuthorizationResponse {
+			redirect_to: request.redirect_to(&[("error", "access_denied")])?,
+		}));
+	}
+
+	let authorization = AuthorizationCode::new(
+		&client,
+		&session.user_uuid(),
+		&request.redirect_uri,
+		scopes,
+		request.code_challenge.to_owned(),
+	);
+
+	authorization.db_create().await?;
+
+	Ok(Json(AuthorizationResponse {
+		redirect_to: request.redirect_to(&[("code", authorization.code())])?,
+	}))
+}
+
+/// Get the profile of the user that authorized the client.
+#[rocket::get("/api/oauth/userinfo")]
+pub async fn userinfo(
+	bearer_token: BearerToken,
+) -> Result<Json<UserSummary>, status::Custom<Json<ErrorResponse>>> {
+	let authorization = bearer_token.authorize(&Scope::Profile).await?;
+
+	let session = authorization
+		.session()
+		.ok_or_else(|| Error::new(Status::Forbidden, "Token isn't for a user", None))?;
+
+	Ok(Json(session.user().await?.summary()))
+}
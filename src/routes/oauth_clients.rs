@@ -0,0 +1,79 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::{Error, ErrorResponse},
+	generic::{BearerToken, GenericOkResponse},
+	models::oauth_client::{OAuthClient, Scope},
+	routes::users::require_admin,
+};
+use rocket::{response::status, serde::json::Json};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct CreateOAuthClientRequest {
+	name: String,
+	redirect_uris: Vec<String>,
+	scopes: Vec<Scope>,
+	/// Whether the client can keep a secret, e.g. a server rather than a browser or desktop app
+	confidential: bool,
+}
+
+#[derive(Serialize)]
+pub struct CreatedOAuthClient {
+	#[serde(flatten)]
+	client: OAuthClient,
+	/// Only returned once, for confidential clients
+	client_secret: Option<String>,
+}
+
+/// Register an OAuth client. Admins only.
+#[rocket::post("/api/admin/oauth_clients", format = "json", data = "<request>")]
+pub async fn create_oauth_client(
+	request: Json<CreateOAuthClientRequest>,
+	bearer_token: BearerToken,
+) -> Result<Json<CreatedOAuthClient>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+
+	let request = request.into_inner();
+
+	let (client, client_secret) = OAuthClient::new(
+		&request.name,
+		request.redirect_uris,
+		request.scopes,
+		request.confidential,
+	)?;
+
+	client.db_create().await?;
+
+	Ok(Json(CreatedOAuthClient {
+		client,
+		client_secret,
+	}))
+}
+
+/// Get every OAuth client. Admins only.
+#[rocket::get("/api/admin/oauth_clients")]
+pub async fn get_oauth_clients(
+	bearer_token: BearerToken,
+) -> Result<Json<Vec<OAuthClient>>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+	Ok(Json(OAuthClient::db_all().await?))
+}
+
+/// Delete an OAuth client, signing out every user that authorized it. Admins only.
+#[rocket::delete("/api/admin/oauth_clients/<id>")]
+pub async fn delete_oauth_client(
+	id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+
+	let client = OAuthClient::db_by_id(id)
+		.await?
+		.ok_or_else(|| Error::not_found("Client not found"))?;
+
+	client.db_delete().await?;
+	Ok(Json(GenericOkResponse::new()))
+}
@@ -18,7 +18,7 @@ pub async fn admin(
 	let session = bearer_token.validate().await?;
 	let user = session.user().await?;
 
-	if !user.has_role(&Role::Admin) {
+	if !user.is_effective_admin() {
 		return Err(Error::forbidden().into());
 	}
 
@@ -1,9 +1,4 @@
-use crate::{
-	dbrecord::DBRecord,
-	error::ErrorResponse,
-	generic::BearerToken,
-	models::{pool_player::PoolPlayer, user::Role},
-};
+use crate::{error::ErrorResponse, generic::BearerToken, models::user::Role};
 use rocket::{response::status, serde::json::Json};
 use serde::Serialize;
 
@@ -20,12 +15,11 @@ pub async fn dashboard(
 ) -> Result<Json<DashboardResponse>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
 	let user = session.user().await?;
+	let is_pool_player = user.pool_player().await?.is_some();
 
 	Ok(Json(DashboardResponse {
 		display_name: user.display_name.to_owned(),
 		roles: user.roles,
-		is_pool_player: PoolPlayer::db_search_one("user", user.uuid.clone())
-			.await?
-			.is_some(),
+		is_pool_player,
 	}))
 }
@@ -1,5 +1,4 @@
 use crate::{
-	dbrecord::DBRecord,
 	error::{Error, ErrorResponse},
 	generic::BearerToken,
 	models::pool_player::PoolPlayer,
@@ -19,9 +18,7 @@ pub async fn pool(
 	let session = bearer_token.validate().await?;
 	let user = session.user().await?;
 
-	let player = PoolPlayer::db_search_one("user", user.uuid.clone())
-		.await?
-		.ok_or_else(Error::forbidden)?;
+	let player = user.pool_player().await?.ok_or_else(Error::forbidden)?;
 
 	Ok(Json(PoolResponse { player }))
 }
@@ -2,7 +2,11 @@ use crate::{
 	dbrecord::DBRecord,
 	error::ErrorResponse,
 	generic::BearerToken,
-	models::{pool_game::PoolGame, pool_player::PoolPlayer, user::User},
+	models::{
+		pool_game::{ExpandedPoolGame, PoolGame},
+		pool_player::{PoolPlayer, PoolPlayerInfo},
+		user::User,
+	},
 	routes::pool_player::require_pool_host,
 };
 use rocket::{response::status, serde::json::Json};
@@ -11,8 +15,9 @@ use serde::Serialize;
 #[derive(Serialize)]
 pub struct PoolHostPageResponse {
 	users: Vec<PoolHostPageUser>,
-	pool_players: Vec<PoolPlayer>,
-	games: Vec<PoolGame>,
+	/// With the users linked to them
+	pool_players: Vec<PoolPlayerInfo>,
+	games: Vec<ExpandedPoolGame>,
 }
 
 #[derive(Serialize)]
@@ -41,7 +46,7 @@ pub async fn pool_host(
 
 	Ok(Json(PoolHostPageResponse {
 		users,
-		pool_players: PoolPlayer::db_all().await?,
-		games: PoolGame::db_all().await?,
+		pool_players: PoolPlayer::infos(PoolPlayer::db_all().await?).await?,
+		games: PoolGame::all_expanded().await?,
 	}))
 }
@@ -1,6 +1,7 @@
 use crate::{
+	dbrecord::DBRecord,
 	error::ErrorResponse,
-	generic::{get_discord_username, BearerToken},
+	generic::{get_discord_username, BearerToken, Versioned},
 };
 use rocket::{response::status, serde::json::Json};
 use serde::Serialize;
@@ -14,15 +15,16 @@ pub struct SettingsPageResponse {
 	referrals: Vec<String>,
 }
 
+/// The settings of the signed in user, with its version in the `ETag` header for updating them.
 #[rocket::get("/api/page/settings")]
 pub async fn settings(
 	bearer_token: BearerToken,
-) -> Result<Json<SettingsPageResponse>, status::Custom<Json<ErrorResponse>>> {
+) -> Result<Versioned<SettingsPageResponse>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
 	let user = session.user().await?;
 
 	let referrals = user
-		.get_referral_registrations()
+		.referral_registrations()
 		.await?
 		.iter()
 		.map(|r| r.registration_key.to_owned())
@@ -34,11 +36,16 @@ pub async fn settings(
 		None
 	};
 
-	Ok(Json(SettingsPageResponse {
-		username: user.username.to_owned(),
-		display_name: user.display_name.to_owned(),
-		discord_username,
-		discord_id: user.discord_id,
-		referrals,
-	}))
+	let version = user.version();
+
+	Ok(Versioned::new(
+		SettingsPageResponse {
+			username: user.username.to_owned(),
+			display_name: user.display_name.to_owned(),
+			discord_username,
+			discord_id: user.discord_id,
+			referrals,
+		},
+		version,
+	))
 }
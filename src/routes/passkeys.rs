@@ -0,0 +1,190 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::{Error, ErrorResponse},
+	generic::{BearerToken, ClientInfo, GenericOkResponse},
+	models::{
+		passkey::{Passkey, PasskeyInfo},
+		user::TWO_FACTOR_REQUIRED_ERROR,
+		webauthn_challenge::WebAuthnChallenge,
+	},
+	rate_limit,
+	routes::users::{get_own_user, get_user},
+	webauthn::{self, RelyingParty},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rocket::{http::Status, response::status, serde::json::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// How long the browser waits for the user to complete a ceremony, in milliseconds
+const CEREMONY_TIMEOUT_MS: u64 = 60 * 1000;
+
+#[derive(Serialize)]
+pub struct PasskeyOptions {
+	/// The challenge to send back when completing the ceremony
+	challenge: String,
+	/// `PublicKeyCredentialCreationOptions` or `PublicKeyCredentialRequestOptions`,
+	/// with binary fields as base64url
+	public_key: Value,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterPasskeyRequest {
+	/// From `PasskeyOptions`
+	challenge: String,
+	/// Chosen by the user to tell their passkeys apart
+	name: String,
+	/// Base64url `response.clientDataJSON` of the new credential
+	client_data_json: String,
+	/// Base64url `response.attestationObject` of the new credential
+	attestation_object: String,
+	/// The user's password, required unless they have two-factor authentication
+	password: Option<String>,
+	/// A code from the authenticator app, required if the user has two-factor authentication
+	otp: Option<String>,
+}
+
+/// Start registering a passkey for the signed in user.
+#[rocket::post("/api/users/<id>/passkeys/options")]
+pub async fn passkey_registration_options(
+	id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<PasskeyOptions>, status::Custom<Json<ErrorResponse>>> {
+	let user = get_own_user(id, &bearer_token).await?;
+	let challenge = WebAuthnChallenge::new(Some(user.uuid.to_owned()))?;
+	challenge.db_create().await?;
+
+	let rp = RelyingParty::from_env();
+
+	// Authenticators that already have a passkey for the user don't create another
+	let exclude_credentials: Vec<Value> = user
+		.passkeys()
+		.await?
+		.iter()
+		.map(|passkey| json!({ "type": "public-key", "id": passkey.credential_id }))
+		.collect();
+
+	Ok(Json(PasskeyOptions {
+		challenge: challenge.challenge().to_owned(),
+		public_key: json!({
+			"challenge": challenge.challenge(),
+			"rp": { "id": rp.id, "name": webauthn::RP_NAME },
+			"user": {
+				"id": URL_SAFE_NO_PAD.encode(user.uuid.uuid_string()),
+				"name": user.username,
+				"displayName": user.display_name,
+			},
+			"pubKeyCredParams": [
+				{ "type": "public-key", "alg": webauthn::ES256 },
+				{ "type": "public-key", "alg": webauthn::EDDSA },
+				{ "type": "public-key", "alg": webauthn::RS256 },
+			],
+			"timeout": CEREMONY_TIMEOUT_MS,
+			"attestation": "none",
+			"excludeCredentials": exclude_credentials,
+			"authenticatorSelection": {
+				"residentKey": "required",
+				"userVerification": "required",
+			},
+		}),
+	}))
+}
+
+/// Complete registering a passkey for the signed in user.
+///
+/// A passkey signs in without the password or second factor, so the user confirms their password,
+/// or a two-factor code if they have two-factor authentication, which a stolen access token can't.
+#[rocket::post("/api/users/<id>/passkeys", format = "json", data = "<request>")]
+pub async fn register_passkey(
+	id: &str,
+	request: Json<RegisterPasskeyRequest>,
+	bearer_token: BearerToken,
+) -> Result<Json<PasskeyInfo>, status::Custom<Json<ErrorResponse>>> {
+	let mut user = get_own_user(id, &bearer_token).await?;
+	rate_limit::check_login(&user.username)?;
+
+	let verified = if user.has_two_factor() {
+		let otp = request.otp.as_ref().ok_or(Error::new(
+			Status::Unauthorized,
+			TWO_FACTOR_REQUIRED_ERROR,
+			None,
+		))?;
+
+		user.verify_second_factor(otp).await
+	} else {
+		let password = request.password.as_ref().ok_or(Error::new(
+			Status::BadRequest,
+			"Missing password",
+			None,
+		))?;
+
+		user.verify_password(password)
+	};
+
+	rate_limit::count_failed_login(&user.username, verified)?;
+
+	let challenge = WebAuthnChallenge::consume(&request.challenge, Some(&user.uuid)).await?;
+
+	let credential = RelyingParty::from_env().verify_registration(
+		challenge.challenge(),
+		&request.client_data_json,
+		&request.attestation_object,
+	)?;
+
+	// Credential ID uniqueness is enforced by the database
+	let passkey = Passkey::new(&user.uuid, &request.name, credential)?;
+	passkey.db_create().await?;
+	Ok(Json(passkey.info()))
+}
+
+#[rocket::get("/api/users/<id>/passkeys")]
+pub async fn get_passkeys(
+	id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<Vec<PasskeyInfo>>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	let user = get_user(id, session).await?;
+	let passkeys = user.passkeys().await?;
+	Ok(Json(passkeys.iter().map(Passkey::info).collect()))
+}
+
+#[rocket::delete("/api/users/<id>/passkeys/<passkey_id>")]
+pub async fn delete_passkey(
+	id: &str,
+	passkey_id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	let user = get_user(id, session).await?;
+
+	let passkey = Passkey::db_by_id(passkey_id)
+		.await?
+		.filter(|passkey| passkey.user == user.uuid)
+		.ok_or(Error::not_found("Passkey not found"))?;
+
+	passkey.db_delete().await?;
+	Ok(Json(GenericOkResponse::new()))
+}
+
+/// Start signing in with a passkey. The authenticator chooses which of its passkeys to use.
+///
+/// Complete it with the "passkey" grant of `/api/auth/token`.
+#[rocket::post("/api/auth/passkey/options")]
+pub async fn passkey_login_options(
+	client: ClientInfo,
+) -> Result<Json<PasskeyOptions>, status::Custom<Json<ErrorResponse>>> {
+	rate_limit::check_ip("passkey", &client)?;
+	let challenge = WebAuthnChallenge::new(None)?;
+	challenge.db_create().await?;
+
+	Ok(Json(PasskeyOptions {
+		challenge: challenge.challenge().to_owned(),
+		public_key: json!({
+			"challenge": challenge.challenge(),
+			"rpId": RelyingParty::from_env().id,
+			"timeout": CEREMONY_TIMEOUT_MS,
+			"allowCredentials": [],
+			"userVerification": "required",
+		}),
+	}))
+}
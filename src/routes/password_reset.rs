@@ -0,0 +1,80 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::{Error, ErrorResponse},
+	generic::{ClientInfo, GenericOkResponse},
+	models::{password_reset::PasswordReset, user::User},
+	notifier, rate_limit,
+};
+use rocket::{response::status, serde::json::Json};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+	username: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+	/// From the password reset link
+	token: String,
+	new_password: String,
+}
+
+/// Send a password reset link to the user, through Discord or another notifier that reaches them.
+///
+/// Succeeds even if the user doesn't exist or can't be reached, so it can't be used to find usernames.
+/// The link is sent in the background, so the response time doesn't tell either.
+#[rocket::post("/api/auth/forgot_password", format = "json", data = "<request>")]
+pub async fn forgot_password(
+	request: Json<ForgotPasswordRequest>,
+	client: ClientInfo,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	rate_limit::check_ip("forgot_password", &client)?;
+	rate_limit::check_username("forgot_password", &request.username)?;
+
+	let username = request.into_inner().username;
+
+	tokio::spawn(async move {
+		// Not reported to the client, which could tell that the user exists
+		if let Err(e) = send_reset_link(&username).await {
+			log::error!("Error sending password reset link: {}", e);
+		}
+	});
+
+	Ok(Json(GenericOkResponse::new()))
+}
+
+/// Create a password reset for the user with `username` and send them the link, if they exist and can be reached.
+async fn send_reset_link(username: &str) -> Result<(), Error> {
+	let Some(user) = User::db_search_one("username", username.to_owned()).await? else {
+		return Ok(());
+	};
+
+	if notifier::notifiers()
+		.iter()
+		.any(|notifier| notifier.reaches(&user))
+	{
+		let token = PasswordReset::create(&user).await?;
+		notifier::notify(&user, &PasswordReset::message(&token, &user.username)).await?;
+	}
+
+	Ok(())
+}
+
+/// Choose a new password with a password reset link, signing out every session.
+#[rocket::post("/api/auth/reset_password", format = "json", data = "<request>")]
+pub async fn reset_password(
+	request: Json<ResetPasswordRequest>,
+	client: ClientInfo,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	rate_limit::check_ip("reset_password", &client)?;
+
+	// Checked first, so a rejected password doesn't use up the link
+	User::verify_password_requirements(&request.new_password)?;
+
+	let mut user = PasswordReset::redeem(&request.token).await?;
+	user.set_password(&request.new_password, None).await?;
+	rate_limit::login_succeeded(&user.username);
+
+	Ok(Json(GenericOkResponse::new()))
+}
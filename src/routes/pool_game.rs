@@ -1,9 +1,10 @@
 use crate::{
 	dbrecord::DBRecord,
 	error::{Error, ErrorResponse},
-	generic::{BearerToken, GenericOkResponse, UUID},
+	generic::{BearerToken, GenericOkResponse, IfMatch, Versioned, UUID},
 	models::{
-		pool_game::{PoolGame, PoolGameType, PoolGameWinner},
+		oauth_client::Scope,
+		pool_game::{ExpandedPoolGame, PoolGame, PoolGameType, PoolGameWinner},
 		pool_player::PoolPlayer,
 		user::User,
 	},
@@ -38,17 +39,19 @@ pub async fn create_pool_game(
 		_ => return Err(Error::new(Status::BadRequest, "Missing required field(s)", None).into()),
 	};
 
-	let player1 = match PoolPlayer::db_by_id(&player1.uuid_string()).await? {
-		Some(player) => player,
+	let players = UUID::load_many(&[player1.clone(), player2.clone()]).await?;
+
+	let player1 = match players.get(&player1.uuid_string()) {
+		Some(player) => player.uuid(),
 		None => return Err(Error::new(Status::NotFound, "Player 1 not found", None).into()),
 	};
 
-	let player2 = match PoolPlayer::db_by_id(&player2.uuid_string()).await? {
-		Some(player) => player,
+	let player2 = match players.get(&player2.uuid_string()) {
+		Some(player) => player.uuid(),
 		None => return Err(Error::new(Status::NotFound, "Player 2 not found", None).into()),
 	};
 
-	let game = PoolGame::new(player1.uuid(), player2.uuid(), session.user().await?.uuid());
+	let game = PoolGame::new(player1, player2, session.user().await?.uuid());
 	game.db_create().await?;
 	Ok(Json(game))
 }
@@ -58,13 +61,14 @@ pub async fn update_pool_game(
 	id: String,
 	request: Json<UpdatePoolGameRequest>,
 	bearer_token: BearerToken,
-) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	if_match: IfMatch,
+) -> Result<Versioned<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
 	require_pool_host(&session).await?;
 
 	let mut updates = vec![];
 
-	let game = PoolGame::db_by_id(&id)
+	let mut game = PoolGame::db_by_id(&id)
 		.await?
 		.ok_or_else(|| Error::new(Status::NotFound, "Pool game not found", None))?;
 
@@ -107,16 +111,21 @@ pub async fn update_pool_game(
 		}
 	}
 
+	if updates.is_empty() {
+		return Ok(Versioned::new(GenericOkResponse::new(), game.version()));
+	}
+
+	if_match.apply(&mut game)?;
 	game.db_update_fields(updates).await?;
 
-	Ok(Json(GenericOkResponse::new()))
+	Ok(Versioned::new(GenericOkResponse::new(), game.version()))
 }
 
 #[rocket::get("/api/pool_games/<id>")]
 pub async fn get_pool_game(
 	id: String,
 	bearer_token: BearerToken,
-) -> Result<Json<PoolGame>, status::Custom<Json<ErrorResponse>>> {
+) -> Result<Versioned<PoolGame>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
 	require_pool_host(&session).await?;
 
@@ -124,16 +133,22 @@ pub async fn get_pool_game(
 		.await?
 		.ok_or_else(|| Error::new(Status::NotFound, "Pool game not found", None))?;
 
-	Ok(Json(game))
+	let version = game.version();
+	Ok(Versioned::new(game, version))
 }
 
 #[rocket::get("/api/pool_games")]
 pub async fn get_pool_games(
 	bearer_token: BearerToken,
-) -> Result<Json<Vec<PoolGame>>, status::Custom<Json<ErrorResponse>>> {
-	let session = bearer_token.validate().await?;
-	require_pool_host(&session).await?;
-	Ok(Json(PoolGame::db_all().await?))
+) -> Result<Json<Vec<ExpandedPoolGame>>, status::Custom<Json<ErrorResponse>>> {
+	let authorization = bearer_token.authorize(&Scope::PoolRead).await?;
+
+	// OAuth clients are granted `pool:read` by admins, but users still need to be pool hosts
+	if let Some(session) = authorization.session() {
+		require_pool_host(session).await?;
+	}
+
+	Ok(Json(PoolGame::all_expanded().await?))
 }
 
 #[rocket::delete("/api/pool_games/<id>")]
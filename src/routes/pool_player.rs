@@ -1,11 +1,12 @@
 use crate::{
-	dbrecord::DBRecord,
+	dbrecord::{DBRecord, DBTransaction},
 	error::{Error, ErrorResponse},
-	generic::{BearerToken, GenericOkResponse, UUID},
+	generic::{BearerToken, GenericOkResponse, IfMatch, Versioned, UUID},
 	models::{
-		pool_player::PoolPlayer,
+		oauth_client::Scope,
+		pool_player::{PoolPlayer, PoolPlayerInfo},
 		session::Session,
-		user::{Role, User},
+		user::{Role, User, PLAYS_AS},
 	},
 };
 use core::str;
@@ -21,6 +22,36 @@ pub async fn require_pool_host(session: &Session) -> Result<(), Error> {
 	Ok(())
 }
 
+/// Queue linking the user with `user_id` to `player`, replacing the user linked to it before, if any.
+///
+/// Returns a 404 `Error` if the user doesn't exist, or a 409 `Error` if they already play as another player.
+async fn link_user(
+	transaction: &mut DBTransaction,
+	player: &PoolPlayer,
+	user_id: &UUID<User>,
+) -> Result<UUID<User>, Error> {
+	let user = User::db_by_id(&user_id.uuid_string())
+		.await?
+		.ok_or_else(|| Error::new(Status::NotFound, "User not found", None))?;
+
+	if let Some(existing) = user.pool_player().await? {
+		if existing.uuid == player.uuid {
+			return Ok(user.uuid());
+		}
+
+		return Err(Error::conflict(
+			"The user already plays as another pool player",
+		));
+	}
+
+	if let Some(old_user) = player.user().await? {
+		transaction.unrelate(&PLAYS_AS, &old_user.uuid(), &player.uuid())?;
+	}
+
+	transaction.relate(&PLAYS_AS, &user.uuid(), &player.uuid())?;
+	Ok(user.uuid())
+}
+
 #[derive(Deserialize)]
 pub struct UpdatePoolPlayerRequest {
 	pub descriptor: Option<String>,
@@ -31,7 +62,7 @@ pub struct UpdatePoolPlayerRequest {
 pub async fn create_pool_player(
 	request: Json<UpdatePoolPlayerRequest>,
 	bearer_token: BearerToken,
-) -> Result<Json<PoolPlayer>, status::Custom<Json<ErrorResponse>>> {
+) -> Result<Json<PoolPlayerInfo>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
 	require_pool_host(&session).await?;
 	let mut player = PoolPlayer::new();
@@ -40,18 +71,16 @@ pub async fn create_pool_player(
 		player = player.with_descriptor(descriptor);
 	}
 
-	if let Some(user) = &request.user {
-		let user = User::db_by_id(&user.uuid_string()).await?;
+	let mut transaction = DBTransaction::new();
+	transaction.create(&player)?;
 
-		if let Some(user) = user {
-			player = player.with_user(user.uuid());
-		} else {
-			return Err(Error::new(Status::NotFound, "User not found", None).into());
-		}
-	}
+	let user = match &request.user {
+		Some(user) => Some(link_user(&mut transaction, &player, user).await?),
+		None => None,
+	};
 
-	player.db_create().await?;
-	Ok(Json(player))
+	transaction.commit().await?;
+	Ok(Json(player.info_with_user(user)))
 }
 
 #[rocket::patch("/api/pool_players/<id>", format = "json", data = "<request>")]
@@ -59,13 +88,14 @@ pub async fn update_pool_player(
 	id: String,
 	request: Json<UpdatePoolPlayerRequest>,
 	bearer_token: BearerToken,
-) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	if_match: IfMatch,
+) -> Result<Versioned<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
 	require_pool_host(&session).await?;
 
 	let mut updates = vec![];
 
-	let player = PoolPlayer::db_by_id(&id)
+	let mut player = PoolPlayer::db_by_id(&id)
 		.await?
 		.ok_or_else(|| Error::new(Status::NotFound, "Pool player not found", None))?;
 
@@ -73,38 +103,53 @@ pub async fn update_pool_player(
 		updates.push(("descriptor", json!(descriptor)));
 	}
 
+	if updates.is_empty() && request.user.is_none() {
+		return Ok(Versioned::new(GenericOkResponse::new(), player.version()));
+	}
+
+	// Linking a user updates the version too, so clients that loaded the player see the change
+	if_match.apply(&mut player)?;
+	let mut transaction = DBTransaction::new();
+	transaction.update_fields(&player, updates)?;
+
 	if let Some(user) = &request.user {
-		if let Some(user) = User::db_by_id(&user.uuid_string()).await? {
-			updates.push(("user", json!(user.uuid())));
-		} else {
-			return Err(Error::new(Status::NotFound, "User not found", None).into());
-		}
+		link_user(&mut transaction, &player, user).await?;
 	}
 
-	player.db_update_fields(updates).await?;
-	Ok(Json(GenericOkResponse::new()))
+	transaction.commit().await?;
+	player.set_version(player.version() + 1);
+	Ok(Versioned::new(GenericOkResponse::new(), player.version()))
 }
 
 #[rocket::get("/api/pool_players")]
 pub async fn get_pool_players(
 	bearer_token: BearerToken,
-) -> Result<Json<Vec<PoolPlayer>>, status::Custom<Json<ErrorResponse>>> {
-	let session = bearer_token.validate().await?;
-	require_pool_host(&session).await?;
-	Ok(Json(PoolPlayer::db_all().await?))
+) -> Result<Json<Vec<PoolPlayerInfo>>, status::Custom<Json<ErrorResponse>>> {
+	let authorization = bearer_token.authorize(&Scope::PoolRead).await?;
+
+	// OAuth clients are granted `pool:read` by admins, but users still need to be pool hosts
+	if let Some(session) = authorization.session() {
+		require_pool_host(session).await?;
+	}
+
+	Ok(Json(PoolPlayer::infos(PoolPlayer::db_all().await?).await?))
 }
 
 #[rocket::get("/api/pool_players/<id>")]
 pub async fn get_pool_player(
 	id: String,
 	bearer_token: BearerToken,
-) -> Result<Json<PoolPlayer>, status::Custom<Json<ErrorResponse>>> {
+) -> Result<Versioned<PoolPlayerInfo>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
 	let user = session.user().await?;
 
 	if id == "me" {
-		return if let Some(player) = PoolPlayer::db_search_one("user", user.uuid().clone()).await? {
-			Ok(Json(player))
+		return if let Some(player) = user.pool_player().await? {
+			let version = player.version();
+			Ok(Versioned::new(
+				player.info_with_user(Some(user.uuid())),
+				version,
+			))
 		} else {
 			Err(Error::new(Status::NotFound, "Pool player not found", None).into())
 		};
@@ -113,7 +158,8 @@ pub async fn get_pool_player(
 	require_pool_host(&session).await?;
 
 	if let Some(player) = PoolPlayer::db_by_id(&id).await? {
-		Ok(Json(player))
+		let version = player.version();
+		Ok(Versioned::new(player.info().await?, version))
 	} else {
 		Err(Error::new(Status::NotFound, "Pool player not found", None).into())
 	}
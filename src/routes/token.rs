@@ -1,10 +1,16 @@
 use crate::{
 	dbrecord::DBRecord,
 	error::{Error, ErrorResponse},
+	generic::ClientInfo,
 	models::{
+		authorization_code::AuthorizationCode,
+		oauth_client::{OAuthClient, Scope},
+		passkey::Passkey,
 		session::{Session, ACCESS_TOKEN_EXPIRY_SECONDS, REFRESH_TOKEN_EXPIRY_SECONDS},
-		user::User,
+		user::{User, TWO_FACTOR_REQUIRED_ERROR},
+		webauthn_challenge::WebAuthnChallenge,
 	},
+	rate_limit,
 };
 use rocket::{
 	form::{Form, FromForm},
@@ -25,8 +31,10 @@ use serde::{Deserialize, Serialize};
 /// [OAuth2 Token Endpoint](https://datatracker.ietf.org/doc/html/rfc6749#section-3.2)
 pub async fn token_form(
 	token_request: Form<TokenRequest>,
+	client: ClientInfo,
 ) -> Result<Json<TokenResponse>, status::Custom<Json<ErrorResponse>>> {
-	token(token_request.into_inner()).await
+	rate_limit::check_ip("token", &client)?;
+	token(token_request.into_inner(), &client).await
 }
 
 #[post(
@@ -36,46 +44,154 @@ pub async fn token_form(
 )]
 pub async fn token_json(
 	token_request: Json<TokenRequest>,
+	client: ClientInfo,
 ) -> Result<Json<TokenResponse>, status::Custom<Json<ErrorResponse>>> {
-	token(token_request.into_inner()).await
+	rate_limit::check_ip("token", &client)?;
+	token(token_request.into_inner(), &client).await
 }
 
 /// Requests from both json and form content types for /auth/token are handled by this function
 pub async fn token(
 	token_request: TokenRequest,
+	client: &ClientInfo,
 ) -> Result<Json<TokenResponse>, status::Custom<Json<ErrorResponse>>> {
-	let user = User::db_search_one("username", token_request.username.clone())
-		.await?
-		.ok_or(Error::generic_401())?;
-
-	let mut session = match token_request.grant_type.as_str() {
+	let response = match token_request.grant_type.as_str() {
 		"password" => {
-			let password = token_request.password.ok_or(Error::new(
+			let username = token_request.username.as_ref().ok_or(Error::new(
 				Status::BadRequest,
-				"Missing password",
+				"Missing username",
 				None,
 			))?;
 
-			user.verify_password(&password)?;
-			let session = Session::new(&user.uuid())?;
+			rate_limit::check_login(username)?;
+
+			let user =
+				rate_limit::count_failed_login(username, token_request.password_user().await)?;
+			rate_limit::login_succeeded(username);
+
+			let mut session = Session::new(&user.uuid(), client)?;
 			session.db_create().await?;
-			session
+			TokenResponse::generate(&mut session).await?
 		}
 		"refresh_token" => {
-			let refresh_token = token_request.refresh_token.ok_or(Error::new(
+			let username = token_request.username.as_ref().ok_or(Error::new(
 				Status::BadRequest,
-				"Missing refresh token",
+				"Missing username",
 				None,
 			))?;
 
-			user.get_session_from_refresh_token(&refresh_token)
+			rate_limit::check_login(username)?;
+
+			let mut session = rate_limit::count_failed_login(
+				username,
+				token_request.refresh_token_session(client).await,
+			)?;
+
+			TokenResponse::generate(&mut session).await?
+		}
+		"authorization_code" => {
+			let oauth_client = token_request.oauth_client().await?;
+
+			let (code, redirect_uri) = match (&token_request.code, &token_request.redirect_uri) {
+				(Some(code), Some(redirect_uri)) => (code, redirect_uri),
+				_ => {
+					return Err(Error::new(
+						Status::BadRequest,
+						"Missing code or redirect URI",
+						None,
+					)
+					.into())
+				}
+			};
+
+			let authorization = AuthorizationCode::redeem(
+				code,
+				&oauth_client,
+				redirect_uri,
+				token_request.code_verifier.as_deref(),
+			)
+			.await?;
+
+			let session = Session::from_authorization_code(&authorization, client)?;
+			session.db_create().await?;
+			TokenResponse::delegated(&session)?
+		}
+		"passkey" => {
+			let (challenge, credential_id, client_data_json, authenticator_data, signature) = match (
+				&token_request.challenge,
+				&token_request.credential_id,
+				&token_request.client_data_json,
+				&token_request.authenticator_data,
+				&token_request.signature,
+			) {
+				(
+					Some(challenge),
+					Some(credential_id),
+					Some(client_data_json),
+					Some(authenticator_data),
+					Some(signature),
+				) => (
+					challenge,
+					credential_id,
+					client_data_json,
+					authenticator_data,
+					signature,
+				),
+				_ => {
+					return Err(
+						Error::new(Status::BadRequest, "Missing passkey assertion", None).into(),
+					)
+				}
+			};
+
+			let challenge = WebAuthnChallenge::consume(challenge, None).await?;
+
+			let mut passkey = Passkey::db_search_one(
+				"credential_id",
+				credential_id.trim_end_matches('=').to_owned(),
+			)
+			.await?
+			.ok_or(Error::generic_401())?;
+
+			// The authenticator verified the user (a PIN or biometric) as well as possession of the passkey,
+			// so two-factor users don't need a code
+			passkey
+				.authenticate(
+					challenge.challenge(),
+					client_data_json,
+					authenticator_data,
+					signature,
+				)
+				.await?;
+
+			let user = passkey
+				.user
+				.object_opt()
 				.await?
-				.ok_or(Error::generic_401())?
+				.ok_or(Error::generic_401())?;
+
+			let mut session = Session::new(&user.uuid(), client)?;
+			session.db_create().await?;
+			TokenResponse::generate(&mut session).await?
+		}
+		"client_credentials" => {
+			let oauth_client = token_request.oauth_client().await?;
+
+			if !oauth_client.is_confidential() {
+				return Err(Error::new(
+					Status::BadRequest,
+					"Public clients can't use the client credentials grant",
+					None,
+				)
+				.into());
+			}
+
+			let scopes = oauth_client.requested_scopes(token_request.scope.as_deref())?;
+			TokenResponse::client_credentials(&oauth_client, &scopes)?
 		}
 		_ => return Err(Error::new(Status::BadRequest, "Invalid grant type", None).into()),
 	};
 
-	let response = TokenResponse::generate(&mut session).await?;
 	Ok(Json(response))
 }
 
@@ -85,13 +201,20 @@ pub struct TokenResponse {
 	/// Used for Bearer authentication by including it in the Authorization header as Bearer <access_token>.
 	access_token: String,
 	/// Used to obtain new access tokens with the refresh_token grant type in the same authorization process.
-	refresh_token: String,
+	///
+	/// Only issued for sessions signed in to this site.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	refresh_token: Option<String>,
 	/// The lifetime in seconds of the access token.
 	expires_in: u64,
 	/// "Bearer"
 	token_type: String,
 	/// The lifetime in seconds of the refresh token.
-	x_refresh_token_expires_in: u64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	x_refresh_token_expires_in: Option<u64>,
+	/// The space-separated scopes of the access token, if issued to an OAuth client.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	scope: Option<String>,
 }
 
 impl TokenResponse {
@@ -101,7 +224,26 @@ impl TokenResponse {
 
 		Ok(Self {
 			access_token,
-			refresh_token,
+			refresh_token: Some(refresh_token),
+			x_refresh_token_expires_in: Some(REFRESH_TOKEN_EXPIRY_SECONDS),
+			..Default::default()
+		})
+	}
+
+	/// Tokens for a session authorized for an OAuth client, which doesn't get a refresh token.
+	pub fn delegated(session: &Session) -> Result<Self, Error> {
+		Ok(Self {
+			access_token: session.generate_access_token()?,
+			scope: Some(Scope::join(&session.scopes)),
+			..Default::default()
+		})
+	}
+
+	/// Tokens for an OAuth client acting for itself.
+	pub fn client_credentials(client: &OAuthClient, scopes: &[Scope]) -> Result<Self, Error> {
+		Ok(Self {
+			access_token: client.generate_access_token(scopes)?,
+			scope: Some(Scope::join(scopes)),
 			..Default::default()
 		})
 	}
@@ -111,10 +253,11 @@ impl Default for TokenResponse {
 	fn default() -> Self {
 		Self {
 			access_token: "".to_string(),
-			refresh_token: "".to_string(),
+			refresh_token: None,
 			expires_in: ACCESS_TOKEN_EXPIRY_SECONDS,
 			token_type: "Bearer".to_string(),
-			x_refresh_token_expires_in: REFRESH_TOKEN_EXPIRY_SECONDS,
+			x_refresh_token_expires_in: None,
+			scope: None,
 		}
 	}
 }
@@ -122,16 +265,43 @@ impl Default for TokenResponse {
 #[derive(Debug, FromForm, Serialize, Deserialize)]
 /// [Access Token Request](https://datatracker.ietf.org/doc/html/rfc6749#section-4.3.2)
 pub struct TokenRequest {
-	/// "password" or "refresh_token"
+	/// "password", "refresh_token", "authorization_code", "client_credentials" or "passkey"
 	grant_type: String,
 	/// User.username
-	username: String,
+	///
+	/// Required when grant_type is "password" or "refresh_token"
+	username: Option<String>,
 	/// Required when grant_type is "password"
 	password: Option<String>,
+	/// A code from the authenticator app or a recovery code, required when grant_type is "password"
+	/// and the user has two-factor authentication
+	otp: Option<String>,
 	/// Required when grant_type is "refresh_token"
 	///
 	/// When provided, the refresh token is invalidated and a new one is generated with the new access token.
 	refresh_token: Option<String>,
+	/// Required when grant_type is "authorization_code"
+	code: Option<String>,
+	/// Required when grant_type is "authorization_code", and must match the authorization request
+	redirect_uri: Option<String>,
+	/// Required when grant_type is "authorization_code" and the authorization request had a code challenge
+	code_verifier: Option<String>,
+	/// OAuthClient.uuid, required when grant_type is "authorization_code" or "client_credentials"
+	client_id: Option<String>,
+	/// Required for confidential clients
+	client_secret: Option<String>,
+	/// Optional space-separated scopes when grant_type is "client_credentials", defaults to every scope of the client
+	scope: Option<String>,
+	/// From `/api/auth/passkey/options`, required when grant_type is "passkey"
+	challenge: Option<String>,
+	/// Base64url `rawId` of the passkey, required when grant_type is "passkey"
+	credential_id: Option<String>,
+	/// Base64url `response.clientDataJSON`, required when grant_type is "passkey"
+	client_data_json: Option<String>,
+	/// Base64url `response.authenticatorData`, required when grant_type is "passkey"
+	authenticator_data: Option<String>,
+	/// Base64url `response.signature`, required when grant_type is "passkey"
+	signature: Option<String>,
 }
 
 impl TokenRequest {
@@ -139,9 +309,83 @@ impl TokenRequest {
 	pub fn new_password_grant(username: &str, password: &str) -> Self {
 		Self {
 			grant_type: "password".to_string(),
-			username: username.to_owned(),
+			username: Some(username.to_owned()),
 			password: Some(password.to_owned()),
+			otp: None,
 			refresh_token: None,
+			code: None,
+			redirect_uri: None,
+			code_verifier: None,
+			client_id: None,
+			client_secret: None,
+			scope: None,
+			challenge: None,
+			credential_id: None,
+			client_data_json: None,
+			authenticator_data: None,
+			signature: None,
+		}
+	}
+
+	async fn user(&self) -> Result<User, Error> {
+		let username = self.username.as_ref().ok_or(Error::new(
+			Status::BadRequest,
+			"Missing username",
+			None,
+		))?;
+
+		User::db_search_one("username", username.to_owned())
+			.await?
+			.ok_or(Error::generic_401())
+	}
+
+	/// Get the session of the user with the refresh token
+	async fn refresh_token_session(&self, client: &ClientInfo) -> Result<Session, Error> {
+		let user = self.user().await?;
+		let refresh_token = self.refresh_token.as_ref().ok_or(Error::new(
+			Status::BadRequest,
+			"Missing refresh token",
+			None,
+		))?;
+
+		user.get_session_from_refresh_token(refresh_token, client)
+			.await?
+			.ok_or(Error::generic_401())
+	}
+
+	/// Authenticate the user with the password, and the two-factor code if they have it enabled
+	async fn password_user(&self) -> Result<User, Error> {
+		let mut user = self.user().await?;
+		let password = self.password.as_ref().ok_or(Error::new(
+			Status::BadRequest,
+			"Missing password",
+			None,
+		))?;
+
+		user.verify_password(password)?;
+
+		if user.has_two_factor() {
+			// The second step, after the client asks for a code
+			let otp = self.otp.as_ref().ok_or(Error::new(
+				Status::Unauthorized,
+				TWO_FACTOR_REQUIRED_ERROR,
+				None,
+			))?;
+
+			user.verify_second_factor(otp).await?;
 		}
+
+		Ok(user)
+	}
+
+	/// Authenticate the OAuth client making the request
+	async fn oauth_client(&self) -> Result<OAuthClient, Error> {
+		let client_id = self.client_id.as_ref().ok_or(Error::new(
+			Status::BadRequest,
+			"Missing client ID",
+			None,
+		))?;
+
+		OAuthClient::authenticate(client_id, self.client_secret.as_deref()).await
 	}
 }
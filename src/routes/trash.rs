@@ -0,0 +1,85 @@
+use crate::{
+	dbrecord::DBRecord,
+	error::{Error, ErrorResponse},
+	generic::{BearerToken, GenericOkResponse},
+	models::{pool_player::PoolPlayer, user::User},
+	routes::users::require_admin,
+};
+use rocket::{response::status, serde::json::Json};
+use serde_json::Value;
+
+fn table_not_found() -> Error {
+	Error::not_found("Table not found or does not use trash")
+}
+
+/// Get every trashed record in a table. Admins only.
+#[rocket::get("/api/admin/trash/<table>")]
+pub async fn get_trashed(
+	table: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<Value>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+
+	let records = match table {
+		"users" => {
+			let mut users = User::db_trashed().await?;
+
+			// Don't include password hashes or two-factor secrets in the response
+			for user in &mut users {
+				user.record.password_hash = Default::default();
+				user.record.two_factor = None;
+			}
+
+			serde_json::to_value(users).map_err(Error::from)?
+		}
+		"pool_players" => {
+			serde_json::to_value(PoolPlayer::db_trashed().await?).map_err(Error::from)?
+		}
+		_ => return Err(table_not_found().into()),
+	};
+
+	Ok(Json(records))
+}
+
+/// Restore a trashed record to its table. Admins only.
+#[rocket::post("/api/admin/trash/<table>/<id>/restore")]
+pub async fn restore_trashed(
+	table: &str,
+	id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+
+	match table {
+		"users" => {
+			User::db_restore(id).await?;
+		}
+		"pool_players" => {
+			PoolPlayer::db_restore(id).await?;
+		}
+		_ => return Err(table_not_found().into()),
+	}
+
+	Ok(Json(GenericOkResponse::new()))
+}
+
+/// Permanently delete a trashed record. Admins only.
+#[rocket::delete("/api/admin/trash/<table>/<id>")]
+pub async fn purge_trashed(
+	table: &str,
+	id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	require_admin(&session).await?;
+
+	match table {
+		"users" => User::db_purge(id).await?,
+		"pool_players" => PoolPlayer::db_purge(id).await?,
+		_ => return Err(table_not_found().into()),
+	}
+
+	Ok(Json(GenericOkResponse::new()))
+}
@@ -0,0 +1,101 @@
+use crate::{
+	error::{Error, ErrorResponse},
+	generic::{BearerToken, GenericOkResponse},
+	routes::users::{get_own_user, get_user},
+};
+use rocket::{http::Status, response::status, serde::json::Json};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct TwoFactorEnrollment {
+	/// Base32 secret, for entering in the authenticator app manually
+	secret: String,
+	/// `otpauth://` URI, shown as a QR code for the authenticator app to scan
+	provisioning_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmTwoFactorRequest {
+	/// A code from the authenticator app
+	code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+	/// One-time codes to sign in without the authenticator app. Only returned once.
+	recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DisableTwoFactorRequest {
+	/// Required unless an admin is disabling it for another user
+	password: Option<String>,
+}
+
+/// Start enrolling in two-factor authentication. It isn't enabled until confirmed.
+#[rocket::post("/api/users/<id>/two_factor")]
+pub async fn start_two_factor(
+	id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<TwoFactorEnrollment>, status::Custom<Json<ErrorResponse>>> {
+	let mut user = get_own_user(id, &bearer_token).await?;
+	let (secret, provisioning_uri) = user.start_two_factor().await?;
+
+	Ok(Json(TwoFactorEnrollment {
+		secret,
+		provisioning_uri,
+	}))
+}
+
+/// Enable two-factor authentication with a code from the authenticator app.
+#[rocket::post(
+	"/api/users/<id>/two_factor/confirm",
+	format = "json",
+	data = "<request>"
+)]
+pub async fn confirm_two_factor(
+	id: &str,
+	request: Json<ConfirmTwoFactorRequest>,
+	bearer_token: BearerToken,
+) -> Result<Json<RecoveryCodes>, status::Custom<Json<ErrorResponse>>> {
+	let mut user = get_own_user(id, &bearer_token).await?;
+	let recovery_codes = user.confirm_two_factor(&request.code).await?;
+	Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+/// Disable two-factor authentication, with the user's password, or by an admin for a user who lost their device.
+#[rocket::delete("/api/users/<id>/two_factor", format = "json", data = "<request>")]
+pub async fn disable_two_factor(
+	id: &str,
+	request: Json<DisableTwoFactorRequest>,
+	bearer_token: BearerToken,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	let session_user = session.user_uuid();
+	let mut user = get_user(id, session).await?;
+
+	// Admins disabling it for someone else are checked by get_user()
+	if user.uuid == session_user {
+		let password = request.password.as_ref().ok_or(Error::new(
+			Status::BadRequest,
+			"Missing password",
+			None,
+		))?;
+
+		if user.verify_password(password).is_err() {
+			return Err(Error::new(Status::Unauthorized, "Invalid password", None).into());
+		}
+
+		if user.two_factor_required() {
+			return Err(Error::new(
+				Status::Forbidden,
+				"Two-factor authentication is required for admins",
+				None,
+			)
+			.into());
+		}
+	}
+
+	user.disable_two_factor().await?;
+	Ok(Json(GenericOkResponse::new()))
+}
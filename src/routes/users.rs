@@ -1,12 +1,13 @@
 use crate::{
-	dbrecord::DBRecord,
+	dbrecord::{DBRecord, DBTransaction},
 	error::{Error, ErrorResponse},
-	generic::{BearerToken, GenericOkResponse, UUID},
+	generic::{BearerToken, ClientInfo, GenericOkResponse, IfMatch, Versioned},
 	models::{
 		registration::Registration,
-		session::Session,
-		user::{Role, User},
+		session::{Session, SessionInfo},
+		user::{Role, User, CREATED_REFERRAL},
 	},
+	rate_limit,
 	routes::token::{token, TokenRequest, TokenResponse},
 };
 use core::str;
@@ -29,14 +30,16 @@ pub struct RegistrationRequest {
 #[rocket::post("/api/register_user", format = "json", data = "<registration>")]
 pub async fn register(
 	registration: Json<RegistrationRequest>,
+	client: ClientInfo,
 ) -> Result<Json<TokenResponse>, status::Custom<Json<ErrorResponse>>> {
+	rate_limit::check_ip("register", &client)?;
 	let registration = registration.into_inner();
 	let user = User::register(&registration).await?;
 
 	// Log them in
 	let token_request = TokenRequest::new_password_grant(&user.username, &registration.password);
 
-	token(token_request).await
+	token(token_request, &client).await
 }
 
 /// Retrieves a user by their ID, subject to security checks based on the session.
@@ -44,7 +47,7 @@ pub async fn register(
 /// This function accepts a user ID and a session. If the ID is "me", it returns the session's user.
 /// Otherwise, it returns the user corresponding to the provided ID only if the session's user is
 /// the same as the user with the ID or if the session's user is an admin.
-async fn get_user(id: &str, session: Session) -> Result<User, Error> {
+pub async fn get_user(id: &str, session: Session) -> Result<User, Error> {
 	if id == "me" {
 		session.user().await
 	} else {
@@ -52,7 +55,7 @@ async fn get_user(id: &str, session: Session) -> Result<User, Error> {
 			Some(target_user) => {
 				let session_user = session.user().await?;
 
-				if target_user.uuid != session_user.uuid() && !session_user.has_role(&Role::Admin) {
+				if target_user.uuid != session_user.uuid() && !session_user.is_effective_admin() {
 					return Err(Error::insufficient_permissions());
 				}
 
@@ -63,6 +66,37 @@ async fn get_user(id: &str, session: Session) -> Result<User, Error> {
 	}
 }
 
+/// Get the user with `id`, who must be the signed in user.
+pub async fn get_own_user(id: &str, bearer_token: &BearerToken) -> Result<User, Error> {
+	let session = bearer_token.validate().await?;
+	let session_user = session.user_uuid();
+	let user = get_user(id, session).await?;
+
+	if user.uuid != session_user {
+		return Err(Error::forbidden());
+	}
+
+	Ok(user)
+}
+
+pub async fn require_admin(session: &Session) -> Result<(), Error> {
+	let user = session.user().await?;
+
+	if !user.has_role(&Role::Admin) {
+		return Err(Error::insufficient_permissions());
+	}
+
+	if !user.is_effective_admin() {
+		return Err(Error::new(
+			Status::Forbidden,
+			"Two-factor authentication is required for admins",
+			None,
+		));
+	}
+
+	Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct ChangePasswordRequest {
 	pub old_password: String,
@@ -76,13 +110,15 @@ pub async fn change_password(
 	bearer_token: BearerToken,
 ) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
+	let session_uuid = session.uuid();
 	let mut user = get_user(&id, session).await?;
 
 	if user.verify_password(&request.old_password).is_err() {
 		return Err(Error::new(Status::Unauthorized, "Invalid password", None).into());
 	}
 
-	user.set_password(&request.new_password).await?;
+	user.set_password(&request.new_password, Some(&session_uuid))
+		.await?;
 
 	Ok(Json(GenericOkResponse::new()))
 }
@@ -103,9 +139,11 @@ pub async fn update_user(
 	id: &str,
 	request: Json<UpdateUserRequest>,
 	bearer_token: BearerToken,
-) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	if_match: IfMatch,
+) -> Result<Versioned<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
-	let is_admin_request = session.user().await?.has_role(&Role::Admin);
+	let is_admin_request = session.user().await?.is_effective_admin();
+	let session_uuid = session.uuid();
 	let mut user = get_user(id, session).await?;
 	let mut updates = vec![];
 
@@ -119,18 +157,32 @@ pub async fn update_user(
 		updates.push(("display_name", json!(display_name)));
 	}
 
+	// Every change is made in one transaction, so none are made if the user changed since `if_match`
+	let mut transaction = DBTransaction::new();
+
 	if let Some(password) = &request.password {
-		if is_admin_request {
-			user.set_password(password).await?;
-		} else {
+		if !is_admin_request {
 			// Users change their own password with the change_password endpoint
 			return Err(Error::insufficient_permissions().into());
 		}
+
+		let password_hash = user
+			.queue_password_change(&mut transaction, password, Some(&session_uuid))
+			.await?;
+
+		updates.push(("password_hash", json!(password_hash)));
 	}
 
-	user.db_update_fields(updates).await?;
+	if updates.is_empty() {
+		return Ok(Versioned::new(GenericOkResponse::new(), user.version()));
+	}
 
-	Ok(Json(GenericOkResponse::new()))
+	if_match.apply(&mut user)?;
+	transaction.update_fields(&user, updates)?;
+	transaction.commit().await?;
+	user.set_version(user.version() + 1);
+
+	Ok(Versioned::new(GenericOkResponse::new(), user.version()))
 }
 
 #[rocket::delete("/api/users/<id>")]
@@ -150,17 +202,14 @@ pub async fn get_users(
 	bearer_token: BearerToken,
 ) -> Result<Json<Vec<User>>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
-	let user = session.user().await?;
-
-	if !user.has_role(&Role::Admin) {
-		return Err(Error::insufficient_permissions().into());
-	}
+	require_admin(&session).await?;
 
 	let mut users = User::db_all().await?;
 
-	// Don't include password hashes in the response
+	// Don't include password hashes or two-factor secrets in the response
 	for user in &mut users {
 		user.password_hash = Default::default();
+		user.two_factor = None;
 	}
 
 	Ok(Json(users))
@@ -177,9 +226,9 @@ pub async fn create_referral(
 	bearer_token: BearerToken,
 ) -> Result<Json<RequestReferral>, status::Custom<Json<ErrorResponse>>> {
 	let session = bearer_token.validate().await?;
-	let mut user = get_user(id, session).await?;
+	let user = get_user(id, session).await?;
 
-	if user.referral_registrations.len() >= 5 {
+	if user.referral_registrations().await?.len() >= 5 {
 		return Err(Error::new(Status::BadRequest, "Too many referral links active", None).into());
 	}
 
@@ -192,11 +241,12 @@ pub async fn create_referral(
 		.into());
 	}
 
-	let registration = Registration::from_user(&user).db_create().await?;
-	user.referral_registrations.push(registration.uuid());
+	let registration = Registration::from_user(&user);
 
-	user.db_update_field("referral_registrations", &user.referral_registrations)
-		.await?;
+	let mut transaction = DBTransaction::new();
+	transaction.create(&registration)?;
+	transaction.relate(&CREATED_REFERRAL, &user.uuid, &registration.uuid())?;
+	transaction.commit().await?;
 
 	Ok(Json(RequestReferral {
 		key: registration.registration_key,
@@ -219,18 +269,6 @@ pub async fn delete_referral(
 	if let Either::Left(referred_by) = &registration.referrer_or_discord {
 		if referred_by == &user.uuid {
 			registration.db_delete().await?;
-
-			let new_referrals: Vec<UUID<Registration>> = user
-				.get_referral_registrations()
-				.await?
-				.into_iter()
-				.filter(|r| r.registration_key != request.key)
-				.map(|r| r.uuid())
-				.collect();
-
-			user.db_update_field("referral_registrations", &new_referrals)
-				.await?;
-
 			return Ok(Json(GenericOkResponse::new()));
 		}
 	}
@@ -252,7 +290,7 @@ pub async fn get_referrals(
 	let user = get_user(id, session).await?;
 
 	let referrals = user
-		.get_referral_registrations()
+		.referral_registrations()
 		.await?
 		.iter()
 		.map(|r| r.registration_key.to_owned())
@@ -260,3 +298,54 @@ pub async fn get_referrals(
 
 	Ok(Json(referrals))
 }
+
+/// Get the sessions of a user, including the one making the request.
+#[rocket::get("/api/users/<id>/sessions")]
+pub async fn get_sessions(
+	id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<Vec<SessionInfo>>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	let session_uuid = session.uuid();
+	let user = get_user(id, session).await?;
+
+	let sessions = user
+		.sessions()
+		.await?
+		.iter()
+		.map(|s| s.info(&session_uuid))
+		.collect();
+
+	Ok(Json(sessions))
+}
+
+/// Revoke a session of a user, signing out the device using it.
+#[rocket::delete("/api/users/<id>/sessions/<session_id>")]
+pub async fn delete_session(
+	id: &str,
+	session_id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	let user = get_user(id, session).await?;
+
+	let target = Session::db_by_id(session_id)
+		.await?
+		.filter(|s| s.user_uuid() == user.uuid)
+		.ok_or_else(|| Error::not_found("Session not found"))?;
+
+	target.db_delete().await?;
+	Ok(Json(GenericOkResponse::new()))
+}
+
+/// Revoke every session of a user, including the one making the request.
+#[rocket::delete("/api/users/<id>/sessions")]
+pub async fn delete_sessions(
+	id: &str,
+	bearer_token: BearerToken,
+) -> Result<Json<GenericOkResponse>, status::Custom<Json<ErrorResponse>>> {
+	let session = bearer_token.validate().await?;
+	let user = get_user(id, session).await?;
+	user.revoke_sessions(None).await?;
+	Ok(Json(GenericOkResponse::new()))
+}
@@ -0,0 +1,307 @@
+use crate::{
+	dbrecord::{record_tables, relation_tables, DBRecord, DBTransaction},
+	error::Error,
+	generic::{ClientInfo, Environment, HashedString, UUID},
+	models::{
+		audit_entry::AuditEntry,
+		pool_game::{PoolGame, PoolGameType, PoolGameWinner},
+		pool_player::PoolPlayer,
+		registration::Registration,
+		session::Session,
+		user::{Role, User, CREATED_REFERRAL, PLAYS_AS, REFERRED},
+	},
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::{rngs::StdRng, seq::IndexedRandom, Rng, SeedableRng};
+
+/// Password of every seeded user except the admin
+pub const SEED_PASSWORD: &str = "password123";
+const ADMIN_PASSWORD: &str = "admin123";
+/// How many records are created in each transaction
+const SEED_BATCH_SIZE: usize = 500;
+
+const FIRST_NAMES: &[&str] = &[
+	"Alex", "Bailey", "Casey", "Dakota", "Emery", "Finley", "Harper", "Jordan", "Kai", "Logan",
+	"Morgan", "Noel", "Parker", "Quinn", "Riley", "Rowan", "Sage", "Taylor", "Avery", "Skyler",
+];
+
+const LAST_NAMES: &[&str] = &[
+	"Nguyen", "Garcia", "Smith", "Kim", "Patel", "Johnson", "Silva", "Müller", "Okafor", "Rossi",
+	"Cohen", "Tanaka", "Brown", "Lopez", "Novak",
+];
+
+const DESCRIPTORS: &[&str] = &[
+	"Red hat",
+	"Tall guy by the jukebox",
+	"Plaid shirt",
+	"Regular on Tuesdays",
+	"Left-handed",
+	"Brought own cue",
+	"Leather jacket",
+	"Birthday party",
+	"Glasses",
+	"Band t-shirt",
+];
+
+/// The amount of data generated by `seed()`.
+pub struct SeedOptions {
+	/// Number of users, including the admin
+	pub users: usize,
+	/// How far back the pool game history goes
+	pub months: u32,
+	pub games_per_week: usize,
+	/// The same seed generates the same data, apart from password salts, registration keys and tokens.
+	/// Times are relative to when the data is seeded.
+	pub seed: u64,
+}
+
+impl Default for SeedOptions {
+	fn default() -> Self {
+		Self {
+			users: 50,
+			months: 6,
+			games_per_week: 20,
+			seed: 1,
+		}
+	}
+}
+
+/// Replace all data with generated development data.
+///
+/// Creates an `admin` user (password `admin123`) with `ADMIN_ID` as its Discord ID,
+/// and `options.users - 1` users with the password `SEED_PASSWORD`.
+///
+/// The audit log isn't replaced, so it records the seeded records being created.
+///
+/// Records are created in batches of `SEED_BATCH_SIZE` after the existing data is cleared,
+/// so if seeding fails partway, seed again to replace what was created.
+pub async fn seed(options: &SeedOptions) -> Result<(), Error> {
+	log::info!("Seeding database with seed {}", options.seed);
+	let mut rng = StdRng::seed_from_u64(options.seed);
+	let seeded_at = Utc::now();
+
+	let mut transaction = DBTransaction::new();
+
+	// The audit log is append-only, so it keeps the entries from before seeding
+	for table in record_tables() {
+		if table != AuditEntry::table() {
+			transaction.clear_table(&table)?;
+		}
+	}
+
+	for relation in relation_tables() {
+		transaction.clear_table(relation)?;
+	}
+
+	transaction.commit().await?;
+	let mut batch = SeedBatch::default();
+
+	// Hashing is slow, so the seeded users share a hash
+	let password_hash = HashedString::new(SEED_PASSWORD)?;
+
+	let admin = User {
+		username: "admin".to_owned(),
+		display_name: "Admin".to_owned(),
+		password_hash: HashedString::new(ADMIN_PASSWORD)?,
+		discord_id: Some(Environment::new().admin_id.val()),
+		roles: vec![Role::Admin, Role::PoolHost],
+		..Default::default()
+	};
+
+	let mut users = vec![reproducible(admin, &mut rng, seeded_at)?];
+
+	for i in 1..options.users {
+		let first_name = FIRST_NAMES.choose(&mut rng).unwrap_or(&"Player");
+		let last_name = LAST_NAMES.choose(&mut rng).unwrap_or(&"");
+
+		let roles = if rng.random_bool(0.1) {
+			vec![Role::PoolHost]
+		} else {
+			vec![]
+		};
+
+		let discord_id = if rng.random_bool(0.8) {
+			Some(
+				rng.random_range(100_000_000_000_000_000u64..999_999_999_999_999_999)
+					.to_string(),
+			)
+		} else {
+			None
+		};
+
+		let user = User {
+			username: format!("{}{}", first_name.to_lowercase(), i),
+			display_name: format!("{} {}", first_name, last_name),
+			password_hash: password_hash.clone(),
+			discord_id,
+			roles,
+			..Default::default()
+		};
+
+		let created_at = seeded_at - Duration::days(rng.random_range(0..365));
+		users.push(reproducible(user, &mut rng, created_at)?);
+	}
+
+	for user in users.iter() {
+		batch.transaction().await?.create(user)?;
+	}
+
+	// Referral chains: users are referred by an earlier user with a linked Discord account
+	for i in 1..users.len() {
+		if !rng.random_bool(0.6) {
+			continue;
+		}
+
+		let referrers: Vec<&User> = users[..i]
+			.iter()
+			.filter(|u| u.discord_id.is_some())
+			.collect();
+
+		if let Some(referrer) = referrers.choose(&mut rng) {
+			batch
+				.transaction()
+				.await?
+				.relate(&REFERRED, &referrer.uuid, &users[i].uuid)?;
+		}
+	}
+
+	// Unused registrations, from referrals and the Discord bot
+	for user in users.iter().filter(|u| u.discord_id.is_some()) {
+		for _ in 0..rng.random_range(0..=2) {
+			let registration = reproducible(Registration::from_user(user), &mut rng, seeded_at)?;
+			let transaction = batch.transaction().await?;
+			transaction.create(&registration)?;
+			transaction.relate(&CREATED_REFERRAL, &user.uuid, &registration.uuid())?;
+		}
+	}
+
+	for _ in 0..(options.users / 10).max(1) {
+		let discord_id = rng.random_range(100_000_000_000_000_000u64..999_999_999_999_999_999);
+		let registration = Registration::from_discord_id(&discord_id.to_string());
+		let registration = reproducible(registration, &mut rng, seeded_at)?;
+		batch.transaction().await?.create(&registration)?;
+	}
+
+	for user in users.iter() {
+		if rng.random_bool(0.5) {
+			let session = Session::new(&user.uuid, &ClientInfo::default())?;
+			let mut session = reproducible(session, &mut rng, seeded_at)?;
+			session.refresh_token_issued_at = seeded_at;
+			batch.transaction().await?.create(&session)?;
+		}
+	}
+
+	// Players linked to users, and unlinked players known only by a descriptor
+	let mut players = vec![];
+
+	for user in users.iter() {
+		if rng.random_bool(0.4) {
+			let player = reproducible(PoolPlayer::new(), &mut rng, seeded_at)?;
+			let transaction = batch.transaction().await?;
+			transaction.create(&player)?;
+			transaction.relate(&PLAYS_AS, &user.uuid, &player.uuid)?;
+			players.push(player.uuid);
+		}
+	}
+
+	for _ in 0..(options.users / 5).max(2) {
+		let descriptor = DESCRIPTORS.choose(&mut rng).unwrap_or(&"Guest");
+		let player = PoolPlayer::new().with_descriptor(descriptor);
+		let player = reproducible(player, &mut rng, seeded_at)?;
+		batch.transaction().await?.create(&player)?;
+		players.push(player.uuid);
+	}
+
+	let hosts: Vec<UUID<User>> = users
+		.iter()
+		.filter(|u| u.has_role(&Role::PoolHost))
+		.map(|u| u.uuid())
+		.collect();
+
+	let days = i64::from(options.months) * 30;
+	let games = days as usize * options.games_per_week / 7;
+	let today = seeded_at.date_naive();
+
+	for _ in 0..games {
+		let player1 = rng.random_range(0..players.len());
+		let player2 = (player1 + rng.random_range(1..players.len())) % players.len();
+		let days_ago = rng.random_range(0..days);
+
+		// Games from the last day may still be in progress
+		let winner = if days_ago == 0 && rng.random_bool(0.5) {
+			PoolGameWinner::Undetermined
+		} else if rng.random_bool(0.5) {
+			PoolGameWinner::Player1
+		} else {
+			PoolGameWinner::Player2
+		};
+
+		let mut game_types = PoolGameType::all();
+		let game_type = game_types.swap_remove(rng.random_range(0..game_types.len()));
+		let host = hosts
+			.choose(&mut rng)
+			.cloned()
+			.unwrap_or_else(|| users[0].uuid());
+
+		let game = PoolGame::new(players[player1].clone(), players[player2].clone(), host)
+			.with_date(today - Duration::days(days_ago))
+			.with_winner(winner)
+			.with_game_type(game_type);
+
+		let created_at = seeded_at - Duration::days(days_ago);
+		let game = reproducible(game, &mut rng, created_at)?;
+		batch.transaction().await?.create(&game)?;
+	}
+
+	batch.transaction.commit().await?;
+
+	log::info!(
+		"Seeded {} users, {} players and {} games",
+		users.len(),
+		players.len(),
+		games
+	);
+
+	Ok(())
+}
+
+/// Give `record` an ID generated by `rng`, and `created_at` as the time it was created and last updated,
+/// so the same seed generates the same records.
+fn reproducible<T: DBRecord>(
+	record: T,
+	rng: &mut StdRng,
+	created_at: DateTime<Utc>,
+) -> Result<T, Error> {
+	let mut value = serde_json::to_value(record)?;
+
+	if let Some(object) = value.as_object_mut() {
+		object.insert(
+			"uuid".to_owned(),
+			serde_json::to_value(UUID::<T>::from_rng(rng))?,
+		);
+		object.insert("created_at".to_owned(), serde_json::to_value(created_at)?);
+		object.insert("updated_at".to_owned(), serde_json::to_value(created_at)?);
+	}
+
+	Ok(serde_json::from_value(value)?)
+}
+
+/// Seeded records, committed in transactions of up to `SEED_BATCH_SIZE` records.
+#[derive(Default)]
+struct SeedBatch {
+	transaction: DBTransaction,
+	records: usize,
+}
+
+impl SeedBatch {
+	/// Get the transaction to queue the next record in, committing the current one first if it's full.
+	async fn transaction(&mut self) -> Result<&mut DBTransaction, Error> {
+		if self.records == SEED_BATCH_SIZE {
+			std::mem::take(&mut self.transaction).commit().await?;
+			self.records = 0;
+		}
+
+		self.records += 1;
+		Ok(&mut self.transaction)
+	}
+}
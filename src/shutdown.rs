@@ -0,0 +1,92 @@
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::watch, task::JoinHandle};
+
+/// How long to wait for each component (e.g. in-flight jobs) to stop before giving up on it
+const SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+
+/// Coordinates shutting down the web server, bot and jobs together.
+///
+/// Cloned into each component, which stops what it's doing once `wait()` resolves.
+#[derive(Clone)]
+pub struct Shutdown {
+	sender: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+	pub fn new() -> Self {
+		let (sender, _) = watch::channel(false);
+
+		Self {
+			sender: Arc::new(sender),
+		}
+	}
+
+	/// Start shutting down every component.
+	pub fn trigger(&self) {
+		self.sender.send_replace(true);
+	}
+
+	pub fn is_triggered(&self) -> bool {
+		*self.sender.borrow()
+	}
+
+	/// Resolves once shutdown is triggered.
+	pub async fn wait(&self) {
+		let mut receiver = self.sender.subscribe();
+		// The sender lives as long as `self`, so this can't fail
+		let _ = receiver.wait_for(|triggered| *triggered).await;
+	}
+
+	/// Trigger shutdown on Ctrl-C, or SIGTERM on Unix.
+	pub fn listen_for_signals(&self) {
+		let shutdown = self.clone();
+
+		tokio::spawn(async move {
+			#[cfg(unix)]
+			{
+				use tokio::signal::unix::{signal, SignalKind};
+
+				match signal(SignalKind::terminate()) {
+					Ok(mut sigterm) => {
+						tokio::select! {
+							_ = tokio::signal::ctrl_c() => {},
+							_ = sigterm.recv() => {},
+						}
+					}
+					Err(e) => {
+						log::error!("Failed to listen for SIGTERM: {}", e);
+						let _ = tokio::signal::ctrl_c().await;
+					}
+				}
+			}
+
+			#[cfg(not(unix))]
+			let _ = tokio::signal::ctrl_c().await;
+
+			log::info!("Received shutdown signal");
+			shutdown.trigger();
+		});
+	}
+
+	/// Wait for `tasks` to finish after shutdown is triggered, abandoning them after `SHUTDOWN_TIMEOUT_SECONDS`.
+	pub async fn join(name: &str, tasks: Vec<JoinHandle<()>>) {
+		let timeout = Duration::from_secs(SHUTDOWN_TIMEOUT_SECONDS);
+
+		let joined = tokio::time::timeout(timeout, async {
+			for task in tasks {
+				if let Err(e) = task.await {
+					log::error!("{} task failed: {}", name, e);
+				}
+			}
+		})
+		.await;
+
+		if joined.is_err() {
+			log::warn!(
+				"{} didn't stop within {} seconds",
+				name,
+				SHUTDOWN_TIMEOUT_SECONDS
+			);
+		}
+	}
+}
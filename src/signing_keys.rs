@@ -0,0 +1,314 @@
+//! Ed25519 keys that sign and verify access tokens.
+//!
+//! Every `<kid>.pem` file (PKCS#8) in `OAUTH_JWT_KEYS_DIRECTORY` verifies tokens,
+//! and the one named by `OAUTH_JWT_SIGNING_KEY_ID` signs new ones.
+//!
+//! To rotate keys, add the new key to every instance first, so they all accept tokens signed by it,
+//! then change `OAUTH_JWT_SIGNING_KEY_ID`. Remove the old key once the tokens it signed have expired.
+//! Keys are loaded once per process, so changes take effect on restart.
+
+use crate::{error::Error, generic::Environment};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+	jwk::{
+		AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+		OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+	},
+	Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::{
+	rand::SystemRandom,
+	signature::{Ed25519KeyPair, KeyPair},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+	collections::HashMap,
+	io::Write,
+	path::{Path, PathBuf},
+	sync::OnceLock,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+const PEM_TAG: &str = "PRIVATE KEY";
+
+static SIGNING_KEYS: OnceLock<SigningKeys> = OnceLock::new();
+
+/// The key set loaded from `OAUTH_JWT_KEYS_DIRECTORY`.
+pub struct SigningKeys {
+	signing_kid: String,
+	keys: HashMap<String, SigningKey>,
+}
+
+struct SigningKey {
+	encoding: EncodingKey,
+	decoding: DecodingKey,
+	jwk: Jwk,
+}
+
+impl SigningKeys {
+	/// Get the key set, loading it on first use.
+	pub fn get() -> Result<&'static Self, Error> {
+		if let Some(keys) = SIGNING_KEYS.get() {
+			return Ok(keys);
+		}
+
+		let keys = Self::load()?;
+		Ok(SIGNING_KEYS.get_or_init(|| keys))
+	}
+
+	fn load() -> Result<Self, Error> {
+		let env = Environment::new();
+		let directory = PathBuf::from(env.oauth_jwt_keys_directory.val());
+		let signing_kid = env.oauth_jwt_signing_key_id.val();
+		let mut keys = HashMap::new();
+
+		for entry in std::fs::read_dir(&directory)? {
+			let path = entry?.path();
+
+			if path.extension().is_none_or(|extension| extension != "pem") {
+				continue;
+			}
+
+			let Some(kid) = path.file_stem().and_then(|stem| stem.to_str()) else {
+				continue;
+			};
+
+			let pem = std::fs::read_to_string(&path)?;
+			keys.insert(kid.to_owned(), SigningKey::from_pem(kid, &pem)?);
+		}
+
+		if !keys.contains_key(&signing_kid) {
+			return Err(Error::generic_500(&format!(
+				"Signing key {} not found in {}",
+				signing_kid,
+				directory.display()
+			)));
+		}
+
+		Ok(Self { signing_kid, keys })
+	}
+
+	/// Sign `claims` with the current signing key.
+	pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+		let key = self
+			.keys
+			.get(&self.signing_kid)
+			.ok_or_else(|| Error::generic_500("Signing key not loaded"))?;
+
+		let mut header = Header::new(Algorithm::EdDSA);
+		header.kid = Some(self.signing_kid.to_owned());
+
+		jsonwebtoken::encode(&header, claims, &key.encoding)
+			.map_err(|e| Error::generic_500(&format!("Error encoding new JWT: {:?}", e)))
+	}
+
+	/// Verify `token` with the key named by its `kid` header and return its claims.
+	///
+	/// Returns a 401 `Error` if the key is unknown or the token is invalid.
+	pub fn decode<T: DeserializeOwned>(
+		&self,
+		token: &str,
+		mut validation: Validation,
+	) -> Result<T, Error> {
+		let header = jsonwebtoken::decode_header(token).map_err(|_| Error::generic_401())?;
+
+		let key = header
+			.kid
+			.and_then(|kid| self.keys.get(&kid))
+			.ok_or(Error::generic_401())?;
+
+		validation.algorithms = vec![Algorithm::EdDSA];
+
+		jsonwebtoken::decode::<T>(token, &key.decoding, &validation)
+			.map(|data| data.claims)
+			.map_err(|_| Error::generic_401())
+	}
+
+	/// The public keys, to be published so other services can verify tokens.
+	pub fn jwk_set(&self) -> JwkSet {
+		let mut keys: Vec<Jwk> = self.keys.values().map(|key| key.jwk.to_owned()).collect();
+		keys.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+		JwkSet { keys }
+	}
+
+	/// Generate a new key as `<kid>.pem` in `directory`, returning its path.
+	pub fn generate(directory: &Path, kid: &str) -> Result<PathBuf, Error> {
+		if kid.is_empty()
+			|| !kid
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+		{
+			return Err(Error::generic_500(
+				"Key ID must contain only alphanumeric characters, dashes and underscores",
+			));
+		}
+
+		let path = directory.join(format!("{}.pem", kid));
+
+		if path.exists() {
+			return Err(Error::generic_500(&format!(
+				"{} already exists",
+				path.display()
+			)));
+		}
+
+		let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+			.map_err(|e| Error::generic_500(&format!("Error generating key: {:?}", e)))?;
+
+		let pem = pem::encode(&pem::Pem::new(PEM_TAG, pkcs8.as_ref()));
+		std::fs::create_dir_all(directory)?;
+
+		let mut options = std::fs::OpenOptions::new();
+		options.write(true).create_new(true);
+
+		// Only readable by the owner, since anyone who can read the key can sign tokens
+		#[cfg(unix)]
+		options.mode(0o600);
+
+		options.open(&path)?.write_all(pem.as_bytes())?;
+
+		Ok(path)
+	}
+}
+
+impl SigningKey {
+	fn from_pem(kid: &str, pem: &str) -> Result<Self, Error> {
+		let invalid = |e: &dyn std::fmt::Debug| {
+			Error::generic_500(&format!("Invalid signing key {}: {:?}", kid, e))
+		};
+
+		let der = pem::parse(pem).map_err(|e| invalid(&e))?;
+
+		if der.tag() != PEM_TAG {
+			return Err(invalid(&der.tag()));
+		}
+
+		let key_pair =
+			Ed25519KeyPair::from_pkcs8_maybe_unchecked(der.contents()).map_err(|e| invalid(&e))?;
+
+		let jwk = Jwk {
+			common: CommonParameters {
+				public_key_use: Some(PublicKeyUse::Signature),
+				key_algorithm: Some(KeyAlgorithm::EdDSA),
+				key_id: Some(kid.to_owned()),
+				..Default::default()
+			},
+			algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+				key_type: OctetKeyPairType::OctetKeyPair,
+				curve: EllipticCurve::Ed25519,
+				x: URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+			}),
+		};
+
+		Ok(Self {
+			encoding: EncodingKey::from_ed_der(der.contents()),
+			decoding: DecodingKey::from_jwk(&jwk).map_err(|e| invalid(&e))?,
+			jwk,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+	use rocket::http::Status;
+	use serde_json::{json, Value};
+
+	fn key(kid: &str) -> SigningKey {
+		let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+		let pem = pem::encode(&pem::Pem::new(PEM_TAG, pkcs8.as_ref()));
+		SigningKey::from_pem(kid, &pem).unwrap()
+	}
+
+	fn key_set(signing_kid: &str, keys: Vec<(&str, SigningKey)>) -> SigningKeys {
+		SigningKeys {
+			signing_kid: signing_kid.to_owned(),
+			keys: keys
+				.into_iter()
+				.map(|(kid, key)| (kid.to_owned(), key))
+				.collect(),
+		}
+	}
+
+	fn claims() -> Value {
+		json!({ "sub": "users:1", "exp": Utc::now().timestamp() + 60 })
+	}
+
+	fn kid(token: &str) -> Option<String> {
+		jsonwebtoken::decode_header(token).unwrap().kid
+	}
+
+	#[test]
+	fn encode_decode_round_trip() {
+		let keys = key_set("current", vec![("current", key("current"))]);
+		let token = keys.encode(&claims()).unwrap();
+
+		assert_eq!(kid(&token).as_deref(), Some("current"));
+
+		let decoded: Value = keys.decode(&token, Validation::default()).unwrap();
+		assert_eq!(decoded["sub"], "users:1");
+	}
+
+	#[test]
+	fn unknown_kid_is_rejected() {
+		let other = key_set("other", vec![("other", key("other"))]);
+		let keys = key_set("current", vec![("current", key("current"))]);
+		let token = other.encode(&claims()).unwrap();
+
+		let result = keys.decode::<Value>(&token, Validation::default());
+		assert_eq!(result.unwrap_err().status(), Status::Unauthorized);
+	}
+
+	#[test]
+	fn rotated_keys_still_verify() {
+		let before = key_set("old", vec![("old", key("old"))]);
+		let old_token = before.encode(&claims()).unwrap();
+
+		// After rotating, the old key is still loaded but the new one signs
+		let after = SigningKeys {
+			signing_kid: "new".to_owned(),
+			keys: before
+				.keys
+				.into_iter()
+				.chain([("new".to_owned(), key("new"))])
+				.collect(),
+		};
+
+		let new_token = after.encode(&claims()).unwrap();
+		assert_eq!(kid(&new_token).as_deref(), Some("new"));
+
+		let decoded: Value = after.decode(&old_token, Validation::default()).unwrap();
+		assert_eq!(decoded["sub"], "users:1");
+		assert!(after
+			.decode::<Value>(&new_token, Validation::default())
+			.is_ok());
+	}
+
+	#[test]
+	fn generated_keys_load() {
+		let directory =
+			std::env::temp_dir().join(format!("kavacoast-signing-keys-{}", std::process::id()));
+
+		let path = SigningKeys::generate(&directory, "generated").unwrap();
+		let pem = std::fs::read_to_string(&path).unwrap();
+		let key = SigningKey::from_pem("generated", &pem).unwrap();
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+			assert_eq!(mode & 0o777, 0o600);
+		}
+
+		// Keys can't be overwritten
+		assert!(SigningKeys::generate(&directory, "generated").is_err());
+		std::fs::remove_dir_all(&directory).unwrap();
+
+		let keys = key_set("generated", vec![("generated", key)]);
+		let token = keys.encode(&claims()).unwrap();
+		assert!(keys.decode::<Value>(&token, Validation::default()).is_ok());
+	}
+}
@@ -0,0 +1,197 @@
+//! [TOTP](https://datatracker.ietf.org/doc/html/rfc6238) codes from authenticator apps,
+//! with the parameters they all support: HMAC-SHA1, 6 digits and a 30 second step.
+
+use crate::error::Error;
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use ring::{
+	hmac,
+	rand::{SecureRandom, SystemRandom},
+};
+
+const ISSUER: &str = "Kava Coast";
+const SECRET_BYTES: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// How many steps a code may be early or late, for clock drift
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+/// Generate a random base32 secret.
+pub fn generate_secret() -> Result<String, Error> {
+	let mut secret = [0u8; SECRET_BYTES];
+
+	SystemRandom::new()
+		.fill(&mut secret)
+		.map_err(|e| Error::generic_500(&format!("Error generating TOTP secret: {:?}", e)))?;
+
+	Ok(BASE32_NOPAD.encode(&secret))
+}
+
+/// The `otpauth://` URI that authenticator apps scan as a QR code to add `secret` for `account`.
+pub fn provisioning_uri(secret: &str, account: &str) -> Result<String, Error> {
+	let mut url = reqwest::Url::parse("otpauth://totp/")
+		.map_err(|e| Error::generic_500(&format!("Error building provisioning URI: {}", e)))?;
+
+	url.set_path(&format!("{}:{}", ISSUER, account));
+
+	url.query_pairs_mut()
+		.append_pair("secret", secret)
+		.append_pair("issuer", ISSUER)
+		.append_pair("digits", &DIGITS.to_string())
+		.append_pair("period", &STEP_SECONDS.to_string());
+
+	Ok(url.to_string())
+}
+
+/// Verify `code` against `secret`, returning the time step it was generated for.
+///
+/// Returns `None` if it doesn't match, or if it isn't newer than `last_used_step`, so it can't be replayed.
+pub fn verify(secret: &str, code: &str, last_used_step: Option<i64>) -> Result<Option<i64>, Error> {
+	let key = BASE32_NOPAD
+		.decode(secret.as_bytes())
+		.map_err(|e| Error::generic_500(&format!("Invalid TOTP secret: {}", e)))?;
+
+	let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &key);
+	let code = code.trim();
+	let current_step = Utc::now().timestamp() / STEP_SECONDS;
+
+	for step in current_step - ALLOWED_DRIFT_STEPS..=current_step + ALLOWED_DRIFT_STEPS {
+		if last_used_step.is_some_and(|last| step <= last) {
+			continue;
+		}
+
+		if generate(&key, step) == code {
+			return Ok(Some(step));
+		}
+	}
+
+	Ok(None)
+}
+
+/// [HOTP](https://datatracker.ietf.org/doc/html/rfc4226#section-5.3) of the time step
+fn generate(key: &hmac::Key, step: i64) -> String {
+	let tag = hmac::sign(key, &step.to_be_bytes());
+	let hash = tag.as_ref();
+	let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+
+	let binary = u32::from_be_bytes([
+		hash[offset] & 0x7f,
+		hash[offset + 1],
+		hash[offset + 2],
+		hash[offset + 3],
+	]);
+
+	format!(
+		"{:0width$}",
+		binary % 10u32.pow(DIGITS),
+		width = DIGITS as usize
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The SHA-1 secret of the RFC 6238 test vectors
+	const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+	fn secret() -> String {
+		BASE32_NOPAD.encode(RFC_SECRET)
+	}
+
+	fn code_at(step: i64) -> String {
+		generate(
+			&hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, RFC_SECRET),
+			step,
+		)
+	}
+
+	fn current_step() -> i64 {
+		Utc::now().timestamp() / STEP_SECONDS
+	}
+
+	#[test]
+	fn generate_matches_rfc_test_vectors() {
+		// The last 6 digits of the 8 digit codes in RFC 6238 Appendix B
+		assert_eq!(code_at(59 / STEP_SECONDS), "287082");
+		assert_eq!(code_at(1111111109 / STEP_SECONDS), "081804");
+		assert_eq!(code_at(1234567890 / STEP_SECONDS), "005924");
+		assert_eq!(code_at(20000000000 / STEP_SECONDS), "353130");
+	}
+
+	#[test]
+	fn verify_accepts_current_code() {
+		let step = current_step();
+		let verified = verify(&secret(), &code_at(step), None).unwrap();
+		assert!(verified.is_some_and(|verified| verified >= step));
+	}
+
+	#[test]
+	fn verify_trims_code() {
+		let code = format!(" {}\n", code_at(current_step()));
+		assert!(verify(&secret(), &code, None).unwrap().is_some());
+	}
+
+	#[test]
+	fn verify_allows_drift() {
+		let step = current_step() + ALLOWED_DRIFT_STEPS;
+		assert_eq!(verify(&secret(), &code_at(step), None).unwrap(), Some(step));
+	}
+
+	#[test]
+	fn verify_rejects_codes_outside_drift() {
+		let step = current_step() - ALLOWED_DRIFT_STEPS - 1;
+		assert_eq!(verify(&secret(), &code_at(step), None).unwrap(), None);
+	}
+
+	#[test]
+	fn verify_rejects_wrong_code() {
+		let code = code_at(current_step());
+		let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+		assert_eq!(verify(&secret(), &wrong, None).unwrap(), None);
+	}
+
+	#[test]
+	fn verify_rejects_replay() {
+		let step = current_step() + ALLOWED_DRIFT_STEPS;
+		let code = code_at(step);
+
+		let verified = verify(&secret(), &code, None).unwrap();
+		assert_eq!(verified, Some(step));
+		assert_eq!(verify(&secret(), &code, verified).unwrap(), None);
+	}
+
+	#[test]
+	fn verify_accepts_codes_newer_than_last_used() {
+		let step = current_step() + ALLOWED_DRIFT_STEPS;
+		assert_eq!(
+			verify(&secret(), &code_at(step), Some(step - 1)).unwrap(),
+			Some(step)
+		);
+	}
+
+	#[test]
+	fn verify_rejects_invalid_secret() {
+		let error = verify("not base32!", "123456", None).unwrap_err();
+		assert_eq!(error.status(), rocket::http::Status::InternalServerError);
+	}
+
+	#[test]
+	fn generated_secrets_are_distinct_base32() {
+		let secret = generate_secret().unwrap();
+		assert_eq!(
+			BASE32_NOPAD.decode(secret.as_bytes()).unwrap().len(),
+			SECRET_BYTES
+		);
+		assert_ne!(secret, generate_secret().unwrap());
+	}
+
+	#[test]
+	fn provisioning_uri_includes_parameters() {
+		let uri = provisioning_uri("SECRET", "alex1").unwrap();
+		assert!(uri.starts_with("otpauth://totp/Kava%20Coast:alex1?"));
+		assert!(uri.contains("secret=SECRET"));
+		assert!(uri.contains("digits=6"));
+		assert!(uri.contains("period=30"));
+	}
+}
@@ -1,4 +1,4 @@
-use crate::{generic::Environment, routes};
+use crate::{audit, generic::Environment, rate_limit, routes, shutdown::Shutdown};
 use rocket::{
 	fs::{relative, NamedFile},
 	response::Redirect,
@@ -36,17 +36,32 @@ pub fn version() -> Json<VersionInfo> {
 	})
 }
 
-pub async fn start_web() {
-	if let Err(e) = rocket::build()
+/// Run the web server until it shuts down, either on its own (e.g. on a signal) or when `shutdown` is triggered.
+pub async fn start_web(shutdown: Shutdown) {
+	let rocket = rocket::build()
 		.mount(
 			"/",
-			rocket::routes![
+			rate_limit::scoped(audit::scoped(rocket::routes![
 				static_pages,
 				version,
 				join,
 				routes::token::token_json,
 				routes::token::token_form,
 				routes::check_token::check_token,
+				routes::logout::logout,
+				routes::jwks::jwks,
+				routes::password_reset::forgot_password,
+				routes::password_reset::reset_password,
+				routes::discord::discord_authorize,
+				routes::discord::discord_login,
+				routes::discord::discord_two_factor,
+				routes::discord::link_discord,
+				routes::oauth::get_authorization,
+				routes::oauth::authorize,
+				routes::oauth::userinfo,
+				routes::oauth_clients::create_oauth_client,
+				routes::oauth_clients::get_oauth_clients,
+				routes::oauth_clients::delete_oauth_client,
 				routes::users::register,
 				routes::check_registration_key::check_registration_key,
 				routes::pages::dashboard::dashboard,
@@ -61,6 +76,17 @@ pub async fn start_web() {
 				routes::users::create_referral,
 				routes::users::delete_referral,
 				routes::users::get_referrals,
+				routes::users::get_sessions,
+				routes::users::delete_session,
+				routes::users::delete_sessions,
+				routes::two_factor::start_two_factor,
+				routes::two_factor::confirm_two_factor,
+				routes::two_factor::disable_two_factor,
+				routes::passkeys::passkey_registration_options,
+				routes::passkeys::register_passkey,
+				routes::passkeys::get_passkeys,
+				routes::passkeys::delete_passkey,
+				routes::passkeys::passkey_login_options,
 				routes::pool_player::create_pool_player,
 				routes::pool_player::get_pool_players,
 				routes::pool_player::get_pool_player,
@@ -70,12 +96,37 @@ pub async fn start_web() {
 				routes::pool_game::get_pool_game,
 				routes::pool_game::get_pool_games,
 				routes::pool_game::delete_pool_game,
-			],
+				routes::trash::get_trashed,
+				routes::trash::restore_trashed,
+				routes::trash::purge_trashed,
+				routes::audit::get_audit_entries,
+				routes::live::live_pool,
+				routes::jobs::get_jobs,
+				routes::jobs::run_job,
+				routes::jobs::pause_job,
+				routes::jobs::resume_job,
+			])),
 		)
 		.attach(Shield::default().enable(Hsts::IncludeSubDomains(Duration::new(31536000, 0))))
-		.launch()
-		.await
-	{
+		.ignite()
+		.await;
+
+	let rocket = match rocket {
+		Ok(rocket) => rocket,
+		Err(e) => {
+			log::error!("Error starting web server: {}", e);
+			return;
+		}
+	};
+
+	let handle = rocket.shutdown();
+
+	tokio::spawn(async move {
+		shutdown.wait().await;
+		handle.notify();
+	});
+
+	if let Err(e) = rocket.launch().await {
 		log::error!("Error starting web server: {}", e);
 	}
 }
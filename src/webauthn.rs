@@ -0,0 +1,650 @@
+//! A minimal [WebAuthn](https://www.w3.org/TR/webauthn-2/) relying party for passkeys.
+//!
+//! Attestation statements aren't verified (passkeys are registered with "none" attestation),
+//! and credentials must be signed with ES256, EdDSA or RS256.
+
+use crate::{error::Error, generic::Environment};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use ring::{digest, signature};
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_RP_ID: &str = "kavacoast.com";
+pub const DEFAULT_ORIGIN: &str = "https://kavacoast.com";
+pub const RP_NAME: &str = "Kava Coast";
+
+/// [COSE algorithms](https://www.iana.org/assignments/cose/cose.xhtml#algorithms) that passkeys may use
+pub const ES256: i64 = -7;
+pub const EDDSA: i64 = -8;
+pub const RS256: i64 = -257;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// This site, as identified to authenticators.
+pub struct RelyingParty {
+	/// The domain passkeys are scoped to
+	pub id: String,
+	/// The origin that ceremonies must run on
+	pub origin: String,
+}
+
+/// The public key of a passkey, with base64url fields.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "alg", rename_all = "snake_case")]
+pub enum PublicKey {
+	/// Uncompressed P-256 point
+	Es256 { point: String },
+	/// Ed25519 key
+	EdDsa { x: String },
+	/// RSA modulus and exponent
+	Rs256 { n: String, e: String },
+}
+
+/// A passkey created by a registration ceremony.
+pub struct NewCredential {
+	/// Base64url credential ID
+	pub id: String,
+	pub public_key: PublicKey,
+	pub sign_count: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientData {
+	#[serde(rename = "type")]
+	ceremony: String,
+	challenge: String,
+	origin: String,
+}
+
+struct AuthenticatorData<'a> {
+	flags: u8,
+	sign_count: u32,
+	/// Attested credential data and extensions
+	rest: &'a [u8],
+}
+
+impl RelyingParty {
+	/// `WEBAUTHN_RP_ID` and `WEBAUTHN_ORIGIN`, or `DEFAULT_RP_ID` and `DEFAULT_ORIGIN` if unset.
+	pub fn from_env() -> Self {
+		let env = Environment::new();
+
+		Self {
+			id: env
+				.webauthn_rp_id
+				.val_opt()
+				.unwrap_or(DEFAULT_RP_ID.to_owned()),
+			origin: env
+				.webauthn_origin
+				.val_opt()
+				.unwrap_or(DEFAULT_ORIGIN.to_owned()),
+		}
+	}
+
+	/// Verify the response to a registration ceremony for `challenge`, returning the new credential.
+	///
+	/// [Registering a New Credential](https://www.w3.org/TR/webauthn-2/#sctn-registering-a-new-credential)
+	pub fn verify_registration(
+		&self,
+		challenge: &str,
+		client_data_json: &str,
+		attestation_object: &str,
+	) -> Result<NewCredential, Error> {
+		let invalid = || Error::new(Status::BadRequest, "Invalid passkey registration", None);
+
+		self.verify_client_data(&decode(client_data_json)?, "webauthn.create", challenge)
+			.map_err(|_| invalid())?;
+
+		let attestation: Value = ciborium::de::from_reader(decode(attestation_object)?.as_slice())
+			.map_err(|_| invalid())?;
+
+		let auth_data = map_get(&attestation, |key| key.as_text() == Some("authData"))
+			.and_then(Value::as_bytes)
+			.ok_or_else(invalid)?;
+
+		let auth_data = self
+			.verify_authenticator_data(auth_data)
+			.map_err(|_| invalid())?;
+
+		if auth_data.flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 || auth_data.rest.len() < 18 {
+			return Err(invalid());
+		}
+
+		// AAGUID (16 bytes), credential ID length (2 bytes), credential ID, credential public key
+		let id_length = u16::from_be_bytes([auth_data.rest[16], auth_data.rest[17]]) as usize;
+		let id = auth_data.rest.get(18..18 + id_length).ok_or_else(invalid)?;
+
+		let cose_key: Value =
+			ciborium::de::from_reader(&auth_data.rest[18 + id_length..]).map_err(|_| invalid())?;
+
+		Ok(NewCredential {
+			id: URL_SAFE_NO_PAD.encode(id),
+			public_key: PublicKey::from_cose(&cose_key).ok_or_else(invalid)?,
+			sign_count: auth_data.sign_count,
+		})
+	}
+
+	/// Verify the response to an authentication ceremony for `challenge`, returning the new signature count.
+	///
+	/// Returns a 401 `Error` if it's invalid, or the signature count didn't increase from `sign_count`,
+	/// which means the authenticator was cloned.
+	///
+	/// [Verifying an Authentication Assertion](https://www.w3.org/TR/webauthn-2/#sctn-verifying-assertion)
+	pub fn verify_authentication(
+		&self,
+		challenge: &str,
+		public_key: &PublicKey,
+		sign_count: u32,
+		client_data_json: &str,
+		authenticator_data: &str,
+		signature: &str,
+	) -> Result<u32, Error> {
+		let invalid = || Error::new(Status::Unauthorized, "Invalid passkey", None);
+		let client_data_json = decode(client_data_json)?;
+		let authenticator_data = decode(authenticator_data)?;
+
+		self.verify_client_data(&client_data_json, "webauthn.get", challenge)
+			.map_err(|_| invalid())?;
+
+		let auth_data = self
+			.verify_authenticator_data(&authenticator_data)
+			.map_err(|_| invalid())?;
+
+		let mut signed = authenticator_data.to_owned();
+		signed.extend_from_slice(digest::digest(&digest::SHA256, &client_data_json).as_ref());
+		public_key
+			.verify(&signed, &decode(signature)?)
+			.map_err(|_| invalid())?;
+
+		// Authenticators that don't count signatures always send 0
+		if (auth_data.sign_count != 0 || sign_count != 0) && auth_data.sign_count <= sign_count {
+			return Err(invalid());
+		}
+
+		Ok(auth_data.sign_count)
+	}
+
+	fn verify_client_data(
+		&self,
+		client_data_json: &[u8],
+		ceremony: &str,
+		challenge: &str,
+	) -> Result<(), ()> {
+		let client_data: ClientData = serde_json::from_slice(client_data_json).map_err(|_| ())?;
+
+		if client_data.ceremony != ceremony
+			|| client_data.challenge.trim_end_matches('=') != challenge
+			|| client_data.origin != self.origin
+		{
+			return Err(());
+		}
+
+		Ok(())
+	}
+
+	fn verify_authenticator_data<'a>(
+		&self,
+		auth_data: &'a [u8],
+	) -> Result<AuthenticatorData<'a>, ()> {
+		if auth_data.len() < 37 {
+			return Err(());
+		}
+
+		let rp_id_hash = digest::digest(&digest::SHA256, self.id.as_bytes());
+
+		if &auth_data[..32] != rp_id_hash.as_ref() {
+			return Err(());
+		}
+
+		let flags = auth_data[32];
+
+		// Passkeys replace both the password and the second factor, so the authenticator must verify the user
+		if flags & FLAG_USER_PRESENT == 0 || flags & FLAG_USER_VERIFIED == 0 {
+			return Err(());
+		}
+
+		Ok(AuthenticatorData {
+			flags,
+			sign_count: u32::from_be_bytes([
+				auth_data[33],
+				auth_data[34],
+				auth_data[35],
+				auth_data[36],
+			]),
+			rest: &auth_data[37..],
+		})
+	}
+}
+
+impl PublicKey {
+	/// Convert a [COSE key](https://datatracker.ietf.org/doc/html/rfc9052#section-7), or `None` if unsupported.
+	fn from_cose(key: &Value) -> Option<Self> {
+		let param = |label: i64| {
+			map_get(key, |k| {
+				k.as_integer()
+					.is_some_and(|i| i64::try_from(i).ok() == Some(label))
+			})
+		};
+
+		let bytes = |label: i64| {
+			param(label)
+				.and_then(Value::as_bytes)
+				.map(|b| URL_SAFE_NO_PAD.encode(b))
+		};
+		let alg = param(3)
+			.and_then(Value::as_integer)
+			.and_then(|i| i64::try_from(i).ok())?;
+
+		match alg {
+			ES256 => {
+				let x = param(-2).and_then(Value::as_bytes)?;
+				let y = param(-3).and_then(Value::as_bytes)?;
+				let mut point = vec![0x04];
+				point.extend_from_slice(x);
+				point.extend_from_slice(y);
+
+				Some(Self::Es256 {
+					point: URL_SAFE_NO_PAD.encode(point),
+				})
+			}
+			EDDSA => Some(Self::EdDsa { x: bytes(-2)? }),
+			RS256 => Some(Self::Rs256 {
+				n: bytes(-1)?,
+				e: bytes(-2)?,
+			}),
+			_ => None,
+		}
+	}
+
+	fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), Error> {
+		let invalid = |_| Error::new(Status::Unauthorized, "Invalid passkey", None);
+
+		match self {
+			Self::Es256 { point } => signature::UnparsedPublicKey::new(
+				&signature::ECDSA_P256_SHA256_ASN1,
+				decode(point)?,
+			)
+			.verify(message, signature)
+			.map_err(invalid),
+			Self::EdDsa { x } => signature::UnparsedPublicKey::new(&signature::ED25519, decode(x)?)
+				.verify(message, signature)
+				.map_err(invalid),
+			Self::Rs256 { n, e } => signature::RsaPublicKeyComponents {
+				n: decode(n)?,
+				e: decode(e)?,
+			}
+			.verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+			.map_err(invalid),
+		}
+	}
+}
+
+/// Generate a random base64url challenge.
+pub fn generate_challenge() -> Result<String, Error> {
+	let mut challenge = [0u8; 32];
+
+	ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut challenge)
+		.map_err(|e| Error::generic_500(&format!("Error generating challenge: {:?}", e)))?;
+
+	Ok(URL_SAFE_NO_PAD.encode(challenge))
+}
+
+/// Decode base64url, with or without padding.
+pub fn decode(value: &str) -> Result<Vec<u8>, Error> {
+	URL_SAFE_NO_PAD
+		.decode(value.trim_end_matches('='))
+		.map_err(|_| Error::new(Status::BadRequest, "Invalid base64url", None))
+}
+
+fn map_get(map: &Value, key: impl Fn(&Value) -> bool) -> Option<&Value> {
+	map.as_map()?
+		.iter()
+		.find(|(k, _)| key(k))
+		.map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ring::{
+		rand::SystemRandom,
+		signature::{Ed25519KeyPair, KeyPair},
+	};
+
+	const CHALLENGE: &str = "Y2hhbGxlbmdl";
+	const CREDENTIAL_ID: &[u8] = b"credential-id";
+
+	fn relying_party() -> RelyingParty {
+		RelyingParty {
+			id: DEFAULT_RP_ID.to_owned(),
+			origin: DEFAULT_ORIGIN.to_owned(),
+		}
+	}
+
+	fn key_pair() -> Ed25519KeyPair {
+		let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+		Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+	}
+
+	fn client_data(ceremony: &str, challenge: &str, origin: &str) -> String {
+		let json = serde_json::json!({
+			"type": ceremony,
+			"challenge": challenge,
+			"origin": origin,
+		});
+
+		URL_SAFE_NO_PAD.encode(json.to_string())
+	}
+
+	fn cbor(value: &Value) -> Vec<u8> {
+		let mut bytes = vec![];
+		ciborium::ser::into_writer(value, &mut bytes).unwrap();
+		bytes
+	}
+
+	fn cose_key(params: Vec<(i64, Value)>) -> Value {
+		Value::Map(
+			params
+				.into_iter()
+				.map(|(label, value)| (Value::Integer(label.into()), value))
+				.collect(),
+		)
+	}
+
+	fn ed25519_cose_key(key_pair: &Ed25519KeyPair) -> Value {
+		cose_key(vec![
+			(1, Value::Integer(1.into())),
+			(3, Value::Integer(EDDSA.into())),
+			(-1, Value::Integer(6.into())),
+			(-2, Value::Bytes(key_pair.public_key().as_ref().to_vec())),
+		])
+	}
+
+	/// The RP ID hash, flags and signature count, followed by `rest`
+	fn authenticator_data(rp_id: &str, flags: u8, sign_count: u32, rest: &[u8]) -> Vec<u8> {
+		let mut auth_data = digest::digest(&digest::SHA256, rp_id.as_bytes())
+			.as_ref()
+			.to_vec();
+		auth_data.push(flags);
+		auth_data.extend_from_slice(&sign_count.to_be_bytes());
+		auth_data.extend_from_slice(rest);
+		auth_data
+	}
+
+	/// Attested credential data: AAGUID, credential ID length, credential ID and COSE key
+	fn attested_credential_data(cose_key: &Value) -> Vec<u8> {
+		let mut data = vec![0u8; 16];
+		data.extend_from_slice(&(CREDENTIAL_ID.len() as u16).to_be_bytes());
+		data.extend_from_slice(CREDENTIAL_ID);
+		data.extend_from_slice(&cbor(cose_key));
+		data
+	}
+
+	fn attestation_object(auth_data: Vec<u8>) -> String {
+		let attestation = Value::Map(vec![
+			(
+				Value::Text("fmt".to_owned()),
+				Value::Text("none".to_owned()),
+			),
+			(Value::Text("attStmt".to_owned()), Value::Map(vec![])),
+			(Value::Text("authData".to_owned()), Value::Bytes(auth_data)),
+		]);
+
+		URL_SAFE_NO_PAD.encode(cbor(&attestation))
+	}
+
+	fn register(flags: u8, cose_key: &Value) -> Result<NewCredential, Error> {
+		let auth_data =
+			authenticator_data(DEFAULT_RP_ID, flags, 0, &attested_credential_data(cose_key));
+
+		relying_party().verify_registration(
+			CHALLENGE,
+			&client_data("webauthn.create", CHALLENGE, DEFAULT_ORIGIN),
+			&attestation_object(auth_data),
+		)
+	}
+
+	/// Sign an authentication ceremony with `key_pair`, returning the authenticator data and signature.
+	fn sign(
+		key_pair: &Ed25519KeyPair,
+		auth_data: &[u8],
+		client_data_json: &str,
+	) -> (String, String) {
+		let mut signed = auth_data.to_vec();
+		signed.extend_from_slice(
+			digest::digest(&digest::SHA256, &decode(client_data_json).unwrap()).as_ref(),
+		);
+
+		(
+			URL_SAFE_NO_PAD.encode(auth_data),
+			URL_SAFE_NO_PAD.encode(key_pair.sign(&signed)),
+		)
+	}
+
+	fn authenticate(
+		key_pair: &Ed25519KeyPair,
+		flags: u8,
+		stored_sign_count: u32,
+		sign_count: u32,
+	) -> Result<u32, Error> {
+		let client_data_json = client_data("webauthn.get", CHALLENGE, DEFAULT_ORIGIN);
+		let auth_data = authenticator_data(DEFAULT_RP_ID, flags, sign_count, &[]);
+		let (auth_data, signature) = sign(key_pair, &auth_data, &client_data_json);
+
+		relying_party().verify_authentication(
+			CHALLENGE,
+			&PublicKey::EdDsa {
+				x: URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+			},
+			stored_sign_count,
+			&client_data_json,
+			&auth_data,
+			&signature,
+		)
+	}
+
+	#[test]
+	fn registration_returns_credential() {
+		let key_pair = key_pair();
+		let credential = register(0x45, &ed25519_cose_key(&key_pair)).unwrap();
+
+		assert_eq!(credential.id, URL_SAFE_NO_PAD.encode(CREDENTIAL_ID));
+		assert_eq!(credential.sign_count, 0);
+		assert!(matches!(
+			credential.public_key,
+			PublicKey::EdDsa { x } if x == URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref())
+		));
+	}
+
+	#[test]
+	fn registration_requires_user_verification() {
+		assert!(matches!(
+			register(0x41, &ed25519_cose_key(&key_pair())),
+			Err(e) if e.status() == Status::BadRequest
+		));
+	}
+
+	#[test]
+	fn registration_requires_attested_credential_data() {
+		assert!(register(0x05, &ed25519_cose_key(&key_pair())).is_err());
+	}
+
+	#[test]
+	fn registration_rejects_other_relying_party() {
+		let rest = attested_credential_data(&ed25519_cose_key(&key_pair()));
+		let auth_data = authenticator_data("example.com", 0x45, 0, &rest);
+
+		let result = relying_party().verify_registration(
+			CHALLENGE,
+			&client_data("webauthn.create", CHALLENGE, DEFAULT_ORIGIN),
+			&attestation_object(auth_data),
+		);
+
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn registration_rejects_mismatched_client_data() {
+		let rest = attested_credential_data(&ed25519_cose_key(&key_pair()));
+
+		for client_data_json in [
+			client_data("webauthn.get", CHALLENGE, DEFAULT_ORIGIN),
+			client_data("webauthn.create", "b3RoZXI", DEFAULT_ORIGIN),
+			client_data("webauthn.create", CHALLENGE, "https://example.com"),
+		] {
+			let auth_data = authenticator_data(DEFAULT_RP_ID, 0x45, 0, &rest);
+			let result = relying_party().verify_registration(
+				CHALLENGE,
+				&client_data_json,
+				&attestation_object(auth_data),
+			);
+
+			assert!(result.is_err());
+		}
+	}
+
+	#[test]
+	fn registration_rejects_truncated_credential_id() {
+		let mut rest = vec![0u8; 16];
+		rest.extend_from_slice(&64u16.to_be_bytes());
+		rest.extend_from_slice(CREDENTIAL_ID);
+		let auth_data = authenticator_data(DEFAULT_RP_ID, 0x45, 0, &rest);
+
+		let result = relying_party().verify_registration(
+			CHALLENGE,
+			&client_data("webauthn.create", CHALLENGE, DEFAULT_ORIGIN),
+			&attestation_object(auth_data),
+		);
+
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn cose_es256_key_is_uncompressed_point() {
+		let key = cose_key(vec![
+			(1, Value::Integer(2.into())),
+			(3, Value::Integer(ES256.into())),
+			(-1, Value::Integer(1.into())),
+			(-2, Value::Bytes(vec![1; 32])),
+			(-3, Value::Bytes(vec![2; 32])),
+		]);
+
+		let mut point = vec![0x04];
+		point.extend_from_slice(&[1; 32]);
+		point.extend_from_slice(&[2; 32]);
+
+		assert!(matches!(
+			PublicKey::from_cose(&key),
+			Some(PublicKey::Es256 { point: p }) if p == URL_SAFE_NO_PAD.encode(&point)
+		));
+	}
+
+	#[test]
+	fn cose_rs256_key_has_modulus_and_exponent() {
+		let key = cose_key(vec![
+			(1, Value::Integer(3.into())),
+			(3, Value::Integer(RS256.into())),
+			(-1, Value::Bytes(vec![0xab; 256])),
+			(-2, Value::Bytes(vec![1, 0, 1])),
+		]);
+
+		assert!(matches!(
+			PublicKey::from_cose(&key),
+			Some(PublicKey::Rs256 { n, e })
+				if n == URL_SAFE_NO_PAD.encode([0xab; 256]) && e == "AQAB"
+		));
+	}
+
+	#[test]
+	fn cose_rejects_unsupported_algorithm() {
+		// ES384
+		let key = cose_key(vec![
+			(1, Value::Integer(2.into())),
+			(3, Value::Integer((-35).into())),
+		]);
+
+		assert!(PublicKey::from_cose(&key).is_none());
+	}
+
+	#[test]
+	fn cose_rejects_missing_parameters() {
+		let key = cose_key(vec![(3, Value::Integer(EDDSA.into()))]);
+		assert!(PublicKey::from_cose(&key).is_none());
+	}
+
+	#[test]
+	fn authentication_returns_sign_count() {
+		assert_eq!(authenticate(&key_pair(), 0x05, 4, 5).unwrap(), 5);
+	}
+
+	#[test]
+	fn authentication_allows_authenticators_without_counters() {
+		assert_eq!(authenticate(&key_pair(), 0x05, 0, 0).unwrap(), 0);
+	}
+
+	#[test]
+	fn authentication_rejects_sign_count_that_did_not_increase() {
+		let error = authenticate(&key_pair(), 0x05, 5, 5).unwrap_err();
+		assert_eq!(error.status(), Status::Unauthorized);
+		assert!(authenticate(&key_pair(), 0x05, 5, 0).is_err());
+	}
+
+	#[test]
+	fn authentication_requires_user_verification() {
+		assert!(authenticate(&key_pair(), 0x01, 0, 1).is_err());
+	}
+
+	#[test]
+	fn authentication_rejects_other_key() {
+		let client_data_json = client_data("webauthn.get", CHALLENGE, DEFAULT_ORIGIN);
+		let auth_data = authenticator_data(DEFAULT_RP_ID, 0x05, 1, &[]);
+		let (auth_data, signature) = sign(&key_pair(), &auth_data, &client_data_json);
+
+		let result = relying_party().verify_authentication(
+			CHALLENGE,
+			&PublicKey::EdDsa {
+				x: URL_SAFE_NO_PAD.encode(key_pair().public_key().as_ref()),
+			},
+			0,
+			&client_data_json,
+			&auth_data,
+			&signature,
+		);
+
+		assert_eq!(result.unwrap_err().status(), Status::Unauthorized);
+	}
+
+	#[test]
+	fn authentication_rejects_registration_client_data() {
+		let key_pair = key_pair();
+		let client_data_json = client_data("webauthn.create", CHALLENGE, DEFAULT_ORIGIN);
+		let auth_data = authenticator_data(DEFAULT_RP_ID, 0x05, 1, &[]);
+		let (auth_data, signature) = sign(&key_pair, &auth_data, &client_data_json);
+
+		let result = relying_party().verify_authentication(
+			CHALLENGE,
+			&PublicKey::EdDsa {
+				x: URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+			},
+			0,
+			&client_data_json,
+			&auth_data,
+			&signature,
+		);
+
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn decode_accepts_padding() {
+		assert_eq!(decode("YQ==").unwrap(), b"a");
+		assert_eq!(decode("YQ").unwrap(), b"a");
+		assert_eq!(
+			decode("not base64!").unwrap_err().status(),
+			Status::BadRequest
+		);
+	}
+}
@@ -193,6 +193,28 @@ class Dashboard {
 		}
 	}
 
+	/**
+	 * If the request failed because what it updated was changed elsewhere (409),
+	 * offer to reload so the user sees the changes, returning whether it was a conflict.
+	 */
+	static reload_on_conflict(error) {
+		if (error.status !== 409) {
+			return false;
+		}
+
+		let message = "This was changed elsewhere since the page was loaded.";
+
+		try {
+			message = JSON.parse(error.message).error;
+		} catch { }
+
+		if (confirm(message + "\n\nReload the page now?")) {
+			window.location.reload();
+		}
+
+		return true;
+	}
+
 	open_loading() {
 		this.show_page("loading");
 	}
@@ -2,6 +2,91 @@ class PoolHost {
     constructor(parent, data) {
         parent.show_page("pool_host", () => {
             console.log(data);
+            this.listen();
         });
     }
-}
\ No newline at end of file
+
+    /**
+     * Show changes to pool games and players as they happen, until the page is left.
+     * The server ends the stream when the access token expires, so it's reopened with a refreshed token.
+     */
+    listen() {
+        if (!document.getElementById("pool-host-updates")) {
+            return;
+        }
+
+        fetch("/api/live/pool", {
+            headers: { "Authorization": `Bearer ${Auth.get_cookie("accessToken")}` }
+        }).then(response => {
+            if (response.status == 401) {
+                return Auth.refresh_token();
+            }
+
+            if (!response.ok) {
+                throw new Error(`Live updates failed with status ${response.status}`);
+            }
+
+            return this.read_events(response.body.getReader());
+        }).then(() => {
+            this.listen();
+        }).catch(e => {
+            console.error(e);
+            setTimeout(() => this.listen(), 5000);
+        });
+    }
+
+    /**
+     * Read server-sent events from the stream, showing each `update` event.
+     */
+    async read_events(reader) {
+        let decoder = new TextDecoder();
+        let buffer = "";
+
+        while (true) {
+            if (!document.getElementById("pool-host-updates")) {
+                reader.cancel();
+                return;
+            }
+
+            let { done, value } = await reader.read();
+
+            if (done) {
+                return;
+            }
+
+            buffer += decoder.decode(value, { stream: true });
+            let events = buffer.split("\n\n");
+            buffer = events.pop();
+
+            for (let event of events) {
+                let name = "message";
+                let data = "";
+
+                for (let line of event.split("\n")) {
+                    if (line.startsWith("event:")) {
+                        name = line.slice(6).trim();
+                    } else if (line.startsWith("data:")) {
+                        data += line.slice(5).trim();
+                    }
+                }
+
+                if (name == "update" && data) {
+                    this.show_update(JSON.parse(data));
+                }
+            }
+        }
+    }
+
+    show_update(update) {
+        let container = document.getElementById("pool-host-updates");
+
+        if (!container) {
+            return;
+        }
+
+        let update_elem = document.createElement("div");
+        let name = update.record.descriptor || update.record.uuid;
+        update_elem.innerText = `${new Date().toLocaleTimeString()}: ${update.action} ${update.table} ${name}`;
+        container.prepend(update_elem);
+    }
+}
@@ -3,6 +3,8 @@ class Settings {
         parent.show_page("settings", () => {
             this.current_username = data.username;
             this.current_displayname = data.display_name;
+            // The user's version, so updates fail if it was changed elsewhere since the page loaded
+            this.etag = Request.etags["/api/page/settings"];
 
             this.update_username_button = document.getElementById("settings-change-username-button");
             this.update_displayname_button = document.getElementById("settings-change-displayname-button");
@@ -28,7 +30,90 @@ class Settings {
                 }
             }
 
+            if (!data.discord_id) {
+                document.getElementById("settings-link-discord-button").style.display = "";
+            }
+
             this.update_referral_list(data.referrals);
+            this.refresh_passkeys();
+        });
+    }
+
+    link_discord() {
+        Auth.request("/api/auth/discord?link=true").then(response => {
+            sessionStorage.setItem("discord_link", "1");
+            window.location.href = JSON.parse(response).authorize_url;
+        }).catch(error => {
+            try {
+                document.getElementById("settings-link-discord-error").innerText = JSON.parse(error.message).error;
+            } catch (e) {
+                document.getElementById("settings-link-discord-error").innerText = "Internal server error";
+            }
+        });
+    }
+
+    add_passkey() {
+        let button = document.getElementById("settings-add-passkey-button");
+        let error_elem = document.getElementById("passkey-error");
+        let name = document.getElementById("settings-passkey-name-input").value;
+        let password = document.getElementById("settings-passkey-password-input").value;
+        let otp = document.getElementById("settings-passkey-otp-input").value;
+        button.disabled = true;
+
+        Auth.request("/api/users/me/passkeys/options", {}, "POST").then(r => {
+            return Passkey.create(JSON.parse(r));
+        }).then(body => {
+            body["name"] = name;
+            // Two-factor users confirm with a code instead of their password
+            if (otp) {
+                body["otp"] = otp;
+            } else {
+                body["password"] = password;
+            }
+            return Auth.request("/api/users/me/passkeys", body, "POST");
+        }).then(() => {
+            button.disabled = false;
+            error_elem.innerText = "";
+            document.getElementById("settings-passkey-password-input").value = "";
+            document.getElementById("settings-passkey-otp-input").value = "";
+            this.refresh_passkeys();
+        }).catch(e => {
+            button.disabled = false;
+            Dashboard.display_error(e, error_elem);
+        });
+    }
+
+    remove_passkey(uuid) {
+        Auth.request(`/api/users/me/passkeys/${uuid}`, {}, "DELETE").then(() => {
+            this.refresh_passkeys();
+        }).catch(e => {
+            Dashboard.display_error(e, document.getElementById("passkey-error"));
+        });
+    }
+
+    refresh_passkeys() {
+        Auth.request("/api/users/me/passkeys").then(r => {
+            let container = document.getElementById("passkey-list-container");
+            container.innerHTML = "";
+
+            for (let passkey of JSON.parse(r)) {
+                let passkey_elem = document.createElement("div");
+                passkey_elem.className = "section";
+
+                let label = document.createElement("span");
+                let last_used = passkey.last_used_at ? new Date(passkey.last_used_at).toLocaleString() : "never";
+                label.innerText = `${passkey.name} (last used ${last_used})`;
+                passkey_elem.appendChild(label);
+
+                let button = document.createElement("button");
+                button.innerText = "Remove";
+                button.onclick = () => this.remove_passkey(passkey.uuid);
+                passkey_elem.appendChild(button);
+
+                container.appendChild(passkey_elem);
+            }
+        }).catch(e => {
+            console.error(e);
         });
     }
 
@@ -130,7 +215,7 @@ class Settings {
 
         Auth.request("/api/users/me", {
             "username": this.new_username
-        }, "PATCH").then(r => {
+        }, "PATCH", { "If-Match": this.etag }).then(r => {
             this.update_username_button.disabled = false;
 
             try {
@@ -142,7 +227,11 @@ class Settings {
                 Dashboard.display_error(e, this.update_username_error);
             }
         }).catch(e => {
-            Dashboard.display_error(e, this.update_username_error);
+            this.update_username_button.disabled = false;
+
+            if (!Dashboard.reload_on_conflict(e)) {
+                Dashboard.display_error(e, this.update_username_error);
+            }
         });
     }
 
@@ -151,7 +240,7 @@ class Settings {
 
         Auth.request("/api/users/me", {
             "display_name": this.new_displayname
-        }, "PATCH").then(r => {
+        }, "PATCH", { "If-Match": this.etag }).then(r => {
             this.update_displayname_button.disabled = false;
 
             try {
@@ -163,7 +252,11 @@ class Settings {
                 Dashboard.display_error(e, this.update_displayname_error);
             }
         }).catch(e => {
-            Dashboard.display_error(e, this.update_displayname_error);
+            this.update_displayname_button.disabled = false;
+
+            if (!Dashboard.reload_on_conflict(e)) {
+                Dashboard.display_error(e, this.update_displayname_error);
+            }
         });
     }
 
@@ -4,16 +4,29 @@ class Login {
 		this.password_elem = document.getElementById("password");
 		this.error_elem = document.getElementById("error");
 		this.submit_elem = document.getElementById("submit");
+		this.otp_elem = document.getElementById("otp");
+		// Set when Discord identified a user who must still enter their two-factor code
+		this.discord_pending = false;
+
+		const queryParams = new URLSearchParams(window.location.search);
+
+		if (queryParams.has("code") && queryParams.has("state")) {
+			this.discord_callback(queryParams.get("code"), queryParams.get("state"));
+			return;
+		}
 
 		if (Auth.get_cookie("refreshToken")) {
 			window.location.href = "/dashboard";
 		}
 
-		const queryParams = new URLSearchParams(window.location.search);
 		if (queryParams.has("expired")) {
 			this.error_elem.innerText = "Session expired or invalid. Please log in again.";
 		}
 
+		if (Passkey.supported()) {
+			document.getElementById("passkey-wrapper").style.display = "";
+		}
+
 		document.getElementById("forgot-password-button").addEventListener("click", function (event) {
 			event.preventDefault();
 			Login.forgot_password();
@@ -37,15 +50,26 @@ class Login {
 	}
 
 	submit() {
+		if (this.discord_pending) {
+			this.discord_two_factor();
+			return;
+		}
+
 		const generic_err_msg = "Internal server error";
 
 		this.submit_elem.disabled = true;
 
-		Request.post("/api/auth/token", {
+		let body = {
 			"grant_type": "password",
 			"username": this.username_elem.value,
 			"password": this.password_elem.value
-		}).then(response => {
+		};
+
+		if (this.otp_elem.value) {
+			body["otp"] = this.otp_elem.value;
+		}
+
+		Request.post("/api/auth/token", body).then(response => {
 			this.submit_elem.disabled = false;
 
 			try {
@@ -63,6 +87,7 @@ class Login {
 				try {
 					let error_obj = JSON.parse(error.message);
 					this.error_elem.innerText = error_obj.error;
+					this.show_otp_if_required(error_obj.error);
 				} catch (e) {
 					this.error_elem.innerText = generic_err_msg;
 				}
@@ -72,6 +97,142 @@ class Login {
 		});
 	}
 
+	/**
+	 * Sign in with a passkey, which the browser lets the user choose.
+	 */
+	passkey() {
+		Request.post("/api/auth/passkey/options", {}).then(response => {
+			return Passkey.get(JSON.parse(response));
+		}).then(body => {
+			return Request.post("/api/auth/token", body);
+		}).then(response => {
+			Auth.store_tokens(JSON.parse(response));
+			return Auth.request("/api/page/settings");
+		}).then(response => {
+			Auth.set_cookie("username", JSON.parse(response).username);
+			window.location.href = "/dashboard";
+		}).catch(error => {
+			this.show_error(error);
+		});
+	}
+
+	/**
+	 * Start signing in with Discord.
+	 * If the username field is filled, it's used for a new account instead of the Discord username.
+	 */
+	discord() {
+		if (this.username_elem.value) {
+			sessionStorage.setItem("discord_username", this.username_elem.value);
+		} else {
+			sessionStorage.removeItem("discord_username");
+		}
+
+		Request.get("/api/auth/discord").then(response => {
+			window.location.href = JSON.parse(response).authorize_url;
+		}).catch(error => {
+			this.show_error(error);
+		});
+	}
+
+	/**
+	 * Complete signing in with, or linking, Discord after it redirects back to this page.
+	 */
+	discord_callback(code, state) {
+		let body = { "code": code, "state": state };
+		window.history.replaceState(null, "", "/login");
+
+		if (sessionStorage.getItem("discord_link")) {
+			sessionStorage.removeItem("discord_link");
+
+			Auth.request("/api/users/me/discord", body, "POST").then(() => {
+				window.location.href = "/dashboard?p=settings";
+			}).catch(error => {
+				this.show_error(error);
+			});
+
+			return;
+		}
+
+		let username = sessionStorage.getItem("discord_username");
+
+		if (username) {
+			body["username"] = username;
+		}
+
+		Request.post("/api/auth/discord", body).then(response => {
+			this.finish_discord_login(response);
+		}).catch(error => {
+			try {
+				// The server keeps the login pending until the code is sent
+				this.discord_pending = JSON.parse(error.message).error === "Two-factor code required";
+				this.submit_elem.disabled = !this.discord_pending;
+			} catch (e) { }
+
+			this.show_error(error);
+		});
+	}
+
+	/**
+	 * Complete signing in with Discord as a user with two-factor authentication, with the entered code.
+	 */
+	discord_two_factor() {
+		this.submit_elem.disabled = true;
+
+		Request.post("/api/auth/discord/two_factor", { "otp": this.otp_elem.value }).then(response => {
+			this.finish_discord_login(response);
+		}).catch(error => {
+			this.submit_elem.disabled = false;
+			this.show_error(error);
+		});
+	}
+
+	finish_discord_login(response) {
+		sessionStorage.removeItem("discord_username");
+		let login_response = JSON.parse(response);
+		Auth.store_tokens(login_response);
+		Auth.set_cookie("username", login_response.username);
+		window.location.href = "/dashboard";
+	}
+
+	show_error(error) {
+		try {
+			this.error_elem.innerText = JSON.parse(error.message).error;
+			this.show_otp_if_required(JSON.parse(error.message).error);
+		} catch (e) {
+			this.error_elem.innerText = "Internal server error";
+		}
+	}
+
+	show_otp_if_required(error) {
+		if (error === "Two-factor code required") {
+			document.getElementById("otp-wrapper").style.display = "";
+			this.otp_elem.focus();
+		}
+	}
+
+	/**
+	 * Request a password reset link for the username entered in the forgot password dialog.
+	 */
+	send_reset_link() {
+		let username = Auth.format_username(document.getElementById("forgot-password-username").value);
+		let status_elem = document.getElementById("forgot-password-status");
+		let submit_elem = document.getElementById("forgot-password-submit");
+		submit_elem.disabled = true;
+
+		Request.post("/api/auth/forgot_password", { "username": username }).then(() => {
+			submit_elem.disabled = false;
+			status_elem.innerText = "If the account can receive it, a link has been sent.";
+		}).catch(error => {
+			submit_elem.disabled = false;
+
+			try {
+				status_elem.innerText = JSON.parse(error.message).error;
+			} catch (e) {
+				status_elem.innerText = "Internal server error";
+			}
+		});
+	}
+
 	static forgot_password() {
 		let info_wrapper = document.getElementById("forgot-password-info-wrapper");
 		if (info_wrapper.style.display === "none") {
@@ -0,0 +1,38 @@
+class ResetPassword {
+	constructor() {
+		this.password_elem = document.getElementById("password");
+		this.confirmpassword_elem = document.getElementById("confirmpassword");
+		this.error_elem = document.getElementById("error");
+		this.submit_elem = document.getElementById("submit");
+
+		this.token = new URLSearchParams(window.location.search).get("token");
+
+		if (!this.token) {
+			this.error_elem.innerText = "Invalid password reset link";
+		}
+	}
+
+	input() {
+		let password = this.password_elem.value;
+		this.submit_elem.disabled = !this.token || password === "" || password !== this.confirmpassword_elem.value;
+	}
+
+	submit() {
+		this.submit_elem.disabled = true;
+
+		Request.post("/api/auth/reset_password", {
+			"token": this.token,
+			"new_password": this.password_elem.value
+		}).then(() => {
+			window.location.href = "/login";
+		}).catch(error => {
+			this.submit_elem.disabled = false;
+
+			try {
+				this.error_elem.innerText = JSON.parse(error.message).error;
+			} catch (e) {
+				this.error_elem.innerText = "Internal server error";
+			}
+		});
+	}
+}
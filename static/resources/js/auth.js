@@ -72,7 +72,19 @@ class Auth {
 	}
 
 
+	/**
+	 * Sign out, ending the session on the server first unless it already ended.
+	 */
 	static logout(reason) {
+		if (!reason && Auth.get_cookie("refreshToken")) {
+			Auth.request("/api/auth/logout", null, "POST").finally(() => {
+				Auth.clear_tokens();
+				location.href = "/login";
+			});
+
+			return;
+		}
+
 		Auth.clear_tokens();
 		if (reason) {
 			location.href = `/login?${reason}`;
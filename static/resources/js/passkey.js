@@ -0,0 +1,59 @@
+/**
+ * WebAuthn ceremonies, converting between the server's base64url fields and the browser's ArrayBuffers.
+ */
+class Passkey {
+	static supported() {
+		return !!window.PublicKeyCredential;
+	}
+
+	static decode(value) {
+		let base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+		base64 += "=".repeat((4 - base64.length % 4) % 4);
+		return Uint8Array.from(atob(base64), c => c.charCodeAt(0)).buffer;
+	}
+
+	static encode(buffer) {
+		let binary = String.fromCharCode(...new Uint8Array(buffer));
+		return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+	}
+
+	/**
+	 * Create a passkey with options from /api/users/me/passkeys/options.
+	 */
+	static async create(options) {
+		let public_key = options.public_key;
+		public_key.challenge = Passkey.decode(public_key.challenge);
+		public_key.user.id = Passkey.decode(public_key.user.id);
+
+		for (let credential of public_key.excludeCredentials) {
+			credential.id = Passkey.decode(credential.id);
+		}
+
+		let credential = await navigator.credentials.create({ publicKey: public_key });
+
+		return {
+			"challenge": options.challenge,
+			"client_data_json": Passkey.encode(credential.response.clientDataJSON),
+			"attestation_object": Passkey.encode(credential.response.attestationObject)
+		};
+	}
+
+	/**
+	 * Sign in with a passkey with options from /api/auth/passkey/options, returning the token request.
+	 */
+	static async get(options) {
+		let public_key = options.public_key;
+		public_key.challenge = Passkey.decode(public_key.challenge);
+
+		let credential = await navigator.credentials.get({ publicKey: public_key });
+
+		return {
+			"grant_type": "passkey",
+			"challenge": options.challenge,
+			"credential_id": Passkey.encode(credential.rawId),
+			"client_data_json": Passkey.encode(credential.response.clientDataJSON),
+			"authenticator_data": Passkey.encode(credential.response.authenticatorData),
+			"signature": Passkey.encode(credential.response.signature)
+		};
+	}
+}
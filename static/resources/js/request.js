@@ -1,4 +1,9 @@
 class Request {
+	/**
+	 * The last `ETag` received from each URL, to send back in an `If-Match` header when updating what it returned.
+	 */
+	static etags = {};
+
 	static get(url, headers = {}) {
 		return Request.send(url, null, "GET", headers);
 	}
@@ -22,9 +27,17 @@ class Request {
 
 			xhr.onload = function () {
 				if (xhr.status >= 200 && xhr.status < 300) {
+					let etag = xhr.getResponseHeader("ETag");
+
+					if (etag) {
+						Request.etags[url] = etag;
+					}
+
 					resolve(xhr.response);
 				} else {
-					reject(new Error(xhr.response));
+					let error = new Error(xhr.response);
+					error.status = xhr.status;
+					reject(error);
 				}
 			};
 